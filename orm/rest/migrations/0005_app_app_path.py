@@ -0,0 +1,18 @@
+# Generated by Django 3.0.5 on 2026-10-18 14:40
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('rest', '0004_app_webhook'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='app',
+            name='app_path',
+            field=models.TextField(blank=True, null=True),
+        ),
+    ]
@@ -14,6 +14,9 @@ class App(models.Model):
     git_repo = models.TextField(null=True, blank=True)
     git_ref = models.TextField(null=True, blank=True)
     yml = models.TextField(null=True, blank=True)
+    webhook_secret = models.CharField(max_length=100, null=True, blank=True)
+    poll_interval = models.PositiveIntegerField(null=True, blank=True)
+    app_path = models.TextField(null=True, blank=True)
 
     class Meta:
         unique_together = ('user', 'name')
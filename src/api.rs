@@ -1,25 +1,38 @@
 use std::sync::Arc;
 use regex::Regex;
 use hyper::body::to_bytes;
-use serde_json::json;
+use serde_json::{json, Value};
 use crate::types::*;
 use crate::http::*;
 use crate::graphql::handle_graphql;
-use crate::db::DB;
+use crate::db::{DB, Query};
+use crate::apps::load_app;
 
-async fn handle_model_get(req: Request, db: DB, model: &ModelDef, uid: Option<i64>) -> Result<Response> {
-    let qm = get_query(&req);
-    let id: Option<i64> = if let Some(x) = qm.get("id") {
-        x.parse().ok()
-    } else {
-        None
+async fn handle_model_get(req: Request, db: DB, model: &ModelDef, uid: Option<i64>,
+                          scope: &ModelQuery) -> Result<Response> {
+    if scope.id.is_some() {
+        return match model.select_query(&db, uid, scope).await?.pop() {
+            Some(x) => json_response(&x.to_value()),
+            None => http404("Record not found"),
+        };
+    }
+    let mut q = match model.parse_query(&get_query(&req)) {
+        Ok(x) => x,
+        Err(e) => { return http400(&e.to_string()); }
     };
-
-    let mut ret = Vec::new();
-    for rec in model.select(&db, uid, id).await? {
-        ret.push(rec.to_value());
+    q.filters.extend(scope.filters.iter().cloned());
+    if q.limit.is_none() {
+        q.limit = Some(config().default_page_size);
+    }
+    let page = model.select_page(&db, uid, &q).await?;
+    let ret: Vec<Value> = page.rows.iter().map(|x| x.to_value()).collect();
+    let mut resp = json_response(&ret)?;
+    let headers = resp.headers_mut();
+    headers.insert("X-Total-Count", page.total.into());
+    if let Some(c) = page.next_cursor {
+        headers.insert("X-Next-Cursor", c.parse()?);
     }
-    json_response(&ret)
+    Ok(resp)
 }
 
 async fn read_body(req: Request) -> Result<String> {
@@ -27,19 +40,111 @@ async fn read_body(req: Request) -> Result<String> {
     Ok(String::from_utf8(bytes.to_vec())?)
 }
 
-async fn handle_model_post(req: Request, db: DB, model: &ModelDef, uid: Option<i64>) -> Result<Response> {
-    let rec = Row::from_json(&read_body(req).await?)?;
+/* Narrow ids down to the records matching the filters captured from the endpoint path */
+async fn scoped_ids(db: &DB, model: &ModelDef, uid: Option<i64>, scope: &ModelQuery,
+                    ids: Vec<i64>) -> Result<Vec<i64>> {
+    if scope.filters.is_empty() {
+        return Ok(ids);
+    }
+    let mut q = ModelQuery {
+        filters: scope.filters.clone(),
+        ..Default::default()
+    };
+    q.filters.push(FieldFilter {
+        field: "id".to_string(),
+        op: FilterOp::In(ids.into_iter().map(RowField::Integer).collect()),
+    });
+    let rows = model.select_query(db, uid, &q).await?;
+    Ok(rows.iter().filter_map(|r| r.get_int("id")).collect())
+}
+
+/* Parse a request body into a record of model. Fields captured from the endpoint path, such as
+ * list in /lists/{list}/items, take precedence over the body. */
+fn parse_record(body: &str, model: &ModelDef, mode: WriteMode, scope: &ModelQuery) -> Result<Row> {
+    let mut v: Value = serde_json::from_str(body)?;
+    if let Value::Object(o) = &mut v {
+        for f in &scope.filters {
+            if let FilterOp::Eq(x) = &f.op {
+                o.insert(f.field.to_string(), x.to_value());
+            }
+        }
+    }
+    model.validate_input(&v, mode)
+}
+
+/* Validation errors are reported field by field, anything else is a malformed body */
+fn input_error(e: Error) -> Result<Response> {
+    if e.is::<ValidationError>() {
+        return error_response(&HttpError::from_error(&e));
+    }
+    http400(&e.to_string())
+}
+
+async fn handle_model_post(req: Request, db: DB, model: &ModelDef, uid: Option<i64>,
+                           scope: &ModelQuery) -> Result<Response> {
+    let rec = match parse_record(&read_body(req).await?, model, WriteMode::Create, scope) {
+        Ok(x) => x,
+        Err(e) => { return input_error(e); }
+    };
     model.create(&db, &rec, uid).await?;
     json_response(&1)
 }
 
-async fn handle_model_put(req: Request, db: DB, model: &ModelDef, uid: Option<i64>) -> Result<Response> {
-    let rec = Row::from_json(&read_body(req).await?)?;
-    model.update(&db, &rec, uid).await?;
-    json_response(&1)
+/* Write the fields of rec to record id and respond with the updated record */
+async fn update_record(db: DB, model: &ModelDef, uid: Option<i64>, scope: &ModelQuery, id: i64,
+                       mut rec: Row) -> Result<Response> {
+    if scoped_ids(&db, model, uid, scope, vec![id]).await?.is_empty() {
+        return http404("Record not found");
+    }
+    rec.fields.remove("id");
+    if !rec.fields.is_empty() {
+        rec.set("id", RowField::Integer(id));
+        if model.update(&db, &rec, uid).await? == 0 {
+            return http404("Record not found");
+        }
+    }
+    match model.get(&db, uid, id).await? {
+        Some(x) => json_response(&x.to_value()),
+        None => http404("Record not found"),
+    }
+}
+
+async fn handle_model_put(req: Request, db: DB, model: &ModelDef, uid: Option<i64>,
+                          scope: &ModelQuery) -> Result<Response> {
+    let rec = match parse_record(&read_body(req).await?, model, WriteMode::Replace, scope) {
+        Ok(x) => x,
+        Err(e) => { return input_error(e); }
+    };
+    /* Without a record path, fall back to the id in the body */
+    let id = match scope.id.or_else(|| rec.get_int("id")) {
+        Some(x) => x,
+        None => { return http400("No record id in request"); }
+    };
+    update_record(db, model, uid, scope, id, rec).await
 }
 
-async fn handle_model_delete(req: Request, db: DB, model: &ModelDef, uid: Option<i64>) -> Result<Response> {
+async fn handle_model_patch(req: Request, db: DB, model: &ModelDef, uid: Option<i64>,
+                            scope: &ModelQuery) -> Result<Response> {
+    let id = match scope.id {
+        Some(x) => x,
+        None => { return http400("PATCH must address a record"); }
+    };
+    let rec = match parse_record(&read_body(req).await?, model, WriteMode::Patch, scope) {
+        Ok(x) => x,
+        Err(e) => { return input_error(e); }
+    };
+    update_record(db, model, uid, scope, id, rec).await
+}
+
+async fn handle_model_delete(req: Request, db: DB, model: &ModelDef, uid: Option<i64>,
+                             scope: &ModelQuery) -> Result<Response> {
+    if let Some(id) = scope.id {
+        let pks = scoped_ids(&db, model, uid, scope, vec![id]).await?;
+        return match model.delete(&db, &pks, uid).await? {
+            0 => http404("Record not found"),
+            r => json_response(&r),
+        };
+    }
     #[derive(Deserialize)]
     struct DeleteReq {
         id: Option<i64>,
@@ -53,31 +158,57 @@ async fn handle_model_delete(req: Request, db: DB, model: &ModelDef, uid: Option
     if let Some(id) = d.id {
         pks.push(id);
     }
+    let pks = scoped_ids(&db, model, uid, scope, pks).await?;
     let r = model.delete(&db, &pks[..], uid).await?;
     json_response(&r)
 }
 
 async fn handle_model_request(req: Request,
                               app: &OctApp,
+                              def: &AppDef,
                               m: &ModelApiDesc,
-                              uid: Option<i64>) -> Result<Response> {
+                              uid: Option<i64>,
+                              params: &PathParams) -> Result<Response> {
     let db = app.db()?;
-    let def = if let Some(x) = app.get_def().await {
-        x
-    } else {
-        return http404("model not found");
-    };
     let model = if let Some(m) = def.get_model(&m.model) {
         m
     } else {
-        bail!("Model not found");
+        return http404("Model not found");
     };
+    /* Path parameters are either the record id or filters on model fields */
+    let scope = match model.parse_query(params) {
+        Ok(x) => x,
+        Err(e) => { return http400(&e.to_string()); }
+    };
+    match req.method() {
+        &hyper::Method::GET => handle_model_get(req, db, model, uid, &scope).await,
+        &hyper::Method::POST if scope.id.is_none() => handle_model_post(req, db, model, uid, &scope).await,
+        &hyper::Method::PUT => handle_model_put(req, db, model, uid, &scope).await,
+        &hyper::Method::PATCH => handle_model_patch(req, db, model, uid, &scope).await,
+        &hyper::Method::DELETE => handle_model_delete(req, db, model, uid, &scope).await,
+        _ => http405(),
+    }
+}
+
+/* Serve a file from the app repo. A directory is served by its index.html. */
+async fn handle_static_request(req: Request, app: &OctApp, def: &StaticApiDesc,
+                               params: &PathParams) -> Result<Response> {
     match req.method() {
-        &hyper::Method::GET => handle_model_get(req, db, &model, uid).await,
-        &hyper::Method::POST => handle_model_post(req, db, &model, uid).await,
-        &hyper::Method::PUT => handle_model_put(req, db, &model, uid).await,
-        &hyper::Method::DELETE => handle_model_delete(req, db, &model, uid).await,
-        _ => bail!("Unsupported method"),
+        &hyper::Method::GET | &hyper::Method::HEAD => (),
+        _ => { return http405(); }
+    }
+    let rel = match params.get(STATIC_FILE_PARAM) {
+        Some(x) => format!("{}/{}", def.localfile, x),
+        None => def.localfile.to_string(),
+    };
+    let root = app.app_root();
+    let file = match root.resolve(&rel) {
+        Ok(x) if x.is_dir() => root.resolve(&format!("{}/index.html", rel)),
+        r => r,
+    };
+    match file {
+        Ok(x) => file_response(req.headers(), &x.to_string_lossy()).await,
+        Err(_) => http404("File not found"),
     }
 }
 
@@ -146,7 +277,10 @@ async fn handle_api_auth_post(_ctx: Arc<Context>, req: Request, app: &OctApp,
     if api_path == "/auth/user" {
         let db = app.db()?;
         let model = ModelDef::make_user_model();
-        let rec = Row::from_json(&read_body(req).await?)?;
+        let rec = match parse_record(&read_body(req).await?, &model, WriteMode::Create, &ModelQuery::default()) {
+            Ok(x) => x,
+            Err(e) => { return input_error(e); }
+        };
         model.create(&db, &rec, None).await?;
         return json_response(&1);
     } else {
@@ -162,7 +296,7 @@ async fn handle_api_auth_request(ctx: Arc<Context>, req: Request, app: &OctApp,
     match req.method() {
         &hyper::Method::GET => handle_api_auth_get(ctx, req, app, api_path).await,
         &hyper::Method::POST => handle_api_auth_post(ctx, req, app, api_path).await,
-        _ => bail!("Unsupported method"),
+        _ => http405(),
     }
 }
 
@@ -173,15 +307,14 @@ async fn api_authenticate(_ctx: Arc<Context>, app: &OctApp,
     } else {
         return Ok(None);
     };
-    if token.contains('\'') {
-        return Ok(None);
-    }
     if token == app.admin_token {
         return Ok(Some(0));
     }
     let db = app.db()?;
-    let cond = format!("token='{}'", token);
-    let rec = match db.get(&ModelDef::make_user_model(), &cond) {
+    let model = ModelDef::make_user_model();
+    let mut q = Query::new(&model);
+    q.eq("token", RowField::String(token.to_string()))?;
+    let rec = match db.get(&q).await {
         Ok(Some(x)) => x,
         _ => { return Ok(None) },
     };
@@ -191,11 +324,9 @@ async fn api_authenticate(_ctx: Arc<Context>, app: &OctApp,
     }
 }
 
-async fn rule_match(_ctx: &Arc<Context>,
-                    _app: &OctApp,
-                    method_name: &str,
-                    rule: &ApiAccessRuleDef,
-                    uid: Option<i64>) -> bool {
+fn rule_match(method_name: &str,
+              rule: &ApiAccessRuleDef,
+              uid: Option<i64>) -> bool {
     if let Some(x) = &rule.method {
         if x.as_str() != method_name {
             return false;
@@ -212,9 +343,27 @@ async fn rule_match(_ctx: &Arc<Context>,
     }
 }
 
-async fn check_access(ctx: Arc<Context>,
+/* Evaluate endpoint access rules, or the app default rules if the endpoint has none, for a
+ * request of the given (lowercase) method. */
+pub fn access_allowed(appdef: &AppDef,
+                      rules: Option<&Vec<ApiAccessRuleDef>>,
+                      method_name: &str,
+                      uid: Option<i64>) -> bool {
+    if Some(0) == uid {
+        /* Admin can do everything */
+        return true;
+    }
+    for rule in &appdef.api.effective_access(rules) {
+        if rule_match(method_name, rule, uid) {
+            return rule.action.allowed();
+        }
+    }
+    false
+}
+
+async fn check_access(_ctx: Arc<Context>,
                       req: &Request,
-                      app: &OctApp,
+                      appdef: &AppDef,
                       ep: &ApiEndpoint,
                       uid: Option<i64>) -> Result<bool> {
     if Some(0) == uid {
@@ -222,28 +371,7 @@ async fn check_access(ctx: Arc<Context>,
         return Ok(true);
     }
     let method_name = req.method().as_str();
-    let appdef = if let Some(x) = app.get_def().await {
-        x
-    } else {
-        return Ok(false);
-    };
-    let defrules;
-    let rules = if let Some(x) = ep.access() {
-        x
-    } else {
-        if let Some(x) = &appdef.api.default_access {
-            defrules = x.get_rules();
-            &defrules
-        } else {
-            return Ok(false);
-        }
-    };
-    for rule in rules {
-        if rule_match(&ctx, app, &method_name.to_lowercase(), &rule, uid).await {
-            return Ok(rule.action.allowed());
-        }
-    }
-    Ok(false)
+    Ok(access_allowed(appdef, ep.access(), &method_name.to_lowercase(), uid))
 }
 
 pub async fn handle_api_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
@@ -258,39 +386,36 @@ pub async fn handle_api_request(ctx: Arc<Context>, req: Request) -> Result<Respo
     let handle = cap.get(1).unwrap().as_str();
     let api_path = cap.get(2).unwrap().as_str();
 
-    let app = if let Ok(x) = OctApp::by_handle(handle).await {
-        x
-    } else {
-        return http404("App not found");
-    };
-    let uid = api_authenticate(ctx.clone(), &app, &req).await?;
+    let loaded = load_app(&ctx, handle).await?;
+    let app = &loaded.app;
+    let uid = api_authenticate(ctx.clone(), app, &req).await?;
 
     if api_path == "/__oct_status" {
-        return handle_status_request(ctx.clone(), &app).await;
+        return handle_status_request(ctx.clone(), app).await;
     } else if api_path == "/__oct_stats" {
-        return handle_stats_request(ctx.clone(), &app).await;
+        return handle_stats_request(ctx.clone(), app).await;
     } else if api_path == "/__oct_query_count" {
-        return handle_query_count_request(ctx.clone(), req, &app).await;
+        return handle_query_count_request(ctx.clone(), req, app).await;
     } else if api_path.starts_with("/auth") {
-        return handle_api_auth_request(ctx.clone(), req, &app, api_path, uid).await;
+        return handle_api_auth_request(ctx.clone(), req, app, api_path, uid).await;
     }
-    let appdef = if let Some(x) = app.get_def().await {
-        x
+    let (appdef, router) = if let (Some(x), Some(r)) = (&loaded.def, &loaded.router) {
+        (x, r)
     } else {
         return http404("API not found");
     };
-    if let Some(ep) = appdef.api.find_endpoint(api_path) {
-        if !check_access(ctx.clone(), &req, &app, &ep, uid).await? {
-            return http401("Permission error");
+    if let Some((ep, params)) = router.route(&appdef.api, api_path) {
+        if !check_access(ctx.clone(), &req, appdef, &ep, uid).await? {
+            return match uid {
+                Some(_) => error_response(&HttpError::new(403, "forbidden", "Permission error")),
+                None => http401("Permission error"),
+            };
         }
         let h = match &ep {
             ApiEndpoint::String(c) => simple_response(c.response.to_string()).await,
-            ApiEndpoint::StaticFile(_) => {
-                bail!("not implemented");
-                //file_response(&c.localfile).await
-            },
-            ApiEndpoint::Model(m) => handle_model_request(req, &app, &m, uid).await,
-            ApiEndpoint::GraphQL(def) => handle_graphql(req, &app, &def, uid).await,
+            ApiEndpoint::StaticFile(def) => handle_static_request(req, app, def, &params).await,
+            ApiEndpoint::Model(m) => handle_model_request(req, app, appdef, m, uid, &params).await,
+            ApiEndpoint::GraphQL(def) => handle_graphql(req, app, appdef, def, uid).await,
         };
         let metric = format!("api.{}.{}.{}",
             handle, ep.name(), &method);
@@ -1,10 +1,78 @@
+use std::fs;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
 use crate::types::*;
 use crate::db::DB;
 use crate::stor::*;
+use crate::locate::locate_errors;
 
 const DB_FILENAME: &str = "db.sqlite";
 const APPS_DIR: &str = "apps";
 const APP_YAML_MAX_SIZE: usize = 64 << 10;
+/* Limits of the files included by app.yml */
+const APP_DEF_MAX_FILES: usize = 256;
+const APP_DEF_MAX_SIZE: usize = 1 << 20;
+
+/* An app as served by the API: its info, the definition of its active deployment and the router
+ * of its endpoints */
+#[derive(Debug)]
+pub struct LoadedApp {
+    pub app: OctApp,
+    pub def: Option<Arc<AppDef>>,
+    pub router: Option<ApiRouter>,
+}
+
+/* Apps loaded for serving API requests, by handle, so that requests don't go to the ORM or parse
+ * app.yml again. An entry is dropped whenever the app changes. */
+#[derive(Debug, Default)]
+pub struct AppRegistry {
+    apps: HashMap<String, Arc<LoadedApp>>,
+    /* Bumped on each invalidation, to not cache what was loaded before it */
+    generation: u64,
+}
+
+impl AppRegistry {
+    pub fn invalidate(&mut self, handle: &str) {
+        self.apps.remove(handle);
+        self.generation += 1;
+    }
+}
+
+/* The app with the handle, loaded from the ORM and its repo unless it's in the registry */
+pub async fn load_app(ctx: &Context, handle: &str) -> Result<Arc<LoadedApp>> {
+    let generation = {
+        let reg = ctx.apps();
+        if let Some(x) = reg.apps.get(handle) {
+            return Ok(x.clone());
+        }
+        reg.generation
+    };
+    let app = match OctApp::by_handle(handle).await {
+        Ok(x) => x,
+        Err(_) => bail!(HttpError::not_found("App not found")),
+    };
+    let def = app.get_def().await.map(Arc::new);
+    /* Checked on sync already, this only fails for a deployment made before that check */
+    let router = match &def {
+        Some(d) => match d.api.router() {
+            Ok(x) => Some(x),
+            Err(e) => bail!(HttpError::new(500, "invalid_app_def",
+                                           &format!("Failed to route the endpoints of app.yml: {}", e))),
+        },
+        None => None,
+    };
+    let loaded = Arc::new(LoadedApp {
+        app,
+        def,
+        router,
+    });
+    let mut reg = ctx.apps();
+    if reg.generation == generation {
+        reg.apps.insert(handle.to_string(), loaded.clone());
+    }
+    Ok(loaded)
+}
 
 pub fn apps_dir() -> Entry {
     Entry::new(APPS_DIR)
@@ -22,17 +90,20 @@ impl OctApp {
             git_ref: None,
             git_repo: None,
             admin_token: gen_random_string(20),
+            webhook_secret: Some(gen_random_string(24)),
+            poll_interval: None,
+            app_path: None,
         };
+        app.generate_deploy_key().await?;
         app.orm_create().await?;
         Ok(app)
     }
 
     pub async fn get_def(&self) -> Option<AppDef> {
-        let def = match self.repo().child("app.yml").read().await {
-            Ok(x) => x,
-            _ => { return None; }
-        };
-        match AppDef::from_yaml(&def) {
+        if !self.app_root().child(APP_YML).exists() {
+            return None;
+        }
+        match get_repo_app_def(&self.repo(), self.app_path.as_deref()).await {
             Ok(x) => Some(x),
             Err(e) => {
                 println!("failed to load app yaml: {}", e);
@@ -41,6 +112,11 @@ impl OctApp {
         }
     }
 
+    /* Directory of app.yml in the active repo */
+    pub fn app_root(&self) -> Entry {
+        app_root(&self.repo(), self.app_path.as_deref())
+    }
+
     pub fn dir(&self) -> Entry {
         apps_dir().child(&self.handle)
     }
@@ -49,9 +125,16 @@ impl OctApp {
         self.dir().child("repo")
     }
 
+    pub fn db_file(&self) -> Entry {
+        self.dir().child(DB_FILENAME)
+    }
+
     pub fn db(&self) -> Result<DB> {
-        let dbf = self.dir().child(DB_FILENAME);
-        DB::new(&dbf.fullpath())
+        DB::new(&self.db_file().fullpath())
+    }
+
+    pub fn has_db(&self) -> bool {
+        self.db_file().exists()
     }
 
     pub fn running(&self) -> bool {
@@ -67,34 +150,149 @@ impl OctApp {
     }
 }
 
-pub async fn sync_models(db: &DB, old: &Option<AppDef>, new: &AppDef) -> Result<()> {
-    let tables = db.tables().await?;
-    let user_model = ModelDef::make_user_model();
-    let mut models = vec![&user_model];
-    let new_models = new.models.iter();
-    models.extend(new_models.as_ref());
-    for model in &models {
-        let model_name = &model.name;
-        if tables.contains(&model.name) {
-            if model_name.starts_with("__oct") {
-                /* Cannot migrate internal models yet. */
-                continue;
+pub fn app_root(repo: &Entry, app_path: Option<&str>) -> Entry {
+    match app_path.map(|x| x.trim_end_matches('/')).filter(|x| !x.is_empty()) {
+        Some(p) => repo.child(p),
+        None => repo.clone(),
+    }
+}
+
+/* The files matching the include patterns, as paths relative to root and their contents */
+fn read_fragments(root: &Path, patterns: &[String]) -> Result<Vec<(String, String)>> {
+    let mut files = BTreeSet::new();
+    for p in patterns {
+        let full = format!("{}/{}", glob::Pattern::escape(&root.to_string_lossy()), p);
+        for path in glob::glob(&full)? {
+            let path = path?;
+            if path.is_file() {
+                if let Ok(rel) = path.strip_prefix(root) {
+                    files.insert(rel.to_string_lossy().to_string());
+                }
             }
-            let oldmodel = old.as_ref().unwrap().get_model(model_name).unwrap();
-            model.alter_table(&db, oldmodel).await?;
-        } else {
-            model.create_table(&db).await?;
         }
     }
-    Ok(())
+    if files.len() > APP_DEF_MAX_FILES {
+        bail!("app.yml includes more than {} files", APP_DEF_MAX_FILES);
+    }
+    let mut ret = Vec::new();
+    let mut total = 0;
+    for rel in files {
+        let path = resolve_within(root, &rel)?;
+        let size = fs::metadata(&path)?.len() as usize;
+        total += size;
+        if size > APP_YAML_MAX_SIZE {
+            bail!("{} too large", rel);
+        }
+        if total > APP_DEF_MAX_SIZE {
+            bail!("Included files are too large in total");
+        }
+        let yml = fs::read_to_string(&path)?;
+        ret.push((rel, yml));
+    }
+    Ok(ret)
 }
 
-pub async fn get_repo_app_def(repo: &Entry) -> Result<AppDef> {
-    let entry = repo.child("app.yml");
-    if entry.size().await? as usize > APP_YAML_MAX_SIZE {
+/* Load app.yml in root with the files it includes, and check the whole definition. Errors in it
+ * are reported as AppDefErrors, located in the files. */
+pub fn load_app_def(root: &Path) -> Result<AppDef> {
+    let path = root.join(APP_YML);
+    if fs::metadata(&path)?.len() as usize > APP_YAML_MAX_SIZE {
         bail!("app.yml too large");
     }
-    let s = entry.read().await?;
-    let r = AppDef::from_yaml(&s)?;
+    let main = fs::read_to_string(&path)?;
+    let mut r = AppDef::parse_yaml(&main)?;
+    let mut files = match &r.include {
+        Some(patterns) => read_fragments(root, patterns)?,
+        None => Vec::new(),
+    };
+    r.add_fragments(&files)?;
+    let mut errors = r.check();
+    if !errors.is_empty() {
+        files.push((APP_YML.to_string(), main));
+        locate_errors(&mut errors, &files);
+        bail!(AppDefErrors { errors });
+    }
     Ok(r)
 }
+
+/* Load the app definition from the directory app_path of the repo */
+pub async fn get_repo_app_def(repo: &Entry, app_path: Option<&str>) -> Result<AppDef> {
+    let base = Path::new(&repo.fullpath()).canonicalize()?;
+    let root = resolve_within(&base, app_path.unwrap_or("."))?;
+    tokio::task::spawn_blocking(move || load_app_def(&root)).await?
+}
+
+#[test]
+fn fragments_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("app");
+    fs::create_dir_all(root.join("models")).unwrap();
+    fs::create_dir_all(root.join("endpoints")).unwrap();
+    let main = "
+meta:
+  schema: v0.0.1
+name: test
+include:
+  - models/*.yml
+  - endpoints/*.yml
+api:
+  default_access: allow
+";
+    fs::write(root.join("models/todo.yml"), "
+models:
+  - name: todo
+    fields:
+      - name: subject
+        type: string
+").unwrap();
+    fs::write(root.join("models/tag.yml"), "
+models:
+  - name: tag
+    fields:
+      - name: label
+        type: string
+").unwrap();
+    fs::write(root.join("endpoints/api.yml"), "
+endpoints:
+  - name: todos
+    type: model
+    path: /todo
+    model: todo
+").unwrap();
+    fs::write(root.join("models/notes.txt"), "ignored").unwrap();
+
+    let mut def = AppDef::parse_yaml(main).unwrap();
+    let files = read_fragments(&root, def.include.as_ref().unwrap()).unwrap();
+    let names: Vec<&str> = files.iter().map(|x| x.0.as_str()).collect();
+    assert_eq!(names, vec!["endpoints/api.yml", "models/tag.yml", "models/todo.yml"]);
+    def.add_fragments(&files).unwrap();
+    def.validate().unwrap();
+    assert_eq!(def.models.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec!["tag", "todo"]);
+    assert_eq!(def.api.endpoints.len(), 1);
+
+    fs::write(root.join("models/todo2.yml"), "
+models:
+  - name: todo
+").unwrap();
+    let mut def = AppDef::parse_yaml(main).unwrap();
+    let files = read_fragments(&root, def.include.as_ref().unwrap()).unwrap();
+    let e = def.add_fragments(&files).unwrap_err().to_string();
+    assert_eq!(e, "Model todo is defined in both models/todo.yml and models/todo2.yml");
+
+    assert!(AppDef::parse_yaml(&main.replace("models/*.yml", "../*.yml")).is_err());
+
+    fs::remove_file(root.join("models/todo2.yml")).unwrap();
+    fs::write(root.join("app.yml"), main).unwrap();
+    assert_eq!(load_app_def(&root).unwrap().models.len(), 2);
+    fs::write(root.join("endpoints/tags.yml"), "
+endpoints:
+  - name: tags
+    type: model
+    path: /tags
+    model: label
+").unwrap();
+    let e = load_app_def(&root).unwrap_err();
+    let errors = &e.downcast_ref::<AppDefErrors>().unwrap().errors;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "endpoints/tags.yml:6:5: endpoints[0].model: Endpoint tags refers to unknown model label");
+}
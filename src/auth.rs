@@ -128,17 +128,14 @@ async fn handle_info(_ctx: Arc<Context>, req: Request) -> Result<Response> {
 pub async fn handle_auth_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
     let path = req.uri().path();
     let cfg = github_auth_config();
-    let r = if path == "/auth/github/config" {
+    /* Errors are turned into responses by handle_request, see HttpError::from_error */
+    if path == "/auth/github/config" {
         json_response(&cfg)
     } else if path == "/auth/info" {
         handle_info(ctx, req).await
     } else if path == "/auth/github/callback" {
         handle_github_callback(ctx, req).await
     } else {
-        http404("")
-    };
-    if let Err(e) = r {
-        return http400(&e.to_string());
+        http404("Not found")
     }
-    r
 }
@@ -4,6 +4,23 @@ pub struct Config {
     pub server_addr: String,
     pub data_dir: String,
     pub orm_addr: String,
+    /* Seconds a request to the ORM server may take */
+    pub orm_timeout: u64,
+    /* Times a failed ORM request is retried, POST requests never are */
+    pub orm_retries: u32,
+    /* Number of deployments kept per app for rollback */
+    pub deployment_retention: usize,
+    /* Seconds an app repo fetch may take */
+    pub fetch_timeout: u64,
+    /* Bytes an app repo may have, both as fetched and checked out */
+    pub max_repo_size: u64,
+    /* Whether app repos may be file:// URLs or paths on this server, for tests and single
+     * tenant setups. Otherwise any user could fetch the repos of other apps. */
+    pub allow_local_repos: bool,
+    /* Records in a page of a model listing when the request gives no limit */
+    pub default_page_size: usize,
+    /* Largest limit a listing request may give */
+    pub max_page_size: usize,
 }
 
 impl Config {
@@ -12,6 +29,14 @@ impl Config {
             server_addr: "0.0.0.0:3000".to_string(),
             data_dir: "/data/oct".to_string(),
             orm_addr: "127.0.0.1:8000".to_string(),
+            orm_timeout: 10,
+            orm_retries: 2,
+            deployment_retention: 10,
+            fetch_timeout: 300,
+            max_repo_size: 256 << 20,
+            allow_local_repos: false,
+            default_page_size: 100,
+            max_page_size: 1000,
         }
     }
 }
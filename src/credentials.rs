@@ -0,0 +1,146 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use tokio::process::Command;
+use crate::types::*;
+use crate::stor::Entry;
+
+/*
+ * Credentials the app repo is fetched with: an SSH deploy key generated per app, and optionally
+ * an HTTPS token. They are kept as files in the app dir, apart from the app info in the ORM, so
+ * nothing but the public key ever leaves the server.
+ */
+
+const DEPLOY_KEY: &str = "deploy_key";
+const HTTPS_CREDENTIAL: &str = "https_credential.json";
+/* Most git hosts take any user name along with a token, GitLab wants this one */
+const DEFAULT_TOKEN_USER: &str = "oauth2";
+
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub ssh_key: Option<PathBuf>,
+    /* User name and token */
+    pub https: Option<(String, String)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HttpsCredential {
+    username: Option<String>,
+    token: String,
+}
+
+/* Replace the file with one only the server user can read, from the moment it's created */
+fn write_private(entry: &Entry, data: &str) -> Result<()> {
+    let path = entry.fullpath();
+    let _ = fs::remove_file(&path);
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    f.write_all(data.as_bytes())?;
+    Ok(())
+}
+
+impl OctApp {
+    fn deploy_key(&self) -> Entry {
+        self.dir().child(DEPLOY_KEY)
+    }
+
+    /* Generate a new deploy key pair, replacing the old one */
+    pub async fn generate_deploy_key(&self) -> Result<()> {
+        let key = self.deploy_key();
+        let _ = fs::remove_file(key.fullpath());
+        let _ = fs::remove_file(key.fullpath() + ".pub");
+        let comment = format!("oct-{}", self.handle);
+        let r = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", &comment, "-f", &key.fullpath()])
+            .status()
+            .await?;
+        if !r.success() {
+            bail!("Failed to generate deploy key");
+        }
+        Ok(())
+    }
+
+    /* Public part of the deploy key, to be added to the git host. Generated on first use. */
+    pub async fn deploy_public_key(&self) -> Result<String> {
+        if !self.deploy_key().exists() {
+            self.generate_deploy_key().await?;
+        }
+        let public = fs::read_to_string(self.deploy_key().fullpath() + ".pub")?;
+        Ok(public.trim().to_string())
+    }
+
+    pub fn has_https_token(&self) -> bool {
+        self.dir().child(HTTPS_CREDENTIAL).exists()
+    }
+
+    /* Set the HTTPS token, or remove it when None */
+    pub fn set_https_token(&self, username: Option<&str>, token: Option<&str>) -> Result<()> {
+        let entry = self.dir().child(HTTPS_CREDENTIAL);
+        match token.filter(|x| !x.is_empty()) {
+            Some(t) => {
+                let c = HttpsCredential {
+                    username: username.filter(|x| !x.is_empty()).map(|x| x.to_string()),
+                    token: t.to_string(),
+                };
+                write_private(&entry, &serde_json::to_string(&c)?)
+            },
+            None => {
+                let _ = fs::remove_file(entry.fullpath());
+                Ok(())
+            },
+        }
+    }
+
+    pub fn credentials(&self) -> Result<Credentials> {
+        let key = self.deploy_key();
+        let ssh_key = if key.exists() {
+            Some(PathBuf::from(key.fullpath()))
+        } else {
+            None
+        };
+        let entry = self.dir().child(HTTPS_CREDENTIAL);
+        let https = if entry.exists() {
+            let c: HttpsCredential = serde_json::from_str(&fs::read_to_string(entry.fullpath())?)?;
+            let user = c.username.unwrap_or_else(|| DEFAULT_TOKEN_USER.to_string());
+            Some((user, c.token))
+        } else {
+            None
+        };
+        Ok(Credentials {
+            ssh_key,
+            https,
+        })
+    }
+}
+
+#[tokio::test]
+async fn credentials_test() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::tempdir().unwrap();
+    crate::config::config_write().data_dir = dir.path().to_str().unwrap().to_string();
+    let app = OctApp::for_test("cred1");
+    app.dir().create_dirs().unwrap();
+    assert!(app.credentials().unwrap().ssh_key.is_none());
+
+    let public = app.deploy_public_key().await.unwrap();
+    assert!(public.starts_with("ssh-ed25519 "));
+    assert!(public.ends_with(" oct-cred1"));
+    let key = app.credentials().unwrap().ssh_key.unwrap();
+    assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+    app.generate_deploy_key().await.unwrap();
+    assert_ne!(app.deploy_public_key().await.unwrap(), public);
+
+    app.set_https_token(None, Some("t0ken")).unwrap();
+    assert!(app.has_https_token());
+    assert_eq!(app.credentials().unwrap().https, Some(("oauth2".to_string(), "t0ken".to_string())));
+    let entry = app.dir().child(HTTPS_CREDENTIAL);
+    assert_eq!(fs::metadata(entry.fullpath()).unwrap().permissions().mode() & 0o777, 0o600);
+    app.set_https_token(Some("bot"), Some("t1")).unwrap();
+    assert_eq!(app.credentials().unwrap().https, Some(("bot".to_string(), "t1".to_string())));
+    app.set_https_token(None, Some("")).unwrap();
+    assert!(!app.has_https_token());
+}
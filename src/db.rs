@@ -1,100 +1,440 @@
-use file_lock::FileLock;
+use std::sync::Mutex;
+use std::time::Duration;
 use rusqlite::{types::*, Connection};
+use tokio::sync::Semaphore;
 use crate::types::*;
 
 pub type DbValue = rusqlite::types::Value;
 
+/* A column of a table as reported by SQLite */
+#[derive(Debug, Clone)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub decl_type: String,
+    pub notnull: bool,
+}
+
+/* Connections kept open per database file, beyond that they're closed once used */
+const POOL_IDLE_MAX: usize = 4;
+/* Queries running at once per database file */
+const POOL_SIZE: usize = 16;
+/* How long a query waits for another connection's write lock before failing */
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const STATEMENT_CACHE_SIZE: usize = 64;
+
+/* The connections to one database file */
 #[derive(Debug)]
+struct Pool {
+    path: String,
+    idle: Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+}
+
+lazy_static! {
+    static ref POOLS: Mutex<HashMap<String, Arc<Pool>>> = Mutex::new(HashMap::new());
+}
+
+fn connect(path: &str) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+    conn.query_row("PRAGMA journal_mode=WAL", [], |_| Ok(()))?;
+    Ok(conn)
+}
+
+impl Pool {
+    fn with_conn<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        let conn = match self.idle.lock().unwrap().pop() {
+            Some(x) => x,
+            None => connect(&self.path)?,
+        };
+        let r = f(&conn);
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < POOL_IDLE_MAX {
+            idle.push(conn);
+        }
+        r
+    }
+}
+
+/* A database of an app. Handles of the same file share a pool of connections, queries run on the
+ * blocking threads of tokio rather than the ones serving requests. */
+#[derive(Debug, Clone)]
 pub struct DB {
-    conn: Connection,
-    lock: FileLock,
+    pool: Arc<Pool>,
 }
 
-// FIXME: thread safety of underlying db api
-unsafe impl Send for DB {}
-unsafe impl Sync for DB {}
+fn to_sql(params: &[DbValue]) -> Vec<&dyn ToSql> {
+    params.iter().map(|x| x as &dyn ToSql).collect()
+}
 
 impl DB {
     pub fn new(path: &str) -> Result<DB> {
-        let conn = Connection::open(path)?;
-        let lock = FileLock::lock(path, true, true)?;
+        let mut pools = POOLS.lock().unwrap();
+        if let Some(x) = pools.get(path) {
+            return Ok(DB {
+                pool: x.clone(),
+            });
+        }
+        /* Connect once to create the file and switch it to WAL before anyone uses it */
+        let conn = connect(path)?;
+        let pool = Arc::new(Pool {
+            path: path.to_string(),
+            idle: Mutex::new(vec![conn]),
+            permits: Arc::new(Semaphore::new(POOL_SIZE)),
+        });
+        pools.insert(path.to_string(), pool.clone());
         Ok(DB {
-            conn,
-            lock,
+            pool,
         })
     }
 
+    /* Drop the pool of the database file, once the file is deleted. Handles still in use keep
+     * the connections they have. */
+    pub fn close(path: &str) {
+        POOLS.lock().unwrap().remove(path);
+    }
+
+    /* Run f with a connection of the pool on a blocking thread */
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce(&Connection) -> Result<T> + Send + 'static {
+        let _permit = self.pool.permits.clone().acquire_owned().await?;
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || pool.with_conn(f)).await?
+    }
+
     pub async fn tables(&self) -> Result<Vec<String>> {
-        let sql = "SELECT name FROM sqlite_master WHERE type='table'";
-        let mut stmt = self.conn.prepare(sql)?;
-        let mut ret = Vec::new();
-        let rows = stmt.query_map([], |row| row.get(0))?;
-        for row in rows {
-            ret.push(row?);
-        }
-        Ok(ret)
-    }
-
-    fn do_query(&self, model: &ModelDef, cond: Option<&str>, max: Option<usize>) -> Result<Vec<Row>> {
-        let fields = model.fields.as_ref().unwrap_or(&Vec::new()).iter().map(|f| f.name()).collect::<Vec<_>>().join(",");
-        let mut stmt = self.conn.prepare(&format!("SELECT id, {} FROM {} WHERE {}",
-                fields, model.name, cond.unwrap_or("1")))?;
-        let rows = stmt.query_map([], |r| {
-            let mut row = Row::new();
-            for (fi, f) in model.fields.as_ref().unwrap_or(&Vec::new()).iter().enumerate() {
-                let i = fi + 1; // col 0 is id
-                match r.get_ref(i) {
-                    Ok(ValueRef::Null) => {
-                        row.fields.insert(f.name().to_string(), RowField::Null);
-                        continue;
-                    }
-                    _ => {}
-                }
-                let rf = match f {
-                    FieldDef::String(_) => RowField::String(r.get(i)?),
-                    FieldDef::Integer(_) => RowField::Integer(r.get(i)?),
-                    FieldDef::Boolean(_) => RowField::Boolean(r.get(i)?),
-                    FieldDef::Float(_) => RowField::Float(r.get(i)?),
-                    FieldDef::DateTime(_) => RowField::DateTime(r.get(i)?),
-                    FieldDef::User(_) => RowField::Integer(r.get(i)?),
-                    FieldDef::Reference(_) => RowField::Integer(r.get(i)?),
-                };
-                row.fields.insert(f.name().to_string(), rf);
+        self.run(|conn| {
+            let sql = "SELECT name FROM sqlite_master WHERE type='table'";
+            let mut stmt = conn.prepare_cached(sql)?;
+            let mut ret = Vec::new();
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            for row in rows {
+                ret.push(row?);
             }
-            row.fields.insert("id".to_string(), RowField::Integer(r.get(0)?));
-            Ok(row)
-        })?;
-        let mut ret = Vec::new();
-        let mut cnt = 0;
-        for row in rows {
-            let r = match row {
-                Ok(x) => x,
-                Err(e) => { println!("err: {}", e); bail!("error") },
-            };
-            ret.push(r);
-            if let Some(x) = max {
-                if cnt >= x {
-                    break;
-                }
+            Ok(ret)
+        }).await
+    }
+
+    pub async fn columns(&self, table: &str) -> Result<Vec<ColumnInfo>> {
+        let sql = format!("PRAGMA table_info({})", table);
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map([], |r| Ok(ColumnInfo {
+                name: r.get(1)?,
+                decl_type: r.get(2)?,
+                notnull: r.get(3)?,
+            }))?;
+            let mut ret = Vec::new();
+            for row in rows {
+                ret.push(row?);
+            }
+            Ok(ret)
+        }).await
+    }
+
+    pub async fn row_count(&self, table: &str) -> Result<usize> {
+        let sql = format!("SELECT COUNT(*) FROM {}", table);
+        self.run(move |conn| {
+            let n: i64 = conn.query_row(&sql, [], |r| r.get(0))?;
+            Ok(n as usize)
+        }).await
+    }
+
+    /* Execute the statements in one transaction, nothing is changed if any of them fails */
+    pub async fn execute_in_transaction(&self, stmts: &[String]) -> Result<()> {
+        let stmts = stmts.to_vec();
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            for sql in &stmts {
+                tx.execute(sql, [])?;
+            }
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    /* Write a consistent copy of the database to a new file */
+    pub async fn backup_to(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        self.run(move |conn| {
+            conn.execute("VACUUM INTO ?", [path])?;
+            Ok(())
+        }).await
+    }
+
+    /* Replace the database with a copy made by backup_to. Waits for the running queries and
+     * closes all connections first, so no one sees the file change under them. */
+    pub async fn restore_from(&self, path: &str) -> Result<()> {
+        let _permits = self.pool.permits.clone().acquire_many_owned(POOL_SIZE as u32).await?;
+        let pool = self.pool.clone();
+        let src = path.to_string();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            pool.idle.lock().unwrap().clear();
+            for suffix in ["-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", pool.path, suffix));
             }
-            cnt += 1;
+            std::fs::copy(&src, &pool.path)?;
+            Ok(())
+        }).await??;
+        /* Later handles get a new pool, unless one was made for the file in the meantime */
+        let mut pools = POOLS.lock().unwrap();
+        if pools.get(&self.pool.path).is_some_and(|x| Arc::ptr_eq(x, &self.pool)) {
+            pools.remove(&self.pool.path);
         }
-        Ok(ret)
+        Ok(())
     }
 
-    pub fn query(&self, model: &ModelDef, cond: &str) -> Result<Vec<Row>> {
-        self.do_query(model, Some(cond), None)
+    fn map_row(model: &ModelDef, r: &rusqlite::Row) -> rusqlite::Result<Row> {
+        let mut row = Row::new();
+        for (fi, f) in model.fields.as_ref().unwrap_or(&Vec::new()).iter().enumerate() {
+            let i = fi + 1; // col 0 is id
+            if let Ok(ValueRef::Null) = r.get_ref(i) {
+                row.fields.insert(f.name().to_string(), RowField::Null);
+                continue;
+            }
+            let rf = match f {
+                FieldDef::String(_) => RowField::String(r.get(i)?),
+                FieldDef::Integer(_) => RowField::Integer(r.get(i)?),
+                FieldDef::Boolean(_) => RowField::Boolean(r.get(i)?),
+                FieldDef::Float(_) => RowField::Float(r.get(i)?),
+                FieldDef::DateTime(_) => RowField::DateTime(r.get(i)?),
+                FieldDef::User(_) => RowField::Integer(r.get(i)?),
+                FieldDef::Reference(_) => RowField::Integer(r.get(i)?),
+            };
+            row.fields.insert(f.name().to_string(), rf);
+        }
+        row.fields.insert("id".to_string(), RowField::Integer(r.get(0)?));
+        Ok(row)
+    }
+
+    /* Rows of the model selected by q */
+    pub async fn select(&self, q: &Query<'_>) -> Result<Vec<Row>> {
+        let st = q.select();
+        let model = q.model.clone();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(&st.sql)?;
+            let rows = stmt.query_map(to_sql(&st.params).as_slice(), |r| Self::map_row(&model, r))?;
+            let mut ret = Vec::new();
+            for row in rows {
+                ret.push(row?);
+            }
+            Ok(ret)
+        }).await
     }
 
-    pub fn get(&self, model: &ModelDef, cond: &str) -> Result<Option<Row>> {
-        let mut r = self.do_query(model, Some(cond), Some(1))?;
+    /* Number of rows matching q, regardless of its limit and offset */
+    pub async fn count(&self, q: &Query<'_>) -> Result<usize> {
+        let st = q.count();
+        self.run(move |conn| {
+            let n: i64 = conn.prepare_cached(&st.sql)?.query_row(to_sql(&st.params).as_slice(), |r| r.get(0))?;
+            Ok(n as usize)
+        }).await
+    }
+
+    pub async fn get(&self, q: &Query<'_>) -> Result<Option<Row>> {
+        let mut q = q.clone();
+        q.limit = Some(1);
+        let mut r = self.select(&q).await?;
         Ok(r.pop())
     }
 
-    pub fn execute(&self, sql: &str, values: &[DbValue]) -> Result<usize> {
-        let vals: Vec<&dyn ToSql> = values.iter().map(|x| x as &dyn ToSql).collect();
-        let r = self.conn.execute(&sql, vals.as_slice())?;
-        Ok(r)
+    pub async fn execute(&self, sql: &str, values: &[DbValue]) -> Result<usize> {
+        let sql = sql.to_string();
+        let values = values.to_vec();
+        self.run(move |conn| {
+            let r = conn.prepare_cached(&sql)?.execute(to_sql(&values).as_slice())?;
+            Ok(r)
+        }).await
+    }
+
+    /* Execute an INSERT statement and return the rowid of the new row */
+    pub async fn insert(&self, sql: &str, values: &[DbValue]) -> Result<i64> {
+        let sql = sql.to_string();
+        let values = values.to_vec();
+        self.run(move |conn| {
+            conn.prepare_cached(&sql)?.execute(to_sql(&values).as_slice())?;
+            Ok(conn.last_insert_rowid())
+        }).await
+    }
+}
+
+/* An SQL statement with its values bound as parameters */
+#[derive(Debug, PartialEq)]
+pub struct Statement {
+    pub sql: String,
+    pub params: Vec<DbValue>,
+}
+
+/*
+ * Building the statements on the table of a model. Every column is looked up in the model and
+ * written as the model names it, every value is bound as a parameter, so nothing from a request
+ * ends up in the SQL text.
+ */
+#[derive(Debug, Clone)]
+pub struct Query<'a> {
+    model: &'a ModelDef,
+    conds: Vec<String>,
+    params: Vec<DbValue>,
+    order: Vec<String>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+/* Columns every model table has besides its fields */
+const SYSTEM_COLUMNS: [&str; 2] = ["id", "_oct_owner"];
+
+impl<'a> Query<'a> {
+    pub fn new(model: &'a ModelDef) -> Query<'a> {
+        Query {
+            model,
+            conds: Vec::new(),
+            params: Vec::new(),
+            order: Vec::new(),
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    fn column(&self, name: &str) -> Result<&'a str> {
+        if let Some(x) = SYSTEM_COLUMNS.iter().find(|x| **x == name) {
+            return Ok(x);
+        }
+        match self.model.fields.iter().flatten().find(|f| f.name() == name) {
+            Some(f) => Ok(f.name()),
+            None => bail!("Unknown field: {}", name),
+        }
+    }
+
+    fn bind(&mut self, v: &RowField) -> &'static str {
+        self.params.push(v.to_db_value());
+        "?"
+    }
+
+    pub fn filter(&mut self, field: &str, op: &FilterOp) -> Result<()> {
+        let col = self.column(field)?;
+        let cond = match op {
+            FilterOp::Eq(v) => format!("{}={}", col, self.bind(v)),
+            FilterOp::Ne(v) => format!("{} IS NOT {}", col, self.bind(v)),
+            FilterOp::Lt(v) => format!("{}<{}", col, self.bind(v)),
+            FilterOp::Gt(v) => format!("{}>{}", col, self.bind(v)),
+            FilterOp::In(vs) if vs.is_empty() => "0".to_string(),
+            FilterOp::In(vs) => {
+                let marks: Vec<&str> = vs.iter().map(|v| self.bind(v)).collect();
+                format!("{} IN ({})", col, marks.join(","))
+            },
+            FilterOp::Like(v) => format!("{} LIKE {}", col, self.bind(&RowField::String(v.to_string()))),
+            FilterOp::IsNull(true) => format!("{} IS NULL", col),
+            FilterOp::IsNull(false) => format!("{} IS NOT NULL", col),
+        };
+        self.conds.push(cond);
+        Ok(())
+    }
+
+    pub fn eq(&mut self, field: &str, v: RowField) -> Result<()> {
+        self.filter(field, &FilterOp::Eq(v))
+    }
+
+    pub fn order_by(&mut self, field: &str, desc: bool) -> Result<()> {
+        let col = self.column(field)?;
+        self.order.push(format!("{} {}", col, if desc { "DESC" } else { "ASC" }));
+        Ok(())
+    }
+
+    /* Keep the rows sorted after the one whose keys have values, for paging by cursor */
+    pub fn after(&mut self, keys: &[SortKey], values: &[RowField]) -> Result<()> {
+        let mut alts = Vec::new();
+        for (i, (k, v)) in keys.iter().zip(values).enumerate() {
+            let mut conds = Vec::new();
+            for (pk, pv) in keys[..i].iter().zip(values) {
+                conds.push(format!("{} IS {}", self.column(&pk.field)?, self.bind(pv)));
+            }
+            let col = self.column(&k.field)?;
+            /* SQLite sorts NULL before any other value */
+            conds.push(match (v, k.desc) {
+                (RowField::Null, false) => format!("{} IS NOT NULL", col),
+                (RowField::Null, true) => "0".to_string(),
+                (_, false) => format!("{}>{}", col, self.bind(v)),
+                (_, true) => format!("({}<{} OR {} IS NULL)", col, self.bind(v), col),
+            });
+            alts.push(format!("({})", conds.join(" AND ")));
+        }
+        if !alts.is_empty() {
+            self.conds.push(format!("({})", alts.join(" OR ")));
+        }
+        Ok(())
+    }
+
+    fn where_sql(&self) -> String {
+        if self.conds.is_empty() {
+            "1".to_string()
+        } else {
+            self.conds.join(" AND ")
+        }
+    }
+
+    fn statement(&self, sql: String) -> Statement {
+        Statement {
+            sql,
+            params: self.params.clone(),
+        }
+    }
+
+    pub fn select(&self) -> Statement {
+        let mut cols = vec!["id"];
+        cols.extend(self.model.fields.iter().flatten().map(|f| f.name()));
+        let mut order = self.order.clone();
+        /* Always end with id so that pages are stable */
+        if !order.iter().any(|x| x.starts_with("id ")) {
+            order.push("id ASC".to_string());
+        }
+        let mut sql = format!("SELECT {} FROM {} WHERE {} ORDER BY {}",
+                              cols.join(","), self.model.name, self.where_sql(), order.join(", "));
+        /* SQLite only accepts OFFSET after a LIMIT clause, -1 means no limit */
+        match self.limit {
+            Some(n) => sql += &format!(" LIMIT {} OFFSET {}", n, self.offset),
+            None if self.offset > 0 => sql += &format!(" LIMIT -1 OFFSET {}", self.offset),
+            None => (),
+        }
+        self.statement(sql)
+    }
+
+    pub fn count(&self) -> Statement {
+        self.statement(format!("SELECT COUNT(*) FROM {} WHERE {}", self.model.name, self.where_sql()))
+    }
+
+    pub fn delete(&self) -> Statement {
+        self.statement(format!("DELETE FROM {} WHERE {}", self.model.name, self.where_sql()))
+    }
+
+    /* Set the columns of the selected rows to values */
+    pub fn update(&self, values: &[(&str, RowField)]) -> Result<Statement> {
+        let mut sets = Vec::new();
+        let mut params = Vec::new();
+        for (k, v) in values {
+            sets.push(format!("{}=?", self.column(k)?));
+            params.push(v.to_db_value());
+        }
+        params.extend(self.params.iter().cloned());
+        Ok(Statement {
+            sql: format!("UPDATE {} SET {} WHERE {}", self.model.name, sets.join(","), self.where_sql()),
+            params,
+        })
+    }
+
+    /* Insert a row with the columns set to values */
+    pub fn insert(&self, values: &[(&str, RowField)]) -> Result<Statement> {
+        let mut cols = Vec::new();
+        let mut params = Vec::new();
+        for (k, v) in values {
+            cols.push(self.column(k)?);
+            params.push(v.to_db_value());
+        }
+        Ok(Statement {
+            sql: format!("INSERT INTO {} ({}) VALUES ({})", self.model.name, cols.join(","),
+                         vec!["?"; cols.len()].join(",")),
+            params,
+        })
     }
 }
 
@@ -110,3 +450,77 @@ impl RowField {
         }
     }
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pool_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.sqlite").to_str().unwrap().to_string();
+    let db = DB::new(&path).unwrap();
+    db.execute("CREATE TABLE t (n INTEGER)", &[]).await.unwrap();
+    let mode: String = db.run(|c| Ok(c.query_row("PRAGMA journal_mode", [], |r| r.get(0))?)).await.unwrap();
+    assert_eq!(mode, "wal");
+
+    let tasks: Vec<_> = (0..32).map(|i| {
+        let db = DB::new(&path).unwrap();
+        tokio::spawn(async move { db.insert("INSERT INTO t (n) VALUES (?)", &[DbValue::Integer(i)]).await })
+    }).collect();
+    for t in tasks {
+        t.await.unwrap().unwrap();
+    }
+    assert_eq!(db.row_count("t").await.unwrap(), 32);
+
+    let snapshot = dir.path().join("snapshot.sqlite").to_str().unwrap().to_string();
+    db.backup_to(&snapshot).await.unwrap();
+    db.execute("DELETE FROM t", &[]).await.unwrap();
+    db.restore_from(&snapshot).await.unwrap();
+    assert_eq!(db.row_count("t").await.unwrap(), 32);
+    let fresh = DB::new(&path).unwrap();
+    assert!(!Arc::ptr_eq(&fresh.pool, &db.pool));
+    assert_eq!(fresh.row_count("t").await.unwrap(), 32);
+    DB::close(&path);
+    assert!(!POOLS.lock().unwrap().contains_key(&path));
+}
+
+#[test]
+fn query_test() {
+    let model: ModelDef = serde_yaml::from_str("
+name: todo
+fields:
+  - name: subject
+    type: string
+  - name: done
+    type: boolean
+").unwrap();
+    let mut q = Query::new(&model);
+    q.eq("subject", RowField::String("x' OR '1'='1".to_string())).unwrap();
+    q.filter("id", &FilterOp::In(vec![RowField::Integer(1), RowField::Integer(2)])).unwrap();
+    q.order_by("done", true).unwrap();
+    assert!(q.eq("subject=subject OR 1", RowField::Null).is_err());
+    assert!(q.order_by("id; DROP TABLE todo", false).is_err());
+    q.limit = Some(10);
+    assert_eq!(q.select(), Statement {
+        sql: "SELECT id,subject,done FROM todo WHERE subject=? AND id IN (?,?) ORDER BY done DESC, id ASC LIMIT 10 OFFSET 0".to_string(),
+        params: vec![DbValue::Text("x' OR '1'='1".to_string()), DbValue::Integer(1), DbValue::Integer(2)],
+    });
+    assert_eq!(q.count().sql, "SELECT COUNT(*) FROM todo WHERE subject=? AND id IN (?,?)");
+
+    let st = q.update(&[("done", RowField::Boolean(true))]).unwrap();
+    assert_eq!(st.sql, "UPDATE todo SET done=? WHERE subject=? AND id IN (?,?)");
+    assert_eq!(st.params[0], DbValue::Integer(1));
+    assert!(q.update(&[("done=1, subject", RowField::Null)]).is_err());
+
+    let st = Query::new(&model).insert(&[("_oct_owner", RowField::Integer(3)), ("subject", RowField::Null)]).unwrap();
+    assert_eq!(st.sql, "INSERT INTO todo (_oct_owner,subject) VALUES (?,?)");
+    assert_eq!(Query::new(&model).delete().sql, "DELETE FROM todo WHERE 1");
+
+    let keys = [SortKey { field: "done".to_string(), desc: true }, SortKey { field: "id".to_string(), desc: false }];
+    let mut q = Query::new(&model);
+    q.after(&keys, &[RowField::Boolean(true), RowField::Integer(4)]).unwrap();
+    assert_eq!(q.count(), Statement {
+        sql: "SELECT COUNT(*) FROM todo WHERE (((done<? OR done IS NULL)) OR (done IS ? AND id>?))".to_string(),
+        params: vec![DbValue::Integer(1), DbValue::Integer(1), DbValue::Integer(4)],
+    });
+    let mut q = Query::new(&model);
+    q.after(&keys, &[RowField::Null, RowField::Integer(4)]).unwrap();
+    assert_eq!(q.count().sql, "SELECT COUNT(*) FROM todo WHERE ((0) OR (done IS ? AND id>?))");
+}
@@ -0,0 +1,347 @@
+use std::fs;
+use std::collections::HashSet;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use chrono::Utc;
+use crate::types::*;
+use crate::stor::Entry;
+use crate::db::DB;
+use crate::migrate::{self, MigrationPlan, MigrationStep};
+
+/*
+ * Every sync and rollback is recorded as a numbered deployment of the app, together with a copy
+ * of the repo it activated. A deployment that changed the schema also keeps the database as it
+ * was right before, which is what rolling back past it restores.
+ */
+
+const DEPLOYMENTS_DIR: &str = "deployments";
+const DEPLOYMENT_FILE: &str = "deployment.json";
+const REPO_COPY: &str = "repo";
+const DB_SNAPSHOT: &str = "db-before.sqlite";
+/* Directory the repo of the rolled back deployment is copied to before it's activated */
+const ROLLBACK_WIP: &str = "rollback-wip";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Deployment {
+    pub id: u32,
+    pub commit: Option<String>,
+    pub time: DateTime,
+    pub triggered_by: String,
+    pub app_yml: String,
+    pub migration: Vec<MigrationStep>,
+    /* Whether the database from before this deployment is kept */
+    pub db_snapshot: bool,
+    /* The deployment this one rolled back to */
+    pub rollback_of: Option<u32>,
+}
+
+impl Deployment {
+    fn changed_schema(&self) -> bool {
+        self.db_snapshot || !self.migration.is_empty()
+    }
+}
+
+/* A deployment that is being made, it's listed once saved */
+pub struct PendingDeployment {
+    dir: Entry,
+    pub record: Deployment,
+}
+
+fn deployments_dir(app: &OctApp) -> Entry {
+    app.dir().child(DEPLOYMENTS_DIR)
+}
+
+fn deployment_dir(app: &OctApp, id: u32) -> Entry {
+    deployments_dir(app).child(&id.to_string())
+}
+
+/* Ids of all deployment directories, including ones that failed before being saved */
+fn deployment_ids(app: &OctApp) -> Result<Vec<u32>> {
+    let mut ids: Vec<u32> = deployments_dir(app).listdir()?
+        .iter()
+        .filter_map(|x| x.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+pub async fn list_deployments(app: &OctApp) -> Result<Vec<Deployment>> {
+    let mut ret = Vec::new();
+    for id in deployment_ids(app)? {
+        let f = deployment_dir(app, id).child(DEPLOYMENT_FILE);
+        if f.exists() {
+            ret.push(serde_json::from_str(&f.read().await?)?);
+        }
+    }
+    Ok(ret)
+}
+
+/* The commit deployed by the last sync, i.e. the head of the remote as of then. None if the app
+ * has no deployments, or only rollbacks are kept. */
+pub fn synced_commit(deployments: &[Deployment]) -> Option<&str> {
+    deployments.iter()
+        .filter(|x| x.rollback_of.is_none())
+        .max_by_key(|x| x.id)
+        .and_then(|x| x.commit.as_deref())
+}
+
+/* Copy a file or directory tree, keeping symlinks as they are and the permissions */
+fn copy_tree(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    if meta.file_type().is_symlink() {
+        symlink(fs::read_link(src)?, dst)
+    } else if meta.is_dir() {
+        fs::create_dir(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        fs::set_permissions(dst, meta.permissions())
+    } else {
+        fs::copy(src, dst).map(|_| ())
+    }
+}
+
+async fn copy_dir(src: &Entry, dst: &Entry) -> Result<()> {
+    let (src, dst) = (src.fullpath(), dst.fullpath());
+    tokio::task::spawn_blocking(move || {
+        copy_tree(Path::new(&src), Path::new(&dst))
+            .map_err(|e| anyhow!("Failed to copy {}: {}", src, e))
+    }).await?
+}
+
+/* The deployments to remove so that the newest keep saved ones remain */
+fn pruned_ids(all: &[u32], saved: &[u32], keep: usize) -> Vec<u32> {
+    let kept: HashSet<u32> = saved.iter().rev().take(keep.max(1)).cloned().collect();
+    all.iter().filter(|x| !kept.contains(x)).cloned().collect()
+}
+
+async fn prune(app: &OctApp) -> Result<()> {
+    let saved: Vec<u32> = list_deployments(app).await?.iter().map(|x| x.id).collect();
+    let keep = config().deployment_retention;
+    for id in pruned_ids(&deployment_ids(app)?, &saved, keep) {
+        fs::remove_dir_all(deployment_dir(app, id).fullpath())?;
+    }
+    Ok(())
+}
+
+impl PendingDeployment {
+    pub fn begin(app: &OctApp, triggered_by: &str) -> Result<PendingDeployment> {
+        let id = deployment_ids(app)?.last().map_or(1, |x| x + 1);
+        let dir = deployment_dir(app, id);
+        dir.create_dirs()?;
+        Ok(PendingDeployment {
+            dir,
+            record: Deployment {
+                id,
+                commit: None,
+                time: Utc::now(),
+                triggered_by: triggered_by.to_string(),
+                app_yml: String::new(),
+                migration: Vec::new(),
+                db_snapshot: false,
+                rollback_of: None,
+            },
+        })
+    }
+
+    /* Keep the database as it is now, before the deployment changes it */
+    pub async fn snapshot_db(&mut self, db: &DB) -> Result<()> {
+        db.backup_to(&self.dir.child(DB_SNAPSHOT).fullpath()).await?;
+        self.record.db_snapshot = true;
+        Ok(())
+    }
+
+    /* Record the deployment, to be called once its database changes are applied */
+    pub async fn save(&self) -> Result<()> {
+        self.dir.child(DEPLOYMENT_FILE).write_json(&self.record).await
+    }
+
+    /* Keep a copy of the activated repo and drop deployments beyond the retention count */
+    pub async fn finish(self, app: &OctApp) -> Result<Deployment> {
+        copy_dir(&app.repo(), &self.dir.child(REPO_COPY)).await?;
+        prune(app).await?;
+        Ok(self.record)
+    }
+}
+
+/* The deployment whose database snapshot has the schema of target, if one changed it since */
+fn restore_point(deployments: &[Deployment], target: u32) -> Option<u32> {
+    deployments.iter()
+        .filter(|x| x.id > target && x.changed_schema())
+        .map(|x| x.id)
+        .min()
+}
+
+/* How the database gets back to the schema of the deployment rolled back to */
+#[derive(Debug, PartialEq)]
+enum DbRollback {
+    /* The schema is that of the target already, e.g. after a change that was reverted */
+    Keep,
+    /* Restore the database from before this deployment, losing what was written since */
+    Restore(u32),
+    /* Migrate to the models of the target, when no snapshot has its schema */
+    Migrate(MigrationPlan),
+}
+
+/* Decide given the migration from the database as it is now to the models of target */
+fn db_rollback(deployments: &[Deployment], target: u32, forward: MigrationPlan) -> DbRollback {
+    if forward.steps.is_empty() {
+        return DbRollback::Keep;
+    }
+    match restore_point(deployments, target) {
+        Some(k) => DbRollback::Restore(k),
+        None => DbRollback::Migrate(forward),
+    }
+}
+
+pub async fn rollback(app: &OctApp, target: u32, triggered_by: &str) -> Result<Deployment> {
+    let deployments = list_deployments(app).await?;
+    let t = match deployments.iter().find(|x| x.id == target) {
+        Some(x) => x,
+        None => bail!(HttpError::not_found("Deployment not found")),
+    };
+    if deployments.last().map(|x| x.id) == Some(target) {
+        bail!(HttpError::bad_request("Deployment is already the current one"));
+    }
+    let src = deployment_dir(app, target).child(REPO_COPY);
+    if !src.exists() {
+        bail!(HttpError::bad_request(&format!("Deployment {} can't be restored", target)));
+    }
+    let target_def = AppDef::parse_yaml(&t.app_yml)?;
+    let db = app.db()?;
+    let forward = migrate::plan(&migrate::snapshot(&db).await?, &target_def)?;
+    let action = db_rollback(&deployments, target, forward);
+    match &action {
+        DbRollback::Restore(k) if !deployment_dir(app, *k).child(DB_SNAPSHOT).exists() => {
+            bail!(HttpError::bad_request(&format!("Database from before deployment {} is not kept", k)));
+        },
+        DbRollback::Migrate(plan) => plan.check(&target_def)?,
+        _ => (),
+    }
+
+    let next = app.dir().child(ROLLBACK_WIP);
+    let _ = fs::remove_dir_all(next.fullpath());
+    copy_dir(&src, &next).await?;
+    let mut dep = PendingDeployment::begin(app, triggered_by)?;
+    dep.record.commit = t.commit.clone();
+    dep.record.app_yml = t.app_yml.clone();
+    dep.record.rollback_of = Some(target);
+    match action {
+        DbRollback::Keep => (),
+        DbRollback::Restore(k) => {
+            let _ = app.event(&format!("Restoring database from before deployment {}...", k)).await;
+            dep.snapshot_db(&db).await?;
+            db.restore_from(&deployment_dir(app, k).child(DB_SNAPSHOT).fullpath()).await?;
+            dep.record.migration.push(MigrationStep {
+                description: format!("Restore database from before deployment {}", k),
+                destructive: true,
+                sql: Vec::new(),
+            });
+        },
+        DbRollback::Migrate(plan) => {
+            let _ = app.event("Migrating database...").await;
+            dep.snapshot_db(&db).await?;
+            plan.apply(&db).await?;
+            dep.record.migration = plan.steps;
+        },
+    }
+    dep.save().await?;
+    let _ = app.event(&format!("Activating deployment {}...", target)).await;
+    let repod = app.repo();
+    let _ = fs::remove_dir_all(repod.fullpath());
+    fs::rename(next.fullpath(), repod.fullpath())?;
+    let _ = app.event(&format!("Done, rolled back to deployment {}", target)).await;
+    dep.finish(app).await
+}
+
+#[test]
+fn restore_point_test() {
+    let dep = |id, migration: usize, db_snapshot| Deployment {
+        id,
+        commit: None,
+        time: Utc::now(),
+        triggered_by: "u".to_string(),
+        app_yml: String::new(),
+        migration: (0..migration).map(|i| MigrationStep {
+            description: i.to_string(),
+            destructive: false,
+            sql: Vec::new(),
+        }).collect(),
+        db_snapshot,
+        rollback_of: None,
+    };
+    let deps = vec![dep(1, 1, false), dep(2, 0, false), dep(3, 2, true), dep(4, 0, false), dep(5, 1, true)];
+    assert_eq!(restore_point(&deps, 1), Some(3));
+    assert_eq!(restore_point(&deps, 2), Some(3));
+    assert_eq!(restore_point(&deps, 3), Some(5));
+    assert_eq!(restore_point(&deps, 4), Some(5));
+    assert_eq!(restore_point(&deps[..4], 3), None);
+}
+
+#[test]
+fn synced_commit_test() {
+    let dep = |id, commit: &str, rollback_of| Deployment {
+        id,
+        commit: Some(commit.to_string()),
+        time: Utc::now(),
+        triggered_by: "u".to_string(),
+        app_yml: String::new(),
+        migration: Vec::new(),
+        db_snapshot: false,
+        rollback_of,
+    };
+    let mut deps = vec![dep(1, "a", None), dep(2, "b", None)];
+    assert_eq!(synced_commit(&deps), Some("b"));
+    deps.push(dep(3, "a", Some(1)));
+    assert_eq!(synced_commit(&deps), Some("b"));
+    assert_eq!(synced_commit(&deps[2..]), None);
+}
+
+#[test]
+fn db_rollback_test() {
+    let dep = |id, db_snapshot| Deployment {
+        id,
+        commit: None,
+        time: Utc::now(),
+        triggered_by: "u".to_string(),
+        app_yml: String::new(),
+        migration: Vec::new(),
+        db_snapshot,
+        rollback_of: None,
+    };
+    let step = MigrationStep {
+        description: "add column".to_string(),
+        destructive: false,
+        sql: vec!["ALTER TABLE t ADD COLUMN x BIGINT".to_string()],
+    };
+    let changed = || MigrationPlan {
+        steps: vec![step.clone()],
+    };
+    let deps = vec![dep(1, false), dep(2, true), dep(3, true)];
+    /* 3 reverted the change of 2, nothing to restore for going back to 1 */
+    assert_eq!(db_rollback(&deps, 1, MigrationPlan::default()), DbRollback::Keep);
+    assert_eq!(db_rollback(&deps, 1, changed()), DbRollback::Restore(2));
+    assert_eq!(db_rollback(&deps[..2], 2, changed()), DbRollback::Migrate(changed()));
+}
+
+#[test]
+fn pruned_ids_test() {
+    assert_eq!(pruned_ids(&[1, 2, 3, 4, 5], &[1, 2, 4, 5], 2), vec![1, 2, 3]);
+    assert_eq!(pruned_ids(&[1, 2, 3], &[1, 2, 3], 10), Vec::<u32>::new());
+    assert_eq!(pruned_ids(&[1, 2], &[1, 2], 0), vec![1]);
+}
+
+#[test]
+fn copy_tree_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("sub/a.yml"), "a").unwrap();
+    symlink("sub/a.yml", src.join("link")).unwrap();
+    let dst = dir.path().join("dst");
+    copy_tree(&src, &dst).unwrap();
+    assert_eq!(fs::read_to_string(dst.join("sub/a.yml")).unwrap(), "a");
+    assert_eq!(fs::read_link(dst.join("link")).unwrap(), Path::new("sub/a.yml"));
+    assert!(copy_tree(&src, &dst).is_err());
+}
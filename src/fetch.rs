@@ -0,0 +1,356 @@
+use std::cell::Cell;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use git2::{AutotagOption, Cred, CredentialType, Direction, FetchOptions, ObjectType, Oid, Remote,
+           RemoteCallbacks, Repository, TreeWalkMode, TreeWalkResult};
+use git2::build::CheckoutBuilder;
+use tokio::sync::watch;
+use crate::types::*;
+use crate::stor::Entry;
+use crate::credentials::Credentials;
+
+/*
+ * Fetching app repos with libgit2 instead of the git command. Only a single commit of the wanted
+ * ref is fetched and checked out, into a fresh directory. The fetch gives up after a timeout, when
+ * the data received or the files to check out exceed the size limit, or when it's cancelled.
+ */
+
+/* Local ref the fetched commit is stored as */
+const FETCH_HEAD: &str = "refs/oct/fetched";
+
+#[derive(Debug, Clone, Copy)]
+pub struct FetchLimits {
+    pub timeout: Duration,
+    pub max_size: u64,
+}
+
+impl FetchLimits {
+    pub fn from_config() -> FetchLimits {
+        let cfg = config();
+        FetchLimits {
+            timeout: Duration::from_secs(cfg.fetch_timeout),
+            max_size: cfg.max_repo_size,
+        }
+    }
+}
+
+/* Only URLs of the transports we support, git would run programs for some others such as ext::.
+ * Repos on this server are only for when allow_local is set. */
+fn url_allowed(url: &str, allow_local: bool) -> Result<()> {
+    let scp_like = url.split_once(':').is_some_and(|(host, path)| {
+        host.contains('@') && !host.contains('/') && !path.starts_with("//")
+    });
+    if url.starts_with("https://") || url.starts_with("ssh://") || scp_like {
+        Ok(())
+    } else if url.starts_with("file://") || url.starts_with('/') {
+        if !allow_local {
+            bail!("Repos on this server are not allowed, use https:// or ssh://");
+        }
+        Ok(())
+    } else {
+        bail!("Unsupported git repo URL, use https:// or ssh://");
+    }
+}
+
+pub fn check_url(url: &str) -> Result<()> {
+    url_allowed(url, config().allow_local_repos)
+}
+
+/* Ref names on the remote that git_ref may refer to, in order of preference */
+fn candidate_refs(git_ref: &str) -> Vec<String> {
+    if git_ref.starts_with("refs/") {
+        vec![git_ref.to_string()]
+    } else {
+        vec![format!("refs/heads/{}", git_ref), format!("refs/tags/{}", git_ref)]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Guard<'a> {
+    deadline: Instant,
+    max_size: u64,
+    cancel: &'a watch::Receiver<bool>,
+    /* Why the transfer was stopped by us, to report instead of libgit2's error */
+    stopped: &'a Cell<Option<&'static str>>,
+    creds: &'a Credentials,
+    /* Credentials are offered once, libgit2 asks again as long as they're rejected */
+    offered: &'a Cell<bool>,
+}
+
+impl<'a> Guard<'a> {
+    fn check(&self, received: u64) -> bool {
+        let reason = if *self.cancel.borrow() {
+            "Sync cancelled"
+        } else if Instant::now() > self.deadline {
+            "Timed out fetching repo"
+        } else if received > self.max_size {
+            "Repo exceeds the size limit"
+        } else {
+            return true;
+        };
+        self.stopped.set(Some(reason));
+        false
+    }
+
+    fn callbacks(&self) -> RemoteCallbacks<'a> {
+        let mut cb = RemoteCallbacks::new();
+        let guard = *self;
+        cb.transfer_progress(move |p| guard.check(p.received_bytes() as u64));
+        cb.sideband_progress(move |_| guard.check(0));
+        cb.credentials(move |_, username, allowed| guard.credential(username, allowed));
+        cb
+    }
+
+    fn credential(&self, username: Option<&str>, allowed: CredentialType) -> std::result::Result<Cred, git2::Error> {
+        if self.offered.replace(true) {
+            return Err(git2::Error::from_str("Authentication failed"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if let Some(key) = &self.creds.ssh_key {
+                return Cred::ssh_key(username.unwrap_or("git"), None, key, None);
+            }
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some((user, token)) = &self.creds.https {
+                return Cred::userpass_plaintext(user, token);
+            }
+        }
+        Err(git2::Error::from_str("No credentials for repo, add the deploy key or an HTTPS token"))
+    }
+
+    fn error(&self, e: git2::Error) -> Error {
+        match self.stopped.get() {
+            Some(reason) => anyhow!(reason),
+            None => e.into(),
+        }
+    }
+}
+
+/* Total size of the files in the tree of the commit */
+fn checkout_size(repo: &Repository, commit: Oid) -> Result<u64> {
+    let odb = repo.odb()?;
+    let tree = repo.find_commit(commit)?.tree()?;
+    let mut size = 0;
+    let mut err = None;
+    tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            match odb.read_header(entry.id()) {
+                Ok((n, _)) => size += n as u64,
+                Err(e) => {
+                    err = Some(e);
+                    return TreeWalkResult::Abort;
+                }
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+    if let Some(e) = err {
+        return Err(e.into());
+    }
+    Ok(size)
+}
+
+/* Fetch git_ref, or the default branch, of url and check it out in dest. Returns the commit. */
+pub fn fetch_blocking(url: &str, git_ref: Option<&str>, dest: &Path, creds: &Credentials,
+                      limits: FetchLimits, cancel: &watch::Receiver<bool>) -> Result<String> {
+    check_url(url)?;
+    let stopped = Cell::new(None);
+    let offered = Cell::new(false);
+    let guard = Guard {
+        deadline: Instant::now() + limits.timeout,
+        max_size: limits.max_size,
+        cancel,
+        stopped: &stopped,
+        creds,
+        offered: &offered,
+    };
+    if !guard.check(0) {
+        bail!(stopped.get().unwrap_or_default());
+    }
+    let repo = Repository::init(dest)?;
+    let mut remote = repo.remote_anonymous(url)?;
+    let refname = {
+        let conn = remote.connect_auth(Direction::Fetch, Some(guard.callbacks()), None)
+            .map_err(|e| guard.error(e))?;
+        let heads: Vec<String> = conn.list()?.iter().map(|h| h.name().to_string()).collect();
+        match git_ref.filter(|x| !x.is_empty()) {
+            Some(r) => match candidate_refs(r).into_iter().find(|x| heads.contains(x)) {
+                Some(x) => x,
+                None => bail!("Ref {} not found in repo", r),
+            },
+            None => match conn.default_branch() {
+                Ok(x) => x.as_str().unwrap_or_default().to_string(),
+                Err(_) => bail!("Repo has no default branch"),
+            },
+        }
+    };
+    offered.set(false);
+    let mut opts = FetchOptions::new();
+    opts.remote_callbacks(guard.callbacks());
+    opts.download_tags(AutotagOption::None);
+    let refspec = format!("+{}:{}", refname, FETCH_HEAD);
+    remote.fetch(&[&refspec], Some(&mut opts), None)
+        .map_err(|e| guard.error(e))?;
+    let commit = repo.revparse_single(FETCH_HEAD)?.peel_to_commit()?.id();
+    if checkout_size(&repo, commit)? > limits.max_size {
+        bail!("Repo exceeds the size limit");
+    }
+    repo.set_head_detached(commit)?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    Ok(commit.to_string())
+}
+
+/* Fetch the repo into dest, see fetch_blocking. A failed fetch leaves nothing behind. */
+pub async fn fetch_repo(url: &str, git_ref: Option<&str>, dest: &Entry, creds: Credentials,
+                        limits: FetchLimits, cancel: watch::Receiver<bool>) -> Result<String> {
+    let url = url.to_string();
+    let git_ref = git_ref.map(|x| x.to_string());
+    let path = dest.fullpath();
+    let r = tokio::task::spawn_blocking(move || {
+        let dest = Path::new(&path);
+        let r = fetch_blocking(&url, git_ref.as_deref(), dest, &creds, limits, &cancel);
+        if r.is_err() {
+            let _ = std::fs::remove_dir_all(dest);
+        }
+        r
+    });
+    /* The deadline in Guard is only checked while data comes in, this also covers a connection
+     * that stalls. The blocking thread stops and cleans up at its next progress callback, so
+     * dest must not be reused by another fetch meanwhile. */
+    match tokio::time::timeout(limits.timeout, r).await {
+        Ok(x) => x?,
+        Err(_) => bail!("Timed out fetching repo"),
+    }
+}
+
+/* The refs of the remote at url with their commits, as (name, sha) like git ls-remote lists them */
+pub fn list_remote_blocking(url: &str, creds: &Credentials, limits: FetchLimits) -> Result<Vec<(String, String)>> {
+    check_url(url)?;
+    let (_tx, cancel) = watch::channel(false);
+    let stopped = Cell::new(None);
+    let offered = Cell::new(false);
+    let guard = Guard {
+        deadline: Instant::now() + limits.timeout,
+        max_size: limits.max_size,
+        cancel: &cancel,
+        stopped: &stopped,
+        creds,
+        offered: &offered,
+    };
+    let mut remote = Remote::create_detached(url)?;
+    let conn = remote.connect_auth(Direction::Fetch, Some(guard.callbacks()), None)
+        .map_err(|e| guard.error(e))?;
+    let refs = conn.list()?.iter()
+        .map(|h| (h.name().to_string(), h.oid().to_string()))
+        .collect();
+    Ok(refs)
+}
+
+/* List the refs of the remote, see list_remote_blocking */
+pub async fn list_remote(url: &str, creds: Credentials, limits: FetchLimits) -> Result<Vec<(String, String)>> {
+    let url = url.to_string();
+    let r = tokio::task::spawn_blocking(move || list_remote_blocking(&url, &creds, limits));
+    match tokio::time::timeout(limits.timeout, r).await {
+        Ok(x) => x?,
+        Err(_) => bail!("Timed out listing remote refs"),
+    }
+}
+
+/* Commit checked out in a work tree */
+pub fn git_head(repo: &Entry) -> Option<String> {
+    let repo = Repository::open(repo.fullpath()).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+#[cfg(test)]
+fn bare_repo(dir: &Path, files: &[(&str, &[u8])]) -> Oid {
+    let repo = Repository::init_bare(dir).unwrap();
+    let mut tb = repo.treebuilder(None).unwrap();
+    for (name, content) in files {
+        let blob = repo.blob(content).unwrap();
+        tb.insert(name, blob, 0o100644).unwrap();
+    }
+    let tree = repo.find_tree(tb.write().unwrap()).unwrap();
+    let sig = git2::Signature::now("Oct", "oct@example.com").unwrap();
+    let commit = repo.commit(Some("refs/heads/main"), &sig, &sig, "init", &tree, &[]).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    repo.tag_lightweight("v1", &repo.find_object(commit, None).unwrap(), false).unwrap();
+    commit
+}
+
+#[test]
+fn fetch_test() {
+    crate::config::config_write().allow_local_repos = true;
+    let dir = tempfile::tempdir().unwrap();
+    let origin = dir.path().join("origin.git");
+    let commit = bare_repo(&origin, &[("app.yml", b"name: test\n"), ("big", &[b'x'; 4096])]);
+    let url = format!("file://{}", origin.display());
+    let limits = FetchLimits {
+        timeout: Duration::from_secs(30),
+        max_size: 1 << 20,
+    };
+    let (_tx, cancel) = watch::channel(false);
+    let creds = Credentials::default();
+
+    let dest = dir.path().join("a");
+    let sha = fetch_blocking(&url, None, &dest, &creds, limits, &cancel).unwrap();
+    assert_eq!(sha, commit.to_string());
+    assert_eq!(std::fs::read_to_string(dest.join("app.yml")).unwrap(), "name: test\n");
+
+    let sha = fetch_blocking(&url, Some("v1"), &dir.path().join("b"), &creds, limits, &cancel).unwrap();
+    assert_eq!(sha, commit.to_string());
+
+    let refs = list_remote_blocking(&url, &creds, limits).unwrap();
+    assert_eq!(crate::webhook::remote_head(&refs, None), Some(commit.to_string()));
+    assert_eq!(crate::webhook::remote_head(&refs, Some("v1")), Some(commit.to_string()));
+
+    let r = fetch_blocking(&url, Some("x' ; touch pwned '"), &dir.path().join("c"), &creds, limits, &cancel);
+    assert!(r.unwrap_err().to_string().contains("not found"));
+    assert!(!Path::new("pwned").exists());
+
+    let small = FetchLimits {
+        max_size: 1024,
+        ..limits
+    };
+    let r = fetch_blocking(&url, Some("main"), &dir.path().join("d"), &creds, small, &cancel);
+    assert!(r.unwrap_err().to_string().contains("size limit"));
+
+    let (tx, cancelled) = watch::channel(false);
+    tx.send(true).unwrap();
+    let r = fetch_blocking(&url, None, &dir.path().join("e"), &creds, limits, &cancelled);
+    assert!(r.is_err());
+}
+
+#[test]
+fn check_url_test() {
+    let check = |url| url_allowed(url, false).is_ok();
+    assert!(check("https://github.com/a/b.git"));
+    assert!(check("ssh://git@github.com/a/b.git"));
+    assert!(check("git@github.com:a/b.git"));
+    assert!(!check("file:///data/apps/other/repo"));
+    assert!(!check("/srv/git/b.git"));
+    assert!(!check("ext::sh -c touch% /tmp/pwned"));
+    assert!(!check("http://example.com/b.git"));
+    assert!(url_allowed("file:///srv/git/b.git", true).is_ok());
+    assert!(url_allowed("/srv/git/b.git", true).is_ok());
+    assert!(url_allowed("ext::sh -c touch% /tmp/pwned", true).is_err());
+}
+
+#[tokio::test]
+async fn fetch_timeout_test() {
+    /* Accepts the connection and never answers */
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}/a.git", listener.local_addr().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let limits = FetchLimits {
+        timeout: Duration::from_secs(1),
+        max_size: 1 << 20,
+    };
+    let (_tx, cancel) = watch::channel(false);
+    let dest = Entry::new(dir.path().join("a").to_str().unwrap());
+    let start = Instant::now();
+    let r = fetch_repo(&url, None, &dest, Credentials::default(), limits, cancel).await;
+    assert_eq!(r.unwrap_err().to_string(), "Timed out fetching repo");
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
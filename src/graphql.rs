@@ -1,110 +1,691 @@
+use std::collections::HashSet;
+use futures::future::{BoxFuture, FutureExt};
 use serde_json::{json, Value, map::Map};
 use graphql_parser::query::*;
+use graphql_parser::query::Value as GqlValue;
 use crate::types::*;
 use crate::db::DB;
 use crate::http::*;
+use crate::api::access_allowed;
+use crate::introspection;
+
+const MAX_FRAGMENT_DEPTH: usize = 16;
+
+type Variables = Map<String, Value>;
+
+lazy_static! {
+    static ref PUBLIC_USER_MODEL: ModelDef = ModelDef::make_public_user_model();
+}
+
+/* A model field that resolves to records of another model */
+enum Relation<'a> {
+    /* Reference or user field, resolving to the referenced record */
+    Forward { field: &'a str, target: &'a ModelDef },
+    /* Reference field of another model pointing at this one, resolving to the referring records */
+    Reverse { source: &'a ModelDef, field: &'a str },
+}
+
+enum MutationKind {
+    Create,
+    Update,
+    Delete,
+}
+
+impl MutationKind {
+    /* The REST method whose access rules guard this mutation */
+    fn method_name(&self) -> &'static str {
+        match self {
+            Self::Create => "post",
+            Self::Update => "put",
+            Self::Delete => "delete",
+        }
+    }
+}
+
+fn value_to_json(v: &GqlValue<String>, vars: &Variables) -> Result<Value> {
+    let r = match v {
+        GqlValue::Variable(x) => match vars.get(x) {
+            Some(v) => v.clone(),
+            None => bail!("Variable ${} is not defined", x),
+        },
+        GqlValue::Int(x) => json!(x.as_i64().ok_or(anyhow!("Invalid integer"))?),
+        GqlValue::Float(x) => json!(x),
+        GqlValue::String(x) => json!(x),
+        GqlValue::Boolean(x) => json!(x),
+        GqlValue::Null => Value::Null,
+        GqlValue::Enum(x) => json!(x),
+        GqlValue::List(x) => {
+            let mut ret = Vec::new();
+            for i in x {
+                ret.push(value_to_json(i, vars)?);
+            }
+            Value::Array(ret)
+        },
+        GqlValue::Object(x) => {
+            let mut ret = Map::new();
+            for (k, i) in x {
+                ret.insert(k.to_string(), value_to_json(i, vars)?);
+            }
+            Value::Object(ret)
+        },
+    };
+    Ok(r)
+}
+
+/* Render an argument value in the textual form that ModelDef::parse_query accepts */
+fn argument_to_param(v: &Value) -> Result<Option<String>> {
+    let r = match v {
+        Value::Null => None,
+        Value::String(x) => Some(x.to_string()),
+        Value::Number(x) => Some(x.to_string()),
+        Value::Bool(x) => Some(x.to_string()),
+        Value::Array(x) => {
+            let mut items = Vec::new();
+            for i in x {
+                if let Some(s) = argument_to_param(i)? {
+                    items.push(s);
+                }
+            }
+            Some(items.join(","))
+        },
+        Value::Object(_) => bail!("Unexpected object value"),
+    };
+    Ok(r)
+}
+
+fn response_key<'a, 'b>(field: &'b Field<'a, String>) -> &'b str {
+    field.alias.as_ref().unwrap_or(&field.name)
+}
+
+/* Type of the introspection object found under the given key */
+fn introspection_child_type(key: &str) -> &'static str {
+    match key {
+        "types" | "queryType" | "mutationType" | "subscriptionType" |
+        "type" | "ofType" | "interfaces" | "possibleTypes" => "__Type",
+        "fields" => "__Field",
+        "args" | "inputFields" => "__InputValue",
+        "enumValues" => "__EnumValue",
+        "directives" => "__Directive",
+        _ => "",
+    }
+}
+
+fn operation_name<'a, 'b>(op: &'b OperationDefinition<'a, String>) -> Option<&'b str> {
+    match op {
+        OperationDefinition::Query(x) => x.name.as_deref(),
+        OperationDefinition::Mutation(x) => x.name.as_deref(),
+        OperationDefinition::Subscription(x) => x.name.as_deref(),
+        OperationDefinition::SelectionSet(_) => None,
+    }
+}
+
+/* A field error; those classified as client errors also carry their code and details */
+fn error_value(e: &Error, path: &str) -> Value {
+    let mut ret = json!({ "message": e.to_string(), "path": [path] });
+    let h = HttpError::from_error(e);
+    if h.status < 500 {
+        let mut ext = json!({ "code": h.code });
+        if let Some(d) = h.details {
+            ext["details"] = d;
+        }
+        ret["extensions"] = ext;
+    }
+    ret
+}
 
 struct ExecuteContext<'a> {
     doc: Document<'a, String>,
     db: DB,
-    app_def: AppDef,
+    app_def: &'a AppDef,
     def: &'a GraphQLApiDesc,
     uid: Option<i64>,
+    variables: Variables,
+    operation_name: Option<String>,
+    allow_mutation: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQLRequest {
+    query: String,
+    variables: Option<Variables>,
+    operation_name: Option<String>,
 }
 
 impl<'s> ExecuteContext<'s> {
+    fn select_operation(&self) -> Result<&OperationDefinition<'s, String>> {
+        let ops: Vec<&OperationDefinition<'s, String>> = self.doc.definitions.iter()
+            .filter_map(|d| match d {
+                Definition::Operation(op) => Some(op),
+                _ => None,
+            })
+            .collect();
+        if let Some(name) = &self.operation_name {
+            return match ops.into_iter().find(|op| operation_name(op) == Some(name.as_str())) {
+                Some(x) => Ok(x),
+                None => bail!("Operation {} not found", name),
+            };
+        }
+        match ops.len() {
+            0 => bail!("No operation in document"),
+            1 => Ok(ops[0]),
+            _ => bail!("Operation name is required when the document has multiple operations"),
+        }
+    }
+
+    /* Execute the selected operation. Errors returned here are request errors, which happen
+     * before execution starts and leave no data. */
     async fn execute_doc(&self) -> Result<Value> {
-        let mut data = Vec::new();
-        for def in &self.doc.definitions {
-            match def {
-                Definition::Fragment(_) => { bail!("Fragment not supported"); }
-                Definition::Operation(OperationDefinition::Query(query)) => {
-                    data.push(self.execute_query(&query).await?);
-                },
-                Definition::Operation(OperationDefinition::SelectionSet(ss)) => {
-                    for si in &ss.items {
-                        data.push(self.execute_selection(si).await?);
-                    }
-                },
-                x => {
-                    bail!("Not supported: operation type is not query: {:?}", x);
+        let mut errors = Vec::new();
+        let data = match self.select_operation()? {
+            OperationDefinition::Query(query) => {
+                self.execute_query(query, &mut errors).await?
+            },
+            OperationDefinition::Mutation(mutation) => {
+                if !self.allow_mutation {
+                    bail!("Mutations are only allowed with POST requests");
                 }
-            }
+                self.execute_mutation(mutation, &mut errors).await?
+            },
+            OperationDefinition::SelectionSet(ss) => {
+                self.execute_fields(ss, "Query", &Variables::new(), false, &mut errors).await?
+            },
+            OperationDefinition::Subscription(_) => {
+                bail!("Not supported: subscription");
+            },
         };
-        Ok(json!({
+        let mut ret = json!({
             "data": data,
-        }))
+        });
+        if !errors.is_empty() {
+            ret["errors"] = Value::Array(errors);
+        }
+        Ok(ret)
     }
 
-    async fn execute_selection<'a>(&self, selection: &Selection<'a, String>) -> Result<Value> {
-        match selection {
-            Selection::Field(field) => {
-                self.query_field(&field).await
-            },
-            _ => {
-                bail!("Not supported: selection type is not field");
+    /* Resolve the variables of an operation from the request, applying defaults */
+    fn operation_variables(&self, defs: &[VariableDefinition<'s, String>]) -> Result<Variables> {
+        let mut ret = Variables::new();
+        for d in defs {
+            let v = match self.variables.get(&d.name) {
+                Some(x) => x.clone(),
+                None => match &d.default_value {
+                    Some(x) => value_to_json(x, &Variables::new())?,
+                    None => {
+                        if let Type::NonNullType(_) = d.var_type {
+                            bail!("Variable ${} is required", d.name);
+                        }
+                        Value::Null
+                    },
+                },
+            };
+            ret.insert(d.name.to_string(), v);
+        }
+        Ok(ret)
+    }
+
+    async fn execute_query(&self, query: &Query<'s, String>, errors: &mut Vec<Value>) -> Result<Value> {
+        if !query.directives.is_empty() {
+            bail!("Directives not supported");
+        }
+        let vars = self.operation_variables(&query.variable_definitions)?;
+        self.execute_fields(&query.selection_set, "Query", &vars, false, errors).await
+    }
+
+    async fn execute_mutation(&self, mutation: &Mutation<'s, String>, errors: &mut Vec<Value>) -> Result<Value> {
+        if !mutation.directives.is_empty() {
+            bail!("Directives not supported");
+        }
+        let vars = self.operation_variables(&mutation.variable_definitions)?;
+        self.execute_fields(&mutation.selection_set, "Mutation", &vars, true, errors).await
+    }
+
+    /* Execute top level fields in order. A failing field is reported in errors and resolves to
+     * null, so that other fields still get their results. */
+    async fn execute_fields(&self,
+                            ss: &SelectionSet<'s, String>,
+                            type_name: &str,
+                            vars: &Variables,
+                            mutation: bool,
+                            errors: &mut Vec<Value>) -> Result<Value> {
+        let mut fields = Vec::new();
+        self.collect_fields(ss, type_name, vars, 0, &mut fields)?;
+        let mut ret = Map::new();
+        for field in fields {
+            let key = response_key(field);
+            let r = if field.name == "__typename" {
+                Ok(json!(type_name))
+            } else if mutation {
+                self.mutate_field(field, vars).await
+            } else {
+                self.query_field(field, vars).await
+            };
+            match r {
+                Ok(x) => {
+                    ret.insert(key.to_string(), x);
+                },
+                Err(e) => {
+                    errors.push(error_value(&e, key));
+                    ret.insert(key.to_string(), Value::Null);
+                }
             }
         }
+        Ok(Value::Object(ret))
+    }
 
+    fn included(&self, directives: &[Directive<'s, String>], vars: &Variables) -> Result<bool> {
+        for d in directives {
+            let cond = match d.arguments.iter().find(|(k, _)| k == "if") {
+                Some((_, v)) => value_to_json(v, vars)?,
+                None => bail!("Directive @{} requires argument 'if'", d.name),
+            };
+            let cond = match cond {
+                Value::Bool(x) => x,
+                _ => bail!("Argument 'if' of @{} must be a boolean", d.name),
+            };
+            match d.name.as_str() {
+                "skip" if cond => return Ok(false),
+                "include" if !cond => return Ok(false),
+                "skip" | "include" => (),
+                x => bail!("Unknown directive @{}", x),
+            }
+        }
+        Ok(true)
     }
 
-    async fn execute_query<'a>(&self, query: &Query<'a, String>) -> Result<Value> {
-        let mut ret = Vec::new();
-        if query.variable_definitions.len() > 0 {
-            bail!("Variable definitions not supported");
+    fn type_matches(cond: &Option<TypeCondition<'_, String>>, type_name: &str) -> bool {
+        match cond {
+            Some(TypeCondition::On(x)) => x == type_name,
+            None => true,
         }
-        if query.directives.len() > 0 {
-            bail!("Directives not supported");
+    }
+
+    fn get_fragment(&self, name: &str) -> Result<&FragmentDefinition<'s, String>> {
+        for def in &self.doc.definitions {
+            if let Definition::Fragment(f) = def {
+                if f.name == name {
+                    return Ok(f);
+                }
+            }
         }
+        bail!("Fragment {} not found", name);
+    }
 
-        for si in &query.selection_set.items {
-            ret.push(self.execute_selection(si).await?);
+    /* Flatten a selection set into the fields to resolve, expanding fragments and applying
+     * @skip/@include. */
+    fn collect_fields<'b>(&'b self,
+                          ss: &'b SelectionSet<'s, String>,
+                          type_name: &str,
+                          vars: &Variables,
+                          depth: usize,
+                          out: &mut Vec<&'b Field<'s, String>>) -> Result<()> {
+        if depth > MAX_FRAGMENT_DEPTH {
+            bail!("Fragments are nested too deep");
         }
-        Ok(serde_json::to_value(ret)?)
+        for si in &ss.items {
+            match si {
+                Selection::Field(f) => {
+                    if self.included(&f.directives, vars)? {
+                        out.push(f);
+                    }
+                },
+                Selection::FragmentSpread(fs) => {
+                    if !self.included(&fs.directives, vars)? {
+                        continue;
+                    }
+                    let frag = self.get_fragment(&fs.fragment_name)?;
+                    if Self::type_matches(&Some(frag.type_condition.clone()), type_name) {
+                        self.collect_fields(&frag.selection_set, type_name, vars, depth + 1, out)?;
+                    }
+                },
+                Selection::InlineFragment(inf) => {
+                    if !self.included(&inf.directives, vars)? {
+                        continue;
+                    }
+                    if Self::type_matches(&inf.type_condition, type_name) {
+                        self.collect_fields(&inf.selection_set, type_name, vars, depth + 1, out)?;
+                    }
+                },
+            }
+        }
+        Ok(())
     }
 
-    async fn query_field<'a>(&self, field: &Field<'a, String>) -> Result<Value> {
-        let mut ret = Vec::new();
-        let field_name = &field.name;
+    /* Build a model query from field arguments: id, limit, offset, cursor, order_by and filter */
+    fn model_query(&self, model: &ModelDef, field: &Field<'s, String>, vars: &Variables) -> Result<ModelQuery> {
+        let mut params = HashMap::new();
+        for (k, v) in &field.arguments {
+            let v = value_to_json(v, vars)?;
+            match k.as_str() {
+                "id" | "limit" | "offset" | "cursor" | "order_by" => {
+                    if let Some(x) = argument_to_param(&v)? {
+                        params.insert(k.to_string(), x);
+                    }
+                },
+                "filter" => {
+                    let filter = match v {
+                        Value::Object(x) => x,
+                        Value::Null => continue,
+                        _ => bail!("Argument 'filter' must be an object"),
+                    };
+                    for (fk, fv) in &filter {
+                        if let Some(x) = argument_to_param(fv)? {
+                            params.insert(fk.to_string(), x);
+                        }
+                    }
+                },
+                x => bail!("Unknown argument '{}' for {}", x, field.name),
+            }
+        }
+        model.parse_query(&params)
+    }
 
-        if field.alias.is_some() {
-            bail!("Field alias is not supported");
+    /* Resolve the selection over a JSON value, used for introspection results */
+    fn project_json(&self, v: &Value, ss: &SelectionSet<'s, String>, type_name: &str, vars: &Variables) -> Result<Value> {
+        if ss.items.is_empty() {
+            return Ok(v.clone());
         }
-        if field.arguments.len() > 0 {
-            bail!("Field arguments is not supported");
+        let obj = match v {
+            Value::Array(x) => {
+                let mut ret = Vec::new();
+                for i in x {
+                    ret.push(self.project_json(i, ss, type_name, vars)?);
+                }
+                return Ok(Value::Array(ret));
+            },
+            Value::Object(x) => x,
+            _ => return Ok(Value::Null),
+        };
+        let mut fields = Vec::new();
+        self.collect_fields(ss, type_name, vars, 0, &mut fields)?;
+        let mut map = Map::new();
+        for f in fields {
+            let r = if f.name == "__typename" {
+                json!(type_name)
+            } else {
+                let child = obj.get(&f.name).unwrap_or(&Value::Null);
+                self.project_json(child, &f.selection_set, introspection_child_type(&f.name), vars)?
+            };
+            map.insert(response_key(f).to_string(), r);
         }
-        if field.directives.len() > 0 {
-            bail!("Field directives is not supported");
+        Ok(Value::Object(map))
+    }
+
+    fn introspect(&self, field: &Field<'s, String>, vars: &Variables) -> Result<Value> {
+        let schema = introspection::schema(self.app_def);
+        if field.name == "__schema" {
+            return self.project_json(&schema, &field.selection_set, "__Schema", vars);
+        }
+        let name = match self.get_argument(field, "name", vars)? {
+            Value::String(x) => x,
+            _ => bail!("Argument 'name' of __type must be a string"),
+        };
+        let t = introspection::find_type(&schema, &name);
+        self.project_json(&t, &field.selection_set, "__Type", vars)
+    }
+
+    async fn query_field(&self, field: &Field<'s, String>, vars: &Variables) -> Result<Value> {
+        let field_name = &field.name;
+
+        if field_name == "__schema" || field_name == "__type" {
+            return self.introspect(field, vars);
         }
+
         let model = if let Some(m) = self.app_def.get_model(field_name) {
             m
         } else {
             bail!("Model {} not found", field_name);
         };
-        for rec in model.select(&self.db, self.uid, None).await? {
-            let mut map = serde_json::map::Map::new();
-            for si in &field.selection_set.items {
-                match si {
-                    Selection::Field(f) => {
-                        if f.selection_set.items.len() > 0 {
-                            bail!("Nested selection not supported");
-                        }
-                        if let Some(x) = rec.get(&f.name) {
-                            map.insert(f.name.clone(), x.to_value());
-                        } else {
-                            bail!("f '{}' not found, fields are {:?}",
-                                  f.name, rec.fields);
-                        }
-                    },
-                    _ => {
-                        bail!("Selection type not supported");
+        if !model_access_allowed(self.app_def, model, "get", self.uid) {
+            bail!("Permission denied for {}", field_name);
+        }
+        let q = self.model_query(model, field, vars)?;
+        let rows = if q.limit.is_some() {
+            model.select_query(&self.db, self.uid, &q).await?
+        } else {
+            self.select_bounded(model, q, field_name).await?
+        };
+        Ok(json!(self.project_rows(model, &rows, &field.selection_set, vars).await?))
+    }
+
+    /* Select records of a query without a limit, there is no cursor to get the rest of a page
+     * so it fails rather than cut the records off at the maximum page size */
+    async fn select_bounded(&self, model: &ModelDef, mut q: ModelQuery, field_name: &str) -> Result<Vec<Row>> {
+        let max = config().max_page_size;
+        q.limit = Some(max + 1);
+        let rows = model.select_query(&self.db, self.uid, &q).await?;
+        if rows.len() > max {
+            bail!("More than {} records for {}, use limit and offset or a filter", max, field_name);
+        }
+        Ok(rows)
+    }
+
+    fn find_relation<'b>(&'b self, model: &'b ModelDef, name: &str) -> Option<Relation<'b>> {
+        if let Some(f) = model.get_field(name) {
+            return match f {
+                FieldDef::Reference(d) => {
+                    let target = self.app_def.get_model(&d.target)?;
+                    Some(Relation::Forward { field: f.name(), target })
+                },
+                FieldDef::User(_) => Some(Relation::Forward { field: f.name(), target: &PUBLIC_USER_MODEL }),
+                _ => None,
+            };
+        }
+        for source in &self.app_def.models {
+            for f in source.fields.as_deref().unwrap_or(&[]) {
+                if let FieldDef::Reference(d) = f {
+                    if d.target == model.name && d.reverse_name(source) == name {
+                        return Some(Relation::Reverse { source, field: f.name() });
                     }
                 }
             }
-            ret.push(json!(map));
         }
-        Ok(json!(&ret))
+        None
+    }
+
+    /* Resolve one nested relation for all records on this level, with a single query */
+    async fn resolve_relation(&self, rel: Relation<'_>, rows: &[Row], field: &Field<'s, String>,
+                              vars: &Variables) -> Result<Vec<Value>> {
+        let model = match &rel {
+            Relation::Forward { target, .. } => *target,
+            Relation::Reverse { source, .. } => *source,
+        };
+        /* Users are public through their own model, whatever the rules of the app */
+        if !std::ptr::eq(model, &*PUBLIC_USER_MODEL) && !model_access_allowed(self.app_def, model, "get", self.uid) {
+            bail!("Permission denied for {}", field.name);
+        }
+        match rel {
+            Relation::Forward { field: name, target } => {
+                if !field.arguments.is_empty() {
+                    bail!("Arguments are not supported on {}", field.name);
+                }
+                let ids: HashSet<i64> = rows.iter().filter_map(|r| r.get_int(name)).collect();
+                let q = ModelQuery {
+                    filters: vec![FieldFilter {
+                        field: "id".to_string(),
+                        op: FilterOp::In(ids.into_iter().map(RowField::Integer).collect()),
+                    }],
+                    ..Default::default()
+                };
+                let children = target.select_query(&self.db, self.uid, &q).await?;
+                let values = self.project_rows(target, &children, &field.selection_set, vars).await?;
+                let by_id: HashMap<i64, Value> = children.iter()
+                    .zip(values)
+                    .filter_map(|(r, v)| Some((r.get_int("id")?, v)))
+                    .collect();
+                Ok(rows.iter()
+                   .map(|r| r.get_int(name).and_then(|x| by_id.get(&x).cloned()).unwrap_or(Value::Null))
+                   .collect())
+            },
+            Relation::Reverse { source, field: name } => {
+                if field.arguments.iter().any(|(k, _)| !["filter", "order_by"].contains(&k.as_str())) {
+                    bail!("Only filter and order_by are supported on {}", field.name);
+                }
+                let mut q = self.model_query(source, field, vars)?;
+                let ids = rows.iter().filter_map(|r| r.get_int("id")).map(RowField::Integer).collect();
+                q.filters.push(FieldFilter {
+                    field: name.to_string(),
+                    op: FilterOp::In(ids),
+                });
+                /* All the children of the parents on this level, at most a page of them */
+                let children = self.select_bounded(source, q, &field.name).await?;
+                let values = self.project_rows(source, &children, &field.selection_set, vars).await?;
+                let mut by_parent: HashMap<i64, Vec<Value>> = HashMap::new();
+                for (r, v) in children.iter().zip(values) {
+                    if let Some(x) = r.get_int(name) {
+                        by_parent.entry(x).or_default().push(v);
+                    }
+                }
+                Ok(rows.iter()
+                   .map(|r| json!(r.get_int("id").and_then(|x| by_parent.remove(&x)).unwrap_or_default()))
+                   .collect())
+            },
+        }
     }
+
+    /* Project the selection over records of a model. Relations are resolved level by level, with
+     * one query per relation field instead of one per record. */
+    fn project_rows<'b>(&'b self,
+                        model: &'b ModelDef,
+                        rows: &'b [Row],
+                        ss: &'b SelectionSet<'s, String>,
+                        vars: &'b Variables) -> BoxFuture<'b, Result<Vec<Value>>> {
+        async move {
+            let type_name = introspection::model_type_name(model);
+            let mut fields = Vec::new();
+            self.collect_fields(ss, type_name, vars, 0, &mut fields)?;
+            let mut maps: Vec<Map<String, Value>> = rows.iter().map(|_| Map::new()).collect();
+            for f in fields {
+                let key = response_key(f);
+                if f.name == "__typename" {
+                    for m in maps.iter_mut() {
+                        m.insert(key.to_string(), json!(type_name));
+                    }
+                    continue;
+                }
+                if !f.selection_set.items.is_empty() {
+                    let rel = match self.find_relation(model, &f.name) {
+                        Some(x) => x,
+                        None => bail!("Field '{}' of {} has no subfields", f.name, type_name),
+                    };
+                    let values = self.resolve_relation(rel, rows, f, vars).await?;
+                    for (m, v) in maps.iter_mut().zip(values) {
+                        m.insert(key.to_string(), v);
+                    }
+                    continue;
+                }
+                for (m, rec) in maps.iter_mut().zip(rows) {
+                    if let Some(x) = rec.get(&f.name) {
+                        m.insert(key.to_string(), x.to_value());
+                    } else {
+                        bail!("Field '{}' not found in {}", f.name, type_name);
+                    }
+                }
+            }
+            Ok(maps.into_iter().map(Value::Object).collect())
+        }.boxed()
+    }
+
+    /* Map createX, updateX and deleteX to the mutation kind and model X */
+    fn find_mutation(&self, name: &str) -> Option<(MutationKind, &ModelDef)> {
+        for m in &self.app_def.models {
+            let kind = if name == introspection::mutation_name("create", m) {
+                MutationKind::Create
+            } else if name == introspection::mutation_name("update", m) {
+                MutationKind::Update
+            } else if name == introspection::mutation_name("delete", m) {
+                MutationKind::Delete
+            } else {
+                continue;
+            };
+            return Some((kind, m));
+        }
+        None
+    }
+
+    fn get_argument(&self, field: &Field<'s, String>, name: &str, vars: &Variables) -> Result<Value> {
+        for (k, v) in &field.arguments {
+            if k == name {
+                return value_to_json(v, vars);
+            }
+        }
+        bail!("Argument '{}' is missing for {}", name, field.name);
+    }
+
+    fn id_argument(&self, field: &Field<'s, String>, vars: &Variables) -> Result<i64> {
+        match self.get_argument(field, "id", vars)? {
+            Value::Number(x) => x.as_i64().ok_or(anyhow!("Invalid id")),
+            Value::String(x) => Ok(x.parse()?),
+            _ => bail!("Invalid id"),
+        }
+    }
+
+    fn input_argument(&self, field: &Field<'s, String>, vars: &Variables, model: &ModelDef,
+                      mode: WriteMode) -> Result<Row> {
+        let input = self.get_argument(field, "input", vars)?;
+        let mut rec = model.validate_input(&input, mode)?;
+        rec.fields.remove("id");
+        Ok(rec)
+    }
+
+    async fn mutate_field(&self, field: &Field<'s, String>, vars: &Variables) -> Result<Value> {
+        let (kind, model) = if let Some(x) = self.find_mutation(&field.name) {
+            x
+        } else {
+            bail!("Mutation {} not found", field.name);
+        };
+        if !access_allowed(self.app_def, self.def.access.as_ref(), kind.method_name(), self.uid)
+            || !model_access_allowed(self.app_def, model, kind.method_name(), self.uid) {
+            bail!("Permission denied for {}", field.name);
+        }
+        let rec = match kind {
+            MutationKind::Create => {
+                let input = self.input_argument(field, vars, model, WriteMode::Create)?;
+                let id = model.create(&self.db, &input, self.uid).await?;
+                model.get(&self.db, self.uid, id).await?
+            },
+            MutationKind::Update => {
+                let id = self.id_argument(field, vars)?;
+                let mut input = self.input_argument(field, vars, model, WriteMode::Patch)?;
+                input.set("id", RowField::Integer(id));
+                if model.update(&self.db, &input, self.uid).await? == 0 {
+                    bail!("{} {} not found", model.name, id);
+                }
+                model.get(&self.db, self.uid, id).await?
+            },
+            MutationKind::Delete => {
+                let id = self.id_argument(field, vars)?;
+                let rec = model.get(&self.db, self.uid, id).await?;
+                if rec.is_some() {
+                    model.delete(&self.db, &[id], self.uid).await?;
+                }
+                rec
+            },
+        };
+        match rec {
+            Some(x) => {
+                let mut r = self.project_rows(model, &[x], &field.selection_set, vars).await?;
+                Ok(r.pop().unwrap_or(Value::Null))
+            },
+            None => bail!("{} not found", model.name),
+        }
+    }
+}
+
+/* A mutation writes a model as its REST endpoints do, so it must also be allowed by one of them,
+ * or by the default rules if the model has none. Otherwise the GraphQL endpoint would bypass
+ * the rules set on the model. */
+fn model_access_allowed(app_def: &AppDef, model: &ModelDef, method_name: &str, uid: Option<i64>) -> bool {
+    let mut rules = app_def.api.endpoints.iter()
+        .filter_map(|ep| match ep {
+            ApiEndpoint::Model(x) if x.model == model.name => Some(x.access.as_ref()),
+            _ => None,
+        })
+        .peekable();
+    if rules.peek().is_none() {
+        return access_allowed(app_def, None, method_name, uid);
+    }
+    rules.any(|x| access_allowed(app_def, x, method_name, uid))
 }
 
 fn parse_query<'a>(query: &'a str) -> Result<Document<'a, String>> {
@@ -112,55 +693,100 @@ fn parse_query<'a>(query: &'a str) -> Result<Document<'a, String>> {
     Ok(r)
 }
 
-pub async fn handle_graphql_get(req: Request,
-                                app: &OctApp,
-                                def: &GraphQLApiDesc,
-                                uid: Option<i64>) -> Result<Response> {
-    let qm = get_query(&req);
-    let query = if let Some(x) = qm.get("query") {
-        x
-    } else {
-        return http400("No GraphQL query in request");
-    };
-    let doc = match parse_query(query) {
+/* A request level error, in the GraphQL response shape rather than the one of error_response */
+fn graphql_error(e: HttpError) -> Result<Response> {
+    json_response_with_status(e.status, &json!({
+        "errors": [{ "message": e.message, "extensions": { "code": e.code } }],
+    }))
+}
+
+async fn execute_request(app: &OctApp,
+                         app_def: &AppDef,
+                         def: &GraphQLApiDesc,
+                         uid: Option<i64>,
+                         gql: GraphQLRequest,
+                         allow_mutation: bool) -> Result<Response> {
+    let doc = match parse_query(&gql.query) {
         Ok(x) => x,
         Err(e) => {
-            return http400(&format!("Invalid query: {}", e));
+            return graphql_error(HttpError::bad_request(&format!("Invalid query: {}", e)));
         },
     };
-    let app_def = if let Some(x) = app.get_def().await {
-        x
-    } else {
-        bail!("Cannot get app def");
-    };
     let exec_ctx = ExecuteContext {
         doc,
         app_def,
         db: app.db()?,
         def,
         uid,
+        variables: gql.variables.unwrap_or_default(),
+        operation_name: gql.operation_name,
+        allow_mutation,
     };
     match exec_ctx.execute_doc().await {
         Ok(x) => json_response(&x),
-        Err(e) => http400(&format!("Failed to execute graphql request: {}", e)),
+        Err(e) => graphql_error(HttpError::bad_request(&format!("Failed to execute graphql request: {}", e))),
     }
 }
 
+pub async fn handle_graphql_get(req: Request,
+                                app: &OctApp,
+                                app_def: &AppDef,
+                                def: &GraphQLApiDesc,
+                                uid: Option<i64>) -> Result<Response> {
+    let mut qm = get_query(&req);
+    let query = if let Some(x) = qm.remove("query") {
+        x
+    } else {
+        return graphql_error(HttpError::bad_request("No GraphQL query in request"));
+    };
+    let variables = match qm.get("variables").map(|x| serde_json::from_str(x)) {
+        None => None,
+        Some(Ok(Value::Object(x))) => Some(x),
+        Some(Ok(Value::Null)) => None,
+        _ => { return graphql_error(HttpError::bad_request("Invalid variables")); }
+    };
+    let gql = GraphQLRequest {
+        query,
+        variables,
+        operation_name: qm.remove("operationName"),
+    };
+    execute_request(app, app_def, def, uid, gql, false).await
+}
+
 pub async fn handle_graphql_post(req: Request,
                                  app: &OctApp,
+                                 app_def: &AppDef,
                                  def: &GraphQLApiDesc,
                                  uid: Option<i64>) -> Result<Response> {
-    http404("not implemented")
+    let is_graphql_body = match req.headers().get("Content-type") {
+        Some(x) => x.to_str().unwrap_or("").starts_with("application/graphql"),
+        None => false,
+    };
+    let body = String::from_utf8(to_bytes(req.into_body()).await?.to_vec())?;
+    let gql = if is_graphql_body {
+        GraphQLRequest {
+            query: body,
+            variables: None,
+            operation_name: None,
+        }
+    } else {
+        match serde_json::from_str(&body) {
+            Ok(x) => x,
+            Err(e) => { return graphql_error(HttpError::bad_request(&format!("Invalid request: {}", e))); }
+        }
+    };
+    execute_request(app, app_def, def, uid, gql, true).await
 }
 
 pub async fn handle_graphql(req: Request,
                             app: &OctApp,
+                            app_def: &AppDef,
                             def: &GraphQLApiDesc,
                             uid: Option<i64>) -> Result<Response> {
     match req.method() {
-        &hyper::Method::GET => handle_graphql_get(req, app, def, uid).await,
-        &hyper::Method::POST => handle_graphql_post(req, app, def, uid).await,
-        _ => bail!("Method not supported for graphql"),
+        &hyper::Method::GET => handle_graphql_get(req, app, app_def, def, uid).await,
+        &hyper::Method::POST => handle_graphql_post(req, app, app_def, def, uid).await,
+        _ => graphql_error(HttpError::method_not_allowed()),
     }
 }
 
@@ -169,24 +795,32 @@ mod tests {
     use super::*;
     use std::fs;
     use tempfile;
-    use crate::apps::sync_models;
+    use crate::migrate::plan_migration;
 
     fn read_data(name: &str) -> String {
         fs::read_to_string(&format!("tests/data/{}", name)).unwrap()
     }
 
     async fn do_test(app: &str, data: &str, query: &str, result: &str) {
-        let qs = read_data(query);
-        let doc = parse_query(&qs).unwrap();
-        let yml = read_data(app);
-        let app_def = AppDef::from_yaml(&yml).unwrap();
+        do_test_vars(app, data, query, "{}", result).await;
+    }
+
+    async fn do_test_vars(app: &str, data: &str, query: &str, vars: &str, result: &str) {
+        let app_def = AppDef::from_yaml(&read_data(app)).unwrap();
+        let r = execute(&app_def, data, &read_data(query), vars).await.unwrap();
+        let expected: Value = serde_json::from_str(&read_data(result)).unwrap();
+        assert_eq!(r, expected);
+    }
+
+    async fn execute(app_def: &AppDef, data: &str, query: &str, vars: &str) -> Result<Value> {
+        let doc = parse_query(query).unwrap();
         let tf = tempfile::NamedTempFile::new().unwrap();
         let dbpath = tf.path();
         let db = DB::new(dbpath.to_str().unwrap()).unwrap();
-        let ep = app_def.api.find_endpoint("/graphql").unwrap();
-        sync_models(&db, &None, &app_def).await.unwrap();
+        let ep = app_def.api.route("/graphql").unwrap().0;
+        plan_migration(&db, app_def).await.unwrap().apply(&db).await.unwrap();
         let v: Value = serde_json::from_str(&read_data(data)).unwrap();
-        prepare_data(&db, &app_def, &v).await;
+        prepare_data(&db, app_def, &v).await;
         let def = match &ep {
             ApiEndpoint::GraphQL(x) => x,
             _ => { panic!("invalid type"); }
@@ -197,10 +831,11 @@ mod tests {
             db,
             def,
             uid: None,
+            variables: serde_json::from_str(vars).unwrap(),
+            operation_name: None,
+            allow_mutation: true,
         };
-        let r = exec_ctx.execute_doc().await.unwrap();
-        let expected: Value = serde_json::from_str(&read_data(result)).unwrap();
-        assert_eq!(r, expected);
+        exec_ctx.execute_doc().await
     }
 
     async fn prepare_data(db: &DB, app_def: &AppDef, data: &Value) {
@@ -221,4 +856,81 @@ mod tests {
                 "graphql-01-result.json")
             .await;
     }
+
+    #[tokio::test]
+    async fn mutation_test() {
+        do_test("graphql.yml",
+                "graphql-01-data.json",
+                "graphql-02-query.txt",
+                "graphql-02-result.json")
+            .await;
+    }
+
+    #[test]
+    fn model_access_test() {
+        let mut app_def = AppDef::from_yaml(&read_data("graphql.yml")).unwrap();
+        assert!(model_access_allowed(&app_def, app_def.get_model("user").unwrap(), "post", None));
+
+        app_def.api.endpoints.push(serde_yaml::from_str("
+name: users
+path: /users
+type: model
+model: user
+access:
+  - method: get
+    action: allow
+").unwrap());
+        let model = app_def.get_model("user").unwrap();
+        assert!(model_access_allowed(&app_def, model, "get", None));
+        assert!(!model_access_allowed(&app_def, model, "post", None));
+        assert!(!model_access_allowed(&app_def, model, "delete", Some(1)));
+        assert!(model_access_allowed(&app_def, model, "delete", Some(0)));
+    }
+
+    #[tokio::test]
+    async fn relation_access_test() {
+        let mut app_def = AppDef::from_yaml(&read_data("graphql-ref.yml")).unwrap();
+        app_def.api.endpoints.push(serde_yaml::from_str("
+name: items
+path: /items
+type: model
+model: TodoItem
+access:
+  - method: get
+    action: deny
+").unwrap());
+        let r = execute(&app_def, "graphql-05-data.json", "{ TodoList { name } }", "{}").await.unwrap();
+        assert!(r.get("errors").is_none());
+        let r = execute(&app_def, "graphql-05-data.json", "{ TodoList { name items { subject } } }", "{}")
+            .await.unwrap();
+        assert_eq!(r["errors"][0]["message"], json!("Permission denied for items"));
+    }
+
+    #[tokio::test]
+    async fn variables_fragments_test() {
+        do_test_vars("graphql.yml",
+                     "graphql-03-data.json",
+                     "graphql-03-query.txt",
+                     r#"{"name": "user 1"}"#,
+                     "graphql-03-result.json")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn introspection_test() {
+        do_test("graphql.yml",
+                "graphql-01-data.json",
+                "graphql-04-query.txt",
+                "graphql-04-result.json")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn reference_test() {
+        do_test("graphql-ref.yml",
+                "graphql-05-data.json",
+                "graphql-05-query.txt",
+                "graphql-05-result.json")
+            .await;
+    }
 }
@@ -4,32 +4,64 @@ use std::net::SocketAddr;
 use std::convert::Infallible;
 use std::collections::HashMap;
 use url::form_urlencoded;
+use serde_json::json;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Response as HyperResponse;
-use hyper::Body;
+use hyper::{Body, HeaderMap};
 pub use hyper::body::to_bytes;
 use tokio_util::codec::{BytesCodec, FramedRead};
+use std::io::SeekFrom;
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use crate::types::*;
 use crate::auth::handle_auth_request;
 use crate::meta::handle_meta_request;
 use crate::api::handle_api_request;
 use crate::alert::alert;
 
-pub fn http500(msg: &str) -> Result<Response> {
-    Ok(HyperResponse::builder().status(500).body(msg.to_string().into())?)
+impl HttpError {
+    /* Classify an error returned by a handler. Anything not recognized is an internal error,
+     * whose details are only logged. */
+    pub fn from_error(e: &Error) -> HttpError {
+        if let Some(x) = e.downcast_ref::<HttpError>() {
+            return x.clone();
+        }
+        if let Some(x) = e.downcast_ref::<ValidationError>() {
+            return HttpError::new(422, "validation_failed", &x.to_string())
+                .with_details(json!({ "fields": x.errors }));
+        }
+        if let Some(x) = e.downcast_ref::<AppDefErrors>() {
+            return HttpError::new(422, "invalid_app_def", "Invalid app definition")
+                .with_details(json!({ "errors": x.errors }));
+        }
+        if let Some(rusqlite::Error::SqliteFailure(f, msg)) = e.downcast_ref::<rusqlite::Error>() {
+            if f.code == rusqlite::ErrorCode::ConstraintViolation {
+                let msg = msg.as_deref().unwrap_or("Constraint violation");
+                return HttpError::new(409, "conflict", msg);
+            }
+        }
+        HttpError::internal("internal error")
+    }
+}
+
+pub fn error_response(e: &HttpError) -> Result<Response> {
+    json_response_with_status(e.status, &json!({ "error": e }))
+}
+
+pub fn http405() -> Result<Response> {
+    error_response(&HttpError::method_not_allowed())
 }
 
 pub fn http404(msg: &str) -> Result<Response> {
-    Ok(HyperResponse::builder().status(404).body(msg.to_string().into())?)
+    error_response(&HttpError::not_found(msg))
 }
 
 pub fn http401(msg: &str) -> Result<Response> {
-    Ok(HyperResponse::builder().status(401).body(msg.to_string().into())?)
+    error_response(&HttpError::unauthorized(msg))
 }
 
 pub fn http400(msg: &str) -> Result<Response> {
-    Ok(HyperResponse::builder().status(400).body(msg.to_string().into())?)
+    error_response(&HttpError::bad_request(msg))
 }
 
 pub fn http302(url: &str) -> Result<Response> {
@@ -50,9 +82,13 @@ pub fn get_query(req: &Request) -> HashMap::<String, String> {
 }
 
 pub fn json_response<T: Serialize + ?Sized>(x: &T) -> Result<Response> {
+    json_response_with_status(200, x)
+}
+
+pub fn json_response_with_status<T: Serialize + ?Sized>(status: u16, x: &T) -> Result<Response> {
     let resp = serde_json::to_string(x)? + "\n";
     let r = hyper::Response::builder()
-        .status(200)
+        .status(status)
         .header("Content-type", "application/json")
         .body(resp.into())?;
     Ok(r)
@@ -68,39 +104,124 @@ fn guess_content_type(filename: &str) -> &'static str {
      * web browsers may reject media files or scripts due to that. So, cover the most common file
      * extensions here.
      */
-    if filename.ends_with(".css") {
-        "text/css; charset=utf-8"
-    } else if filename.ends_with(".js") {
-        "text/javascript; charset=utf-8"
-    } else if filename.ends_with(".html") {
-        "text/html; charset=utf-8"
-    } else if filename.ends_with(".png") {
-        "image/png"
-    } else if filename.ends_with(".jpeg") {
-        "image/jpeg"
-    } else if filename.ends_with(".gif") {
-        "image/gif"
+    let ext = filename.rsplit_once('.').map(|x| x.1.to_lowercase()).unwrap_or_default();
+    match ext.as_str() {
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "json" | "map" => "application/json",
+        "md" => "text/markdown; charset=utf-8",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        _ => "text/plain",
+    }
+}
+
+/* The inclusive byte range asked for by a Range header. Only a single range is supported, for
+ * anything else None is returned and the whole file is sent. Err(()) means the range cannot be
+ * satisfied. */
+fn parse_range(header: &str, size: u64) -> Option<std::result::Result<(u64, u64), ()>> {
+    let spec = header.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        let n: u64 = end.parse().ok()?;
+        if n == 0 || size == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((size.saturating_sub(n), size - 1)));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() {
+        u64::MAX
     } else {
-        "text/plain"
+        end.parse().ok()?
+    };
+    if end < start {
+        return None;
+    }
+    if start >= size {
+        return Some(Err(()));
     }
+    Some(Ok((start, end.min(size - 1))))
 }
 
-pub async fn file_response(filename: &str) -> Result<Response> {
-    match File::open(filename).await {
-        Ok(file) => {
-            let stream = FramedRead::new(file, BytesCodec::new());
-            let body = Body::wrap_stream(stream);
-            let resp = hyper::Response::builder()
-            .status(200)
-            .header("Content-type", guess_content_type(filename))
-            .body(body)?;
-            Ok(resp)
-        },
+/* Send a file, answering conditional (If-None-Match, If-Modified-Since) and Range requests
+ * according to the request headers. */
+pub async fn file_response(headers: &HeaderMap, filename: &str) -> Result<Response> {
+    let mut file = match File::open(filename).await {
+        Ok(x) => x,
         Err(e) => {
             println!("err: {}", e);
-            http404("Not found")
+            return http404("Not found");
         }
+    };
+    let meta = file.metadata().await?;
+    if meta.is_dir() {
+        return http404("Not found");
+    }
+    let size = meta.len();
+    let mtime = DateTime::from(meta.modified()?);
+    let etag = format!("\"{:x}-{:x}\"", size, mtime.timestamp_nanos());
+    let last_modified = mtime.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    let header = |name: &str| headers.get(name).and_then(|x| x.to_str().ok());
+
+    let not_modified = match header("If-None-Match") {
+        Some(x) => x.split(',').map(|t| t.trim()).any(|t| t == "*" || t.trim_start_matches("W/") == etag),
+        None => match header("If-Modified-Since").and_then(|x| chrono::DateTime::parse_from_rfc2822(x).ok()) {
+            Some(t) => mtime.timestamp() <= t.timestamp(),
+            None => false,
+        },
+    };
+    let builder = hyper::Response::builder()
+        .header("Content-type", guess_content_type(filename))
+        .header("ETag", &etag)
+        .header("Last-Modified", &last_modified)
+        .header("Accept-Ranges", "bytes");
+    if not_modified {
+        return Ok(builder.status(304).body(Body::empty())?);
     }
+
+    /* With If-Range, a range only applies if the client has the current version of the file */
+    let current = match header("If-Range") {
+        Some(x) => x == etag || x == last_modified,
+        None => true,
+    };
+    let range = match header("Range") {
+        Some(x) if current => parse_range(x, size),
+        _ => None,
+    };
+    let (builder, start, len) = match range {
+        None => (builder.status(200), 0, size),
+        Some(Ok((start, end))) => {
+            let b = builder.status(206)
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end, size));
+            (b, start, end - start + 1)
+        },
+        Some(Err(())) => {
+            return Ok(builder.status(416)
+                .header("Content-Range", format!("bytes */{}", size))
+                .body(Body::empty())?);
+        },
+    };
+    file.seek(SeekFrom::Start(start)).await?;
+    let stream = FramedRead::new(file.take(len), BytesCodec::new());
+    let resp = builder
+        .header("Content-Length", len)
+        .body(Body::wrap_stream(stream))?;
+    Ok(resp)
 }
 
 async fn handle_status_request(_: Arc<Context>, req: Request) -> Result<Response> {
@@ -129,6 +250,7 @@ async fn handle_feedback_request(_: Arc<Context>, req: Request) -> Result<Respon
 
 async fn handle_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
     let path = String::from(req.uri().path());
+    let headers = req.headers().clone();
     let r = if path.starts_with("/a/") {
         Some(handle_api_request(ctx, req).await)
     } else if path.starts_with("/auth/") {
@@ -147,8 +269,11 @@ async fn handle_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
             return Ok(x);
         },
         Some(Err(e)) => {
-            println!("{:?}", e);
-            return http500("internal error");
+            let err = HttpError::from_error(&e);
+            if err.status >= 500 {
+                println!("{:?}", e);
+            }
+            return error_response(&err);
         },
         None => (),
     }
@@ -175,7 +300,7 @@ async fn handle_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
             _ => default
         }
     };
-    file_response(&fname).await
+    file_response(&headers, &fname).await
 }
 
 pub async fn run_server(ctx: Arc<Context>) -> Result<()> {
@@ -210,3 +335,34 @@ pub fn get_auth_token(req: &Request) -> Option<&str> {
     }
     Some(fs[1])
 }
+
+#[test]
+fn classify_error_test() {
+    let e: Error = HttpError::not_found("gone").into();
+    assert_eq!(HttpError::from_error(&e).status, 404);
+    let e: Error = ValidationError {
+        errors: vec![FieldError { field: "name".to_string(), message: "is required".to_string() }],
+    }.into();
+    let h = HttpError::from_error(&e);
+    assert_eq!(h.status, 422);
+    assert_eq!(h.details.unwrap()["fields"][0]["field"], "name");
+
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.execute("CREATE TABLE t (x INTEGER UNIQUE)", []).unwrap();
+    conn.execute("INSERT INTO t VALUES (1)", []).unwrap();
+    let e: Error = conn.execute("INSERT INTO t VALUES (1)", []).unwrap_err().into();
+    assert_eq!(HttpError::from_error(&e).code, "conflict");
+    assert_eq!(HttpError::from_error(&anyhow!("boom")).status, 500);
+}
+
+#[test]
+fn parse_range_test() {
+    assert_eq!(parse_range("bytes=0-9", 100), Some(Ok((0, 9))));
+    assert_eq!(parse_range("bytes=90-", 100), Some(Ok((90, 99))));
+    assert_eq!(parse_range("bytes=90-200", 100), Some(Ok((90, 99))));
+    assert_eq!(parse_range("bytes=-10", 100), Some(Ok((90, 99))));
+    assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+    assert_eq!(parse_range("bytes=5-1", 100), None);
+    assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+    assert_eq!(parse_range("items=0-1", 100), None);
+}
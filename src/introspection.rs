@@ -0,0 +1,259 @@
+use serde_json::{json, Value};
+use crate::types::*;
+
+/* Introspection result for the GraphQL schema generated from the app models, in the shape of
+ * the __Schema type of the GraphQL spec. */
+
+const SCALARS: [&str; 5] = ["Int", "Float", "String", "Boolean", "ID"];
+const FILTER_OPS: [&str; 7] = ["", "ne", "lt", "gt", "in", "like", "isnull"];
+
+fn named(kind: &str, name: &str) -> Value {
+    json!({ "kind": kind, "name": name, "ofType": null })
+}
+
+fn non_null(t: Value) -> Value {
+    json!({ "kind": "NON_NULL", "name": null, "ofType": t })
+}
+
+fn list_of(t: Value) -> Value {
+    json!({ "kind": "LIST", "name": null, "ofType": t })
+}
+
+fn scalar(name: &str) -> Value {
+    named("SCALAR", name)
+}
+
+fn input_value(name: &str, description: Option<&str>, t: Value) -> Value {
+    json!({
+        "name": name,
+        "description": description,
+        "type": t,
+        "defaultValue": null,
+    })
+}
+
+fn field(name: &str, description: Option<&str>, args: Vec<Value>, t: Value) -> Value {
+    json!({
+        "name": name,
+        "description": description,
+        "args": args,
+        "type": t,
+        "isDeprecated": false,
+        "deprecationReason": null,
+    })
+}
+
+fn full_type(kind: &str, name: &str, description: Option<&str>) -> Value {
+    json!({
+        "kind": kind,
+        "name": name,
+        "description": description,
+        "fields": null,
+        "inputFields": null,
+        "interfaces": if kind == "OBJECT" { json!([]) } else { Value::Null },
+        "enumValues": null,
+        "possibleTypes": null,
+        "specifiedByURL": null,
+    })
+}
+
+pub fn field_scalar(f: &FieldDef) -> &'static str {
+    match f {
+        FieldDef::String(_) => "String",
+        FieldDef::Integer(_) => "Int",
+        FieldDef::Float(_) => "Float",
+        FieldDef::Boolean(_) => "Boolean",
+        FieldDef::DateTime(_) => "Int",
+        FieldDef::User(_) => "Int",
+        FieldDef::Reference(_) => "Int",
+    }
+}
+
+fn field_description(f: &FieldDef) -> Option<&str> {
+    match f {
+        FieldDef::String(d) => d.description.as_deref(),
+        FieldDef::Integer(d) => d.description.as_deref(),
+        FieldDef::Float(d) => d.description.as_deref(),
+        FieldDef::Boolean(d) => d.description.as_deref(),
+        FieldDef::DateTime(d) => d.description.as_deref(),
+        FieldDef::User(d) => d.description.as_deref(),
+        FieldDef::Reference(d) => d.description.as_deref(),
+    }
+}
+
+fn model_fields(m: &ModelDef) -> &[FieldDef] {
+    m.fields.as_deref().unwrap_or(&[])
+}
+
+/* GraphQL type name of a model, the internal user model is exposed as OctUser */
+pub fn model_type_name(m: &ModelDef) -> &str {
+    if m.name == "__oct_user" {
+        "OctUser"
+    } else {
+        &m.name
+    }
+}
+
+fn relation_args(m: &ModelDef) -> Vec<Value> {
+    vec![
+        input_value("filter", None, named("INPUT_OBJECT", &filter_type_name(m))),
+        input_value("order_by", None, list_of(non_null(scalar("String")))),
+    ]
+}
+
+fn model_type(app: &AppDef, m: &ModelDef) -> Value {
+    let mut t = full_type("OBJECT", model_type_name(m), m.description.as_deref());
+    let mut fields = vec![field("id", None, vec![], non_null(scalar("Int")))];
+    for f in model_fields(m) {
+        let ft = match f {
+            FieldDef::Reference(d) => named("OBJECT", &d.target),
+            FieldDef::User(_) => named("OBJECT", "OctUser"),
+            f => scalar(field_scalar(f)),
+        };
+        let ft = if f.is_optional() { ft } else { non_null(ft) };
+        fields.push(field(f.name(), field_description(f), vec![], ft));
+    }
+    for source in &app.models {
+        for f in model_fields(source) {
+            if let FieldDef::Reference(d) = f {
+                if d.target == m.name {
+                    let ft = non_null(list_of(non_null(named("OBJECT", &source.name))));
+                    fields.push(field(&d.reverse_name(source), None, relation_args(source), ft));
+                }
+            }
+        }
+    }
+    t["fields"] = json!(fields);
+    t
+}
+
+fn input_type_name(m: &ModelDef) -> String {
+    format!("{}Input", m.name)
+}
+
+fn filter_type_name(m: &ModelDef) -> String {
+    format!("{}Filter", m.name)
+}
+
+fn model_input_type(m: &ModelDef) -> Value {
+    let mut t = full_type("INPUT_OBJECT", &input_type_name(m), None);
+    let fields: Vec<Value> = model_fields(m).iter()
+        .map(|f| input_value(f.name(), field_description(f), scalar(field_scalar(f))))
+        .collect();
+    t["inputFields"] = json!(fields);
+    t
+}
+
+fn model_filter_type(m: &ModelDef) -> Value {
+    let mut t = full_type("INPUT_OBJECT", &filter_type_name(m), None);
+    let mut fields = Vec::new();
+    let names = std::iter::once(("id", "Int"))
+        .chain(model_fields(m).iter().map(|f| (f.name(), field_scalar(f))));
+    for (name, ft) in names {
+        for op in FILTER_OPS {
+            let (key, t) = match op {
+                "" => (name.to_string(), scalar(ft)),
+                "in" => (format!("{}__in", name), list_of(non_null(scalar(ft)))),
+                "like" => (format!("{}__like", name), scalar("String")),
+                "isnull" => (format!("{}__isnull", name), scalar("Boolean")),
+                op => (format!("{}__{}", name, op), scalar(ft)),
+            };
+            fields.push(input_value(&key, None, t));
+        }
+    }
+    t["inputFields"] = json!(fields);
+    t
+}
+
+fn query_type(app: &AppDef) -> Value {
+    let mut t = full_type("OBJECT", "Query", None);
+    let fields: Vec<Value> = app.models.iter().map(|m| {
+        let args = vec![
+            input_value("id", None, scalar("Int")),
+            input_value("filter", None, named("INPUT_OBJECT", &filter_type_name(m))),
+            input_value("order_by", None, list_of(non_null(scalar("String")))),
+            input_value("limit", None, scalar("Int")),
+            input_value("offset", None, scalar("Int")),
+            input_value("cursor", None, scalar("String")),
+        ];
+        let t = non_null(list_of(non_null(named("OBJECT", &m.name))));
+        field(&m.name, m.description.as_deref(), args, t)
+    }).collect();
+    t["fields"] = json!(fields);
+    t
+}
+
+pub fn mutation_name(action: &str, m: &ModelDef) -> String {
+    let mut c = m.name.chars();
+    match c.next() {
+        Some(f) => format!("{}{}", action, f.to_uppercase().chain(c).collect::<String>()),
+        None => action.to_string(),
+    }
+}
+
+fn mutation_type(app: &AppDef) -> Value {
+    let mut t = full_type("OBJECT", "Mutation", None);
+    let mut fields = Vec::new();
+    for m in &app.models {
+        let ret = named("OBJECT", &m.name);
+        let id = input_value("id", None, non_null(scalar("Int")));
+        let input = input_value("input", None, non_null(named("INPUT_OBJECT", &input_type_name(m))));
+        fields.push(field(&mutation_name("create", m), None, vec![input.clone()], ret.clone()));
+        fields.push(field(&mutation_name("update", m), None, vec![id.clone(), input], ret.clone()));
+        fields.push(field(&mutation_name("delete", m), None, vec![id], ret));
+    }
+    t["fields"] = json!(fields);
+    t
+}
+
+fn directives() -> Value {
+    let locations = json!(["FIELD", "FRAGMENT_SPREAD", "INLINE_FRAGMENT"]);
+    let args = vec![input_value("if", None, non_null(scalar("Boolean")))];
+    json!([
+        {
+            "name": "include",
+            "description": "Include only when the argument is true",
+            "locations": locations,
+            "args": args,
+            "isRepeatable": false,
+        },
+        {
+            "name": "skip",
+            "description": "Skip when the argument is true",
+            "locations": locations,
+            "args": args,
+            "isRepeatable": false,
+        },
+    ])
+}
+
+pub fn schema(app: &AppDef) -> Value {
+    let mut types = vec![query_type(app)];
+    if !app.models.is_empty() {
+        types.push(mutation_type(app));
+    }
+    types.push(model_type(app, &ModelDef::make_public_user_model()));
+    for m in &app.models {
+        types.push(model_type(app, m));
+        types.push(model_input_type(m));
+        types.push(model_filter_type(m));
+    }
+    for s in SCALARS {
+        types.push(full_type("SCALAR", s, None));
+    }
+    json!({
+        "description": null,
+        "queryType": { "kind": "OBJECT", "name": "Query" },
+        "mutationType": if app.models.is_empty() { Value::Null } else { json!({ "kind": "OBJECT", "name": "Mutation" }) },
+        "subscriptionType": null,
+        "types": types,
+        "directives": directives(),
+    })
+}
+
+pub fn find_type(schema: &Value, name: &str) -> Value {
+    schema["types"].as_array()
+        .and_then(|ts| ts.iter().find(|t| t["name"] == name))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
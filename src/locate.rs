@@ -0,0 +1,153 @@
+use yaml_rust::parser::{Event, Parser};
+use yaml_rust::scanner::Marker;
+use crate::types::*;
+
+/*
+ * Finding the line and column of app definition errors. serde_yaml doesn't keep positions, so the
+ * YAML text is walked again with the low level parser of yaml-rust, recording where every node
+ * path such as models[1].fields[0].target starts. Entries of a mapping are located at their key.
+ */
+
+/* Line and column, both counted from 1 */
+type Position = (usize, usize);
+
+fn position(m: &Marker) -> Position {
+    (m.line(), m.col() + 1)
+}
+
+struct Walker<'a> {
+    parser: Parser<std::str::Chars<'a>>,
+    positions: HashMap<String, Position>,
+}
+
+impl<'a> Walker<'a> {
+    fn next(&mut self) -> Option<(Event, Marker)> {
+        self.parser.next().ok()
+    }
+
+    /* Walk the node started by event, recording the positions under path. None on a parse error. */
+    fn node(&mut self, event: Event, path: &str) -> Option<()> {
+        match event {
+            Event::MappingStart(_) => loop {
+                let (key, mark) = self.next()?;
+                /* The start of a mapping in a sequence is where its first key is */
+                self.positions.entry(path.to_string()).or_insert_with(|| position(&mark));
+                let child = match key {
+                    Event::MappingEnd => { return Some(()); },
+                    Event::Scalar(ref k, ..) if path.is_empty() => k.clone(),
+                    Event::Scalar(ref k, ..) => format!("{}.{}", path, k),
+                    /* Complex keys don't appear in app definitions, just skip them */
+                    other => {
+                        self.node(other, "?")?;
+                        "?".to_string()
+                    },
+                };
+                self.positions.entry(child.clone()).or_insert_with(|| position(&mark));
+                let (value, _) = self.next()?;
+                self.node(value, &child)?;
+            },
+            Event::SequenceStart(_) => {
+                for i in 0.. {
+                    let (item, mark) = self.next()?;
+                    if item == Event::SequenceEnd {
+                        break;
+                    }
+                    let child = format!("{}[{}]", path, i);
+                    if !matches!(item, Event::MappingStart(_)) {
+                        self.positions.insert(child.clone(), position(&mark));
+                    }
+                    self.node(item, &child)?;
+                }
+                Some(())
+            },
+            _ => Some(()),
+        }
+    }
+}
+
+/* Positions of the nodes of the first document in yml, by path */
+fn node_positions(yml: &str) -> HashMap<String, Position> {
+    let mut w = Walker {
+        parser: Parser::new(yml.chars()),
+        positions: HashMap::new(),
+    };
+    while let Some((event, _)) = w.next() {
+        match event {
+            Event::StreamStart | Event::DocumentStart => continue,
+            Event::MappingStart(_) | Event::SequenceStart(_) => {
+                let _ = w.node(event, "");
+            },
+            _ => (),
+        }
+        break;
+    }
+    w.positions
+}
+
+/* The path of the node containing the one at path */
+fn parent_path(path: &str) -> Option<&str> {
+    path.rfind(['.', '[']).map(|i| &path[..i])
+}
+
+/* Fill in the line and column of errors from the text of the files they refer to. Errors at paths
+ * that aren't in the text, such as optional fields that are missing, point to the closest parent. */
+pub fn locate_errors(errors: &mut [AppDefError], files: &[(String, String)]) {
+    let mut cache: HashMap<&str, HashMap<String, Position>> = HashMap::new();
+    for e in errors.iter_mut() {
+        let yml = match files.iter().find(|(f, _)| *f == e.file) {
+            Some((_, x)) => x,
+            None => continue,
+        };
+        let positions = cache.entry(&e.file).or_insert_with(|| node_positions(yml));
+        let mut path = Some(e.path.as_str());
+        while let Some(p) = path {
+            if let Some((line, column)) = positions.get(p) {
+                e.line = Some(*line);
+                e.column = Some(*column);
+                break;
+            }
+            path = parent_path(p);
+        }
+    }
+}
+
+#[test]
+fn locate_errors_test() {
+    let yml = "\
+name: test
+models:
+  - name: todo
+    fields:
+      - name: owner
+        type: reference
+        target: person
+  - name: todo
+api:
+  endpoints:
+    - name: todos
+      type: model
+      path: /todo
+      model: task
+";
+    let files = vec![("app.yml".to_string(), yml.to_string())];
+    let err = |path: &str| AppDefError::new("app.yml".to_string(), path.to_string(), String::new());
+    let mut errors = vec![
+        err("models[0].fields[0].target"),
+        err("models[1].name"),
+        err("api.endpoints[0].model"),
+        err("models[1].description"),
+        err("models[0]"),
+        AppDefError::new("other.yml".to_string(), "models[0]".to_string(), String::new()),
+    ];
+    locate_errors(&mut errors, &files);
+    let found: Vec<(Option<usize>, Option<usize>)> = errors.iter().map(|e| (e.line, e.column)).collect();
+    assert_eq!(found, vec![
+        (Some(7), Some(9)),
+        (Some(8), Some(5)),
+        (Some(14), Some(7)),
+        (Some(8), Some(5)),
+        (Some(3), Some(5)),
+        (None, None),
+    ]);
+    assert_eq!(errors[0].to_string(), "app.yml:7:9: models[0].fields[0].target: ");
+}
@@ -10,8 +10,17 @@ mod model;
 mod db;
 mod worker;
 mod orm;
+mod store;
 mod stats;
 mod graphql;
+mod introspection;
+mod migrate;
+mod sync;
+mod deploy;
+mod webhook;
+mod fetch;
+mod credentials;
+mod locate;
 mod alert;
 
 use std::sync::Arc;
@@ -39,6 +48,27 @@ fn start_doc() {
         .expect("failed to start doc dev server");
 }
 
+/* Check the app definition in dir, for the validate command */
+fn validate_app(dir: &str) -> ! {
+    match apps::load_app_def(std::path::Path::new(dir)) {
+        Ok(def) => {
+            println!("{}: ok", def.name);
+            std::process::exit(0);
+        },
+        Err(e) => {
+            match e.downcast_ref::<AppDefErrors>() {
+                Some(x) => {
+                    for err in &x.errors {
+                        println!("{}", err);
+                    }
+                },
+                None => println!("{}", e),
+            }
+            std::process::exit(1);
+        },
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let matches = clap::App::new("Oct API server")
@@ -64,14 +94,53 @@ async fn main() -> Result<()> {
             .short("-J")
             .takes_value(true)
             .help("Django server address"))
+        .arg(clap::Arg::with_name("orm-timeout")
+            .long("--orm-timeout")
+            .takes_value(true)
+            .help("Seconds a request to the Django server may take"))
+        .arg(clap::Arg::with_name("orm-retries")
+            .long("--orm-retries")
+            .takes_value(true)
+            .help("Times a failed request to the Django server is retried"))
         .arg(clap::Arg::with_name("no-start-orm")
             .long("--no-start-orm")
             .help("Don't start Django server"))
+        .arg(clap::Arg::with_name("store")
+            .long("--store")
+            .takes_value(true)
+            .possible_values(&["sqlite", "django"])
+            .help("Where users and apps are kept, the Django server is only used with django"))
+        .arg(clap::Arg::with_name("import-django")
+            .long("--import-django")
+            .takes_value(true)
+            .help("Import users and apps from the database file of the Django server"))
+        .arg(clap::Arg::with_name("keep-deployments")
+            .long("--keep-deployments")
+            .takes_value(true)
+            .help("Number of deployments kept per app for rollback"))
+        .arg(clap::Arg::with_name("allow-local-repos")
+            .long("--allow-local-repos")
+            .help("Allow app repos on this server, as file:// URLs or paths"))
+        .arg(clap::Arg::with_name("page-size")
+            .long("--page-size")
+            .takes_value(true)
+            .help("Records in a page of a model listing when the request gives no limit"))
+        .arg(clap::Arg::with_name("max-page-size")
+            .long("--max-page-size")
+            .takes_value(true)
+            .help("Largest limit a model listing request may give"))
         .arg(clap::Arg::with_name("start-doc")
             .long("--start-doc")
             .short("-D")
             .help("Start doc dev server"))
+        .subcommand(clap::SubCommand::with_name("validate")
+            .about("Check the app definition in a directory")
+            .arg(clap::Arg::with_name("dir")
+                .help("Directory of app.yml, the current one by default")))
         .get_matches();
+    if let Some(m) = matches.subcommand_matches("validate") {
+        validate_app(m.value_of("dir").unwrap_or("."));
+    }
     {
         let mut cfg = config::config_write();
         if let Some(x) = matches.value_of("addr") {
@@ -83,11 +152,39 @@ async fn main() -> Result<()> {
         if let Some(x) = matches.value_of("orm-addr") {
             cfg.orm_addr = x.to_string();
         }
+        if let Some(x) = matches.value_of("orm-timeout") {
+            cfg.orm_timeout = x.parse().expect("Invalid ORM timeout");
+        }
+        if let Some(x) = matches.value_of("orm-retries") {
+            cfg.orm_retries = x.parse().expect("Invalid number of ORM retries");
+        }
+        if let Some(x) = matches.value_of("keep-deployments") {
+            cfg.deployment_retention = x.parse().expect("Invalid number of deployments to keep");
+        }
+        cfg.allow_local_repos = matches.is_present("allow-local-repos");
+        if let Some(x) = matches.value_of("page-size") {
+            cfg.default_page_size = x.parse().expect("Invalid page size");
+        }
+        if let Some(x) = matches.value_of("max-page-size") {
+            cfg.max_page_size = x.parse().expect("Invalid maximum page size");
+        }
     }
     let stats = stats::try_load_stats().await;
     let ctx = Arc::new(Context::new(stats));
-    if !matches.is_present("no-start-orm") {
-        orm::start().await.expect("Cannot start ORM");
+    if matches.value_of("store") == Some("django") {
+        if !matches.is_present("no-start-orm") {
+            orm::start().await.expect("Cannot start ORM");
+        }
+        store::set_store(Arc::new(orm::DjangoStore));
+    } else {
+        let data_dir = config().data_dir.clone();
+        std::fs::create_dir_all(&data_dir).expect("Cannot create data directory");
+        let s = store::SqliteStore::open_data_dir(std::path::Path::new(&data_dir))
+            .expect("Cannot open metadata store");
+        if let Some(x) = matches.value_of("import-django") {
+            s.import_django(std::path::Path::new(x)).expect("Cannot import Django database");
+        }
+        store::set_store(Arc::new(s));
     }
     if matches.is_present("start-ui") {
         start_ui();
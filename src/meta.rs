@@ -1,16 +1,45 @@
-use std::fs;
 use std::sync::Arc;
-use std::process::Command;
 use serde::{Serialize, Deserialize};
+use serde_json::json;
 use hyper::Method;
 use crate::types::*;
 use crate::http::*;
-use crate::apps::*;
 use crate::auth::authenticate;
+use crate::sync::preview_sync;
+use crate::worker::{submit_sync, submit_rollback};
+use crate::webhook::handle_webhook_request;
+use crate::deploy::list_deployments;
+
+/* An app as listed to its owner. The admin token and webhook secret are left out, they're only
+ * given out by /meta/credentials. */
+#[derive(Debug, Serialize)]
+struct AppInfo {
+    id: Option<i64>,
+    name: String,
+    handle: String,
+    git_repo: Option<String>,
+    git_ref: Option<String>,
+    poll_interval: Option<u32>,
+    app_path: Option<String>,
+}
+
+impl From<OctApp> for AppInfo {
+    fn from(app: OctApp) -> AppInfo {
+        AppInfo {
+            id: app.id,
+            name: app.name,
+            handle: app.handle,
+            git_repo: app.git_repo,
+            git_ref: app.git_ref,
+            poll_interval: app.poll_interval,
+            app_path: app.app_path,
+        }
+    }
+}
 
 #[derive(Debug, Serialize)]
 struct AppGet {
-    info: OctApp,
+    info: AppInfo,
     base_uri: String,
     status: String,
     storage_limit_mb: u32,
@@ -27,7 +56,7 @@ async fn handle_app_get(_ctx: Arc<Context>, user: OctUser, _req: Request) -> Res
         let events = app.get_events().await?;
         ret.push(AppGet {
             status: app.status().to_string(),
-            info: app,
+            info: app.into(),
             base_uri,
             storage_limit_mb: 1024,
             storage_usage_mb: used_mb as u32,
@@ -53,26 +82,46 @@ async fn handle_app_post(_ctx: Arc<Context>, user: OctUser, req: Request) -> Res
     json_response(&1)
 }
 
-async fn handle_app_put(_ctx: Arc<Context>, user: OctUser, req: Request) -> Result<Response> {
+async fn handle_app_put(ctx: Arc<Context>, user: OctUser, req: Request) -> Result<Response> {
     let data = String::from_utf8(to_bytes(req.into_body()).await?.to_vec())?;
-    let mut newdata: OctApp = match serde_json::from_str(&data) {
+    /* The fields the owner may change, the rest of what GET returns is ignored */
+    #[derive(Debug, Deserialize)]
+    struct AppPutRequest {
+        name: String,
+        git_repo: Option<String>,
+        git_ref: Option<String>,
+        /* Replace the webhook secret, kept if not given */
+        webhook_secret: Option<String>,
+        poll_interval: Option<u32>,
+        app_path: Option<String>,
+    }
+    let newdata: AppPutRequest = match serde_json::from_str(&data) {
         Ok(x) => x,
         Err(_) => { return http400("Invalid json"); },
     };
-    let app = if let Ok(x) = OctApp::by_name(&user.username, &newdata.name).await {
+    let mut app = if let Ok(x) = OctApp::by_name(&user.username, &newdata.name).await {
         x
     } else {
         return http404("App not found");
     };
-    newdata.id = app.id;
-    newdata.user = app.user;
-    newdata.name = app.name;
-    newdata.handle = app.handle;
-    newdata.update().await?;
+    if let Some(p) = newdata.app_path.as_deref().filter(|x| !x.is_empty()) {
+        if let Err(e) = validate_file_path(p) {
+            return http400(&format!("Invalid app path: {}", e));
+        }
+    }
+    app.git_repo = newdata.git_repo;
+    app.git_ref = newdata.git_ref;
+    if newdata.webhook_secret.is_some() {
+        app.webhook_secret = newdata.webhook_secret;
+    }
+    app.poll_interval = newdata.poll_interval;
+    app.app_path = newdata.app_path;
+    app.update().await?;
+    ctx.apps().invalidate(&app.handle);
     json_response(&1)
 }
 
-async fn handle_app_delete(_ctx: Arc<Context>, user: OctUser, req: Request) -> Result<Response> {
+async fn handle_app_delete(ctx: Arc<Context>, user: OctUser, req: Request) -> Result<Response> {
     let data = String::from_utf8(to_bytes(req.into_body()).await?.to_vec())?;
     #[derive(Deserialize)]
     struct AppDeleteReq {
@@ -87,8 +136,9 @@ async fn handle_app_delete(_ctx: Arc<Context>, user: OctUser, req: Request) -> R
     } else {
         return http404("app not found");
     };
-    let r = OctApp::delete(app.id.unwrap()).await?;
-    json_response(&r)
+    app.delete().await?;
+    ctx.apps().invalidate(&app.handle);
+    json_response(&1)
 }
 
 async fn handle_app_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
@@ -102,67 +152,79 @@ async fn handle_app_request(ctx: Arc<Context>, req: Request) -> Result<Response>
         &Method::POST => handle_app_post(ctx, user, req).await,
         &Method::PUT => handle_app_put(ctx, user, req).await,
         &Method::DELETE => handle_app_delete(ctx, user, req).await,
-        _ => http404("method not recognized"),
+        _ => http405(),
     }
 }
 
-fn check_migration(_old: &Option<AppDef>, _new: &AppDef) -> Result<()> {
-    // TODO: validate model change between cur and next
-    Ok(())
+async fn handle_sync_post(ctx: Arc<Context>, req: Request) -> Result<Response> {
+    let user = if let Some(x) = authenticate(&req).await {
+        x
+    } else {
+        return http401("Invalid or empty token in request");
+    };
+    let data = String::from_utf8(to_bytes(req.into_body()).await?.to_vec())?;
+    #[derive(Deserialize)]
+    struct Req {
+        name: String,
+        /* Only report what the sync would change */
+        dry_run: Option<bool>,
+    }
+    let req: Req = match serde_json::from_str(&data) {
+        Ok(x) => x,
+        Err(_) => { return http400("Invalid request"); },
+    };
+    let username = user.username;
+    let appname = req.name;
+    let app = if let Ok(x) = OctApp::by_name(&username, &appname).await {
+        x
+    } else {
+        return http404("App not found");
+    };
+    if app.git_repo.is_none() {
+        return http400("Repo has no associated git");
+    }
+    if req.dry_run.unwrap_or(false) {
+        return match preview_sync(&app).await {
+            Ok(x) => json_response(&x),
+            Err(e) if e.is::<AppDefErrors>() => error_response(&HttpError::from_error(&e)),
+            Err(e) => error_response(&HttpError::new(400, "sync_failed", &format!("Failed to check app: {}", e))),
+        };
+    }
+    let id = submit_sync(ctx.clone(), app, &username)?;
+    let job = ctx.jobs().get(id).cloned();
+    json_response_with_status(202, &job)
 }
 
-async fn sync_app(app: &OctApp) -> Result<()> {
-    let appd = app.dir();
-    let next = appd.child("sync-wip");
-    let repod = app.repo();
-    fs::remove_dir_all(&next.fullpath());
-    let repo = if let Some(x) = &app.git_repo {
+async fn handle_sync_get(ctx: Arc<Context>, req: Request) -> Result<Response> {
+    let user = if let Some(x) = authenticate(&req).await {
         x
     } else {
-        bail!("Git repo is not set for app");
+        return http401("Invalid or empty token in request");
     };
-    let opts = if let Some(r) = &app.git_ref {
-        if r != "" {
-            format!("-b '{}'", r)
+    let query = get_query(&req);
+    let job = if let Some(id) = query.get("job") {
+        let id: u64 = match id.parse() {
+            Ok(x) => x,
+            Err(_) => { return http400("Invalid job id"); },
+        };
+        ctx.jobs().get(id).filter(|x| Some(x.user) == user.id).cloned()
+    } else if let Some(name) = query.get("name") {
+        let app = if let Ok(x) = OctApp::by_name(&user.username, name).await {
+            x
         } else {
-            "".to_string()
-        }
+            return http404("App not found");
+        };
+        ctx.jobs().latest(&app).cloned()
     } else {
-        "".to_string()
-    };
-    app.event(&format!("Cloning {}...", repo)).await;
-    let cmd = format!("git clone --depth 1 {} '{}' {}",
-                      opts,
-                      repo,
-                      next.fullpath());
-    let r = Command::new("sh")
-                    .current_dir(appd.fullpath())
-                    .args(&["-c", &cmd])
-                    .status()?;
-
-    if ! r.success() {
-        let err = "Failed to clone repo";
-        app.event(err);
-        bail!(err);
-    }
-    app.event(&format!("Checking schema...")).await;
-    let olddef = if app.running() {
-        Some(get_repo_app_def(&repod).await?)
-    } else {
-        None
-    };
-    let newdef = get_repo_app_def(&next).await?;
-    check_migration(&olddef, &newdef)?;
-    app.event(&format!("Sync database...")).await;
-    sync_models(&app.db()?, &olddef, &newdef).await?;
-    app.event(&format!("Activating...")).await;
-    fs::remove_dir_all(&repod.fullpath());
-    fs::rename(&next.fullpath(), &repod.fullpath())?;
-    app.event(&format!("Done, app is up!")).await;
-    Ok(())
-}
-
-async fn handle_sync_post(_ctx: Arc<Context>, req: Request) -> Result<Response> {
+        return http400("Missing job id or app name");
+    };
+    match job {
+        Some(x) => json_response(&x),
+        None => http404("Sync job not found"),
+    }
+}
+
+async fn handle_sync_delete(ctx: Arc<Context>, req: Request) -> Result<Response> {
     let user = if let Some(x) = authenticate(&req).await {
         x
     } else {
@@ -170,37 +232,158 @@ async fn handle_sync_post(_ctx: Arc<Context>, req: Request) -> Result<Response>
     };
     let data = String::from_utf8(to_bytes(req.into_body()).await?.to_vec())?;
     #[derive(Deserialize)]
-    struct Req { name: String }
+    struct Req {
+        job: u64,
+    }
     let req: Req = match serde_json::from_str(&data) {
         Ok(x) => x,
         Err(_) => { return http400("Invalid request"); },
     };
-    let username = user.username;
-    let appname = req.name;
-    let app = if let Ok(x) = OctApp::by_name(&username, &appname).await {
+    let mut jobs = ctx.jobs();
+    if jobs.get(req.job).filter(|x| Some(x.user) == user.id).is_none() {
+        return http404("Sync job not found");
+    }
+    jobs.cancel(req.job)?;
+    json_response(&1)
+}
+
+async fn handle_sync_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
+    match *req.method() {
+        Method::GET => handle_sync_get(ctx, req).await,
+        Method::POST => handle_sync_post(ctx, req).await,
+        Method::DELETE => handle_sync_delete(ctx, req).await,
+        _ => http405(),
+    }
+}
+
+async fn handle_deployments_get(_ctx: Arc<Context>, req: Request) -> Result<Response> {
+    let user = if let Some(x) = authenticate(&req).await {
+        x
+    } else {
+        return http401("Invalid or empty token in request");
+    };
+    let name = if let Some(x) = get_query(&req).remove("name") {
+        x
+    } else {
+        return http400("Missing app name");
+    };
+    let app = if let Ok(x) = OctApp::by_name(&user.username, &name).await {
         x
     } else {
         return http404("App not found");
     };
-    if app.git_repo.is_some() {
-        match sync_app(&app).await {
-            Ok(_) => (),
-            Err(e) => {
-                let msg = format!("Failed to sync app: {}", e);
-                app.event(&msg).await;
-                return http400(&msg);
-            },
-        }
-        json_response(&1)
+    json_response(&list_deployments(&app).await?)
+}
+
+async fn handle_rollback_post(ctx: Arc<Context>, req: Request) -> Result<Response> {
+    let user = if let Some(x) = authenticate(&req).await {
+        x
+    } else {
+        return http401("Invalid or empty token in request");
+    };
+    let data = String::from_utf8(to_bytes(req.into_body()).await?.to_vec())?;
+    #[derive(Deserialize)]
+    struct Req {
+        name: String,
+        deployment: u32,
+    }
+    let req: Req = match serde_json::from_str(&data) {
+        Ok(x) => x,
+        Err(_) => { return http400("Invalid request"); },
+    };
+    let app = if let Ok(x) = OctApp::by_name(&user.username, &req.name).await {
+        x
     } else {
-        http400("Repo has no associated git")
+        return http404("App not found");
+    };
+    if !list_deployments(&app).await?.iter().any(|x| x.id == req.deployment) {
+        return http404("Deployment not found");
     }
+    let id = submit_rollback(ctx.clone(), app, req.deployment, &user.username)?;
+    let job = ctx.jobs().get(id).cloned();
+    json_response_with_status(202, &job)
 }
 
-async fn handle_sync_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
+async fn handle_credentials_get(_ctx: Arc<Context>, req: Request) -> Result<Response> {
+    let user = if let Some(x) = authenticate(&req).await {
+        x
+    } else {
+        return http401("Invalid or empty token in request");
+    };
+    let name = if let Some(x) = get_query(&req).remove("name") {
+        x
+    } else {
+        return http400("Missing app name");
+    };
+    let app = if let Ok(x) = OctApp::by_name(&user.username, &name).await {
+        x
+    } else {
+        return http404("App not found");
+    };
+    json_response(&json!({
+        "deploy_key": app.deploy_public_key().await?,
+        "https_token": app.has_https_token(),
+        "admin_token": app.admin_token,
+        "webhook_secret": app.webhook_secret,
+    }))
+}
+
+async fn handle_credentials_put(_ctx: Arc<Context>, req: Request) -> Result<Response> {
+    let user = if let Some(x) = authenticate(&req).await {
+        x
+    } else {
+        return http401("Invalid or empty token in request");
+    };
+    let data = String::from_utf8(to_bytes(req.into_body()).await?.to_vec())?;
+    #[derive(Deserialize)]
+    struct Req {
+        name: String,
+        /* Replace the deploy key with a new one */
+        regenerate_key: Option<bool>,
+        https_username: Option<String>,
+        /* Set the HTTPS token, an empty one removes it */
+        https_token: Option<String>,
+    }
+    let req: Req = match serde_json::from_str(&data) {
+        Ok(x) => x,
+        Err(_) => { return http400("Invalid request"); },
+    };
+    let app = if let Ok(x) = OctApp::by_name(&user.username, &req.name).await {
+        x
+    } else {
+        return http404("App not found");
+    };
+    if req.regenerate_key.unwrap_or(false) {
+        app.generate_deploy_key().await?;
+    }
+    if let Some(token) = &req.https_token {
+        app.set_https_token(req.https_username.as_deref(), Some(token))?;
+    }
+    json_response(&json!({
+        "deploy_key": app.deploy_public_key().await?,
+        "https_token": app.has_https_token(),
+    }))
+}
+
+async fn handle_credentials_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
+    match *req.method() {
+        Method::GET => handle_credentials_get(ctx, req).await,
+        Method::PUT => handle_credentials_put(ctx, req).await,
+        _ => http405(),
+    }
+}
+
+async fn handle_deployments_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
+    match req.method() {
+        &Method::GET => handle_deployments_get(ctx, req).await,
+        _ => http405(),
+    }
+}
+
+async fn handle_rollback_request(ctx: Arc<Context>, req: Request) -> Result<Response> {
     match req.method() {
-        &Method::POST => handle_sync_post(ctx, req).await,
-        _ => http404("method not recognized"),
+        &Method::POST => handle_rollback_post(ctx, req).await,
+        _ => http405(),
     }
 }
 
@@ -210,7 +393,28 @@ pub async fn handle_meta_request(ctx: Arc<Context>, req: Request) -> Result<Resp
         handle_app_request(ctx, req).await
     } else if path == "/meta/sync" {
         handle_sync_request(ctx, req).await
+    } else if let Some(handle) = path.strip_prefix("/meta/hook/") {
+        let handle = handle.to_string();
+        handle_webhook_request(ctx, &handle, req).await
+    } else if path == "/meta/credentials" {
+        handle_credentials_request(ctx, req).await
+    } else if path == "/meta/deployments" {
+        handle_deployments_request(ctx, req).await
+    } else if path == "/meta/rollback" {
+        handle_rollback_request(ctx, req).await
     } else {
-        http404("")
+        http404("Not found")
     }
 }
+
+#[test]
+fn app_info_test() {
+    let app = OctApp {
+        admin_token: "adm1n".to_string(),
+        webhook_secret: Some("s3cret".to_string()),
+        ..OctApp::for_test("h")
+    };
+    let s = serde_json::to_string(&AppInfo::from(app)).unwrap();
+    assert!(s.contains("\"handle\":\"h\""));
+    assert!(!s.contains("adm1n") && !s.contains("s3cret"));
+}
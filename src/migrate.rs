@@ -0,0 +1,397 @@
+use std::collections::HashSet;
+use crate::types::*;
+use crate::db::{DB, ColumnInfo};
+
+/*
+ * Migration of the app database to the models of a new app.yml. The plan is made by comparing
+ * the models with the tables as they are in the database, so it doesn't matter which app.yml
+ * version created them. Simple changes are done with ALTER TABLE, anything SQLite can't alter in
+ * place (type changes, dropped columns, nullability) rebuilds the table by copying it.
+ */
+
+/* Columns of every model table besides the model fields */
+const INTERNAL_COLUMNS: [&str; 4] = ["id", "_oct_owner", "_oct_create_time", "_oct_update_time"];
+const REBUILD_PREFIX: &str = "__oct_rebuild_";
+
+#[derive(Debug)]
+pub struct TableInfo {
+    pub columns: Vec<ColumnInfo>,
+    pub rows: usize,
+}
+
+pub type DbSchema = HashMap<String, TableInfo>;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct MigrationStep {
+    pub description: String,
+    /* Whether the step loses data that exists in the database */
+    pub destructive: bool,
+    pub sql: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Default)]
+pub struct MigrationPlan {
+    pub steps: Vec<MigrationStep>,
+}
+
+impl MigrationPlan {
+    /* Refuse destructive steps unless app.yml allows them */
+    pub fn check(&self, def: &AppDef) -> Result<()> {
+        let allowed = def.migration.as_ref()
+            .and_then(|x| x.allow_destructive)
+            .unwrap_or(false);
+        let destructive: Vec<&str> = self.steps.iter()
+            .filter(|x| x.destructive)
+            .map(|x| x.description.as_str())
+            .collect();
+        if !allowed && !destructive.is_empty() {
+            bail!("Migration would lose data, set migration.allow_destructive in app.yml to proceed: {}",
+                  destructive.join("; "));
+        }
+        Ok(())
+    }
+
+    pub async fn apply(&self, db: &DB) -> Result<()> {
+        let stmts: Vec<String> = self.steps.iter()
+            .flat_map(|x| x.sql.iter().cloned())
+            .collect();
+        db.execute_in_transaction(&stmts).await
+    }
+}
+
+fn step(description: String, destructive: bool, sql: Vec<String>) -> MigrationStep {
+    MigrationStep {
+        description,
+        destructive,
+        sql,
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ColumnKind {
+    Text,
+    Integer,
+    Real,
+    Boolean,
+    DateTime,
+    Other,
+}
+
+fn column_kind(decl_type: &str) -> ColumnKind {
+    let t = decl_type.to_uppercase();
+    if t.starts_with("VARCHAR") || t == "TEXT" {
+        ColumnKind::Text
+    } else if t == "BIGINT" {
+        ColumnKind::Integer
+    } else if t == "FLOAT" {
+        ColumnKind::Real
+    } else if t == "INTEGER" {
+        ColumnKind::Boolean
+    } else if t == "DATETIME" {
+        ColumnKind::DateTime
+    } else {
+        ColumnKind::Other
+    }
+}
+
+/* Whether every value of a column survives the conversion */
+fn lossless(from: ColumnKind, to: ColumnKind) -> bool {
+    use ColumnKind::*;
+    match (from, to) {
+        (a, b) if a == b => true,
+        (Other, _) => false,
+        (_, Text) => true,
+        (Integer, DateTime) | (DateTime, Integer) => true,
+        (Boolean, Integer) | (Boolean, Real) | (Integer, Real) => true,
+        _ => false,
+    }
+}
+
+fn sql_literal(v: &RowField) -> String {
+    match v {
+        RowField::String(x) => format!("'{}'", x.replace('\'', "''")),
+        RowField::Integer(x) | RowField::DateTime(x) => x.to_string(),
+        RowField::Float(x) => format!("{:?}", x),
+        RowField::Boolean(x) => if *x { "1" } else { "0" }.to_string(),
+        RowField::Null => "NULL".to_string(),
+    }
+}
+
+fn plan_table(model: &ModelDef, info: &TableInfo, steps: &mut Vec<MigrationStep>) -> Result<()> {
+    let table = &model.name;
+    let fields = model.fields.as_deref().unwrap_or(&[]);
+    let existing: HashMap<&str, &ColumnInfo> = info.columns.iter()
+        .map(|c| (c.name.as_str(), c))
+        .collect();
+    let has_data = info.rows > 0;
+    let mut rebuild = Vec::new();
+    let mut destructive = false;
+    let mut alters = Vec::new();
+    /* Column of the new table and the expression to fill it from the old one */
+    let mut copies: Vec<(&str, String)> = Vec::new();
+    let mut used: HashSet<&str> = HashSet::new();
+
+    for f in fields {
+        let name = f.name();
+        let renamed = f.renamed_from()
+            .filter(|x| !existing.contains_key(name) && existing.contains_key(x))
+            .filter(|x| !fields.iter().any(|g| g.name() == *x));
+        let source = if existing.contains_key(name) { Some(name) } else { renamed };
+        let default = f.default_value();
+        let col = match source {
+            Some(x) => existing[x],
+            None => {
+                match (&default, f.is_optional()) {
+                    (Some(d), _) => {
+                        copies.push((name, sql_literal(d)));
+                        let sql = format!("ALTER TABLE {} ADD COLUMN {} DEFAULT {}",
+                                          table, f.type_sql(), sql_literal(d));
+                        alters.push(step(format!("Add field {}.{} with default {}", table, name, sql_literal(d)),
+                                         false, vec![sql]));
+                    },
+                    (None, true) => {
+                        let sql = format!("ALTER TABLE {} ADD COLUMN {}", table, f.type_sql());
+                        alters.push(step(format!("Add field {}.{}", table, name), false, vec![sql]));
+                    },
+                    (None, false) if has_data => {
+                        bail!("New required field {}.{} needs a default for the existing rows", table, name);
+                    },
+                    (None, false) => rebuild.push(format!("add required field {}", name)),
+                }
+                continue;
+            },
+        };
+        used.insert(&col.name);
+        if let Some(old) = renamed {
+            let sql = format!("ALTER TABLE {} RENAME COLUMN {} TO {}", table, old, name);
+            alters.push(step(format!("Rename field {}.{} to {}", table, old, name), false, vec![sql]));
+        }
+
+        let mut expr = col.name.to_string();
+        let (from, to) = (column_kind(&col.decl_type), column_kind(&f.column_type()));
+        if from != to {
+            rebuild.push(format!("change type of {} from {} to {}", name, col.decl_type, f.column_type()));
+            destructive |= has_data && !lossless(from, to);
+            expr = format!("CAST({} AS {})", expr, f.column_type());
+        }
+        if !col.notnull && !f.is_optional() {
+            match &default {
+                Some(d) => expr = format!("COALESCE({}, {})", expr, sql_literal(d)),
+                None if has_data => {
+                    bail!("Field {}.{} became required and needs a default for the existing rows", table, name);
+                },
+                None => (),
+            }
+            rebuild.push(format!("make {} required", name));
+        } else if col.notnull && f.is_optional() {
+            rebuild.push(format!("make {} optional", name));
+        }
+        copies.push((name, expr));
+    }
+    for c in &info.columns {
+        if !INTERNAL_COLUMNS.contains(&c.name.as_str()) && !used.contains(c.name.as_str()) {
+            rebuild.push(format!("drop field {}", c.name));
+            destructive |= has_data;
+        }
+    }
+
+    if rebuild.is_empty() {
+        steps.extend(alters);
+        return Ok(());
+    }
+    /* The copy also takes care of renames and new fields */
+    let tmp = format!("{}{}", REBUILD_PREFIX, table);
+    let mut cols: Vec<&str> = INTERNAL_COLUMNS.to_vec();
+    let mut exprs: Vec<String> = INTERNAL_COLUMNS.iter().map(|x| x.to_string()).collect();
+    for (c, e) in copies {
+        cols.push(c);
+        exprs.push(e);
+    }
+    let sql = vec![
+        model.create_table_query(&tmp),
+        format!("INSERT INTO {} ({}) SELECT {} FROM {}", tmp, cols.join(", "), exprs.join(", "), table),
+        format!("DROP TABLE {}", table),
+        format!("ALTER TABLE {} RENAME TO {}", tmp, table),
+    ];
+    steps.push(step(format!("Rebuild table {}: {}", table, rebuild.join(", ")), destructive, sql));
+    Ok(())
+}
+
+/* Plan the migration of the database with the given tables to the models of def */
+pub fn plan(schema: &DbSchema, def: &AppDef) -> Result<MigrationPlan> {
+    let mut steps = Vec::new();
+    let user_model = ModelDef::make_user_model();
+    if !schema.contains_key(&user_model.name) {
+        /* Cannot migrate internal models yet, only create them. */
+        steps.push(step(format!("Create table {}", user_model.name), false,
+                        vec![user_model.create_table_query(&user_model.name)]));
+    }
+    let mut kept: HashSet<&str> = HashSet::new();
+    for m in &def.models {
+        if let Some(info) = schema.get(&m.name) {
+            kept.insert(&m.name);
+            plan_table(m, info, &mut steps)?;
+            continue;
+        }
+        let renamed = m.renamed_from.as_deref()
+            .filter(|x| schema.contains_key(*x) && def.get_model(x).is_none());
+        if let Some(old) = renamed {
+            kept.insert(old);
+            steps.push(step(format!("Rename model {} to {}", old, m.name), false,
+                            vec![format!("ALTER TABLE {} RENAME TO {}", old, m.name)]));
+            plan_table(m, &schema[old], &mut steps)?;
+            continue;
+        }
+        steps.push(step(format!("Create table {}", m.name), false, vec![m.create_table_query(&m.name)]));
+    }
+    let mut dropped: Vec<(&String, &TableInfo)> = schema.iter()
+        .filter(|(t, _)| !t.starts_with("__oct") && !kept.contains(t.as_str()))
+        .collect();
+    dropped.sort_by_key(|x| x.0);
+    for (t, info) in dropped {
+        steps.push(step(format!("Drop table {} with {} rows", t, info.rows), info.rows > 0,
+                        vec![format!("DROP TABLE {}", t)]));
+    }
+    Ok(MigrationPlan {
+        steps,
+    })
+}
+
+pub async fn snapshot(db: &DB) -> Result<DbSchema> {
+    let mut ret = HashMap::new();
+    for t in db.tables().await? {
+        if t.starts_with("sqlite_") {
+            continue;
+        }
+        let info = TableInfo {
+            columns: db.columns(&t).await?,
+            rows: db.row_count(&t).await?,
+        };
+        ret.insert(t, info);
+    }
+    Ok(ret)
+}
+
+/* Plan the migration of db to the models of def, checking that it is allowed */
+pub async fn plan_migration(db: &DB, def: &AppDef) -> Result<MigrationPlan> {
+    let plan = plan(&snapshot(db).await?, def)?;
+    plan.check(def)?;
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Query;
+
+    const OLD: &str = "
+meta:
+  schema: v0.0.1
+name: test
+models:
+  - name: todo
+    fields:
+      - name: subject
+        type: string
+      - name: priority
+        type: integer
+      - name: note
+        type: string
+        optional: true
+api:
+  endpoints: []
+";
+
+    async fn setup() -> (tempfile::NamedTempFile, DB) {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let db = DB::new(tf.path().to_str().unwrap()).unwrap();
+        let def = AppDef::from_yaml(OLD).unwrap();
+        plan_migration(&db, &def).await.unwrap().apply(&db).await.unwrap();
+        db.execute("INSERT INTO todo (subject, priority) VALUES ('a', 1)", &[]).await.unwrap();
+        (tf, db)
+    }
+
+    const PRIORITY: &str = "      - name: priority\n        type: integer\n";
+    const NOTE: &str = "      - name: note\n        type: string\n        optional: true\n";
+
+    fn app(yaml: &str) -> AppDef {
+        AppDef::from_yaml(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrate_test() {
+        let (_tf, db) = setup().await;
+        assert!(plan_migration(&db, &app(OLD)).await.unwrap().steps.is_empty());
+
+        /* Renames and new required fields with a default are done in place */
+        let yaml = OLD.replace(PRIORITY, "      - name: rank
+        type: integer
+        renamed_from: priority
+      - name: done
+        type: boolean
+        default: false
+");
+        let def = app(&yaml);
+        let plan = plan_migration(&db, &def).await.unwrap();
+        assert_eq!(plan.steps.len(), 2);
+        assert!(plan.steps.iter().all(|x| !x.destructive && x.sql.len() == 1));
+        plan.apply(&db).await.unwrap();
+        let r = db.get(&Query::new(&def.models[0])).await.unwrap().unwrap();
+        assert_eq!(r.get_int("rank"), Some(1));
+        assert_eq!(r.get("done"), Some(&RowField::Boolean(false)));
+
+        /* A field can't become required without a default for the existing rows */
+        let yaml = yaml.replace("        optional: true\n", "");
+        assert!(plan_migration(&db, &app(&yaml)).await.is_err());
+        let yaml = yaml.replace(NOTE.replace("        optional: true\n", "").as_str(),
+                                "      - name: note\n        type: string\n        default: ''\n");
+        plan_migration(&db, &app(&yaml)).await.unwrap().apply(&db).await.unwrap();
+        assert_eq!(db.get(&Query::new(&app(&yaml).models[0])).await.unwrap().unwrap().get_str("note"), Some(""));
+    }
+
+    #[tokio::test]
+    async fn rebuild_test() {
+        let (_tf, db) = setup().await;
+
+        /* Lossless type change rebuilds the table */
+        let yaml = OLD.replace(PRIORITY, "      - name: priority\n        type: string\n");
+        let def = app(&yaml);
+        let plan = plan_migration(&db, &def).await.unwrap();
+        assert_eq!(plan.steps.len(), 1);
+        assert!(plan.steps[0].description.starts_with("Rebuild table todo"));
+        assert!(!plan.steps[0].destructive);
+        plan.apply(&db).await.unwrap();
+        let r = db.get(&Query::new(&def.models[0])).await.unwrap().unwrap();
+        assert_eq!(r.get_str("priority"), Some("1"));
+        assert_eq!(r.get_int("id"), Some(1));
+
+        /* Lossy type change or dropping a field need to be allowed */
+        assert!(plan_migration(&db, &app(OLD)).await.is_err());
+        let yaml = yaml.replace(NOTE, "");
+        assert!(plan_migration(&db, &app(&yaml)).await.is_err());
+        let yaml = yaml + "migration:\n  allow_destructive: true\n";
+        plan_migration(&db, &app(&yaml)).await.unwrap().apply(&db).await.unwrap();
+        assert!(!db.columns("todo").await.unwrap().iter().any(|c| c.name == "note"));
+        assert_eq!(db.row_count("todo").await.unwrap(), 1);
+
+        /* Renamed models keep their rows, other tables are dropped */
+        let yaml = yaml.replace("  - name: todo\n", "  - name: task\n    renamed_from: todo\n");
+        let plan = plan_migration(&db, &app(&yaml)).await.unwrap();
+        assert_eq!(plan.steps.len(), 1);
+        plan.apply(&db).await.unwrap();
+        assert_eq!(db.row_count("task").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_plan_changes_nothing_test() {
+        let (_tf, db) = setup().await;
+        let plan = MigrationPlan {
+            steps: vec![
+                step("ok".to_string(), false, vec!["ALTER TABLE todo ADD COLUMN x BIGINT".to_string()]),
+                step("bad".to_string(), false, vec!["ALTER TABLE missing ADD COLUMN y BIGINT".to_string()]),
+            ],
+        };
+        assert!(plan.apply(&db).await.is_err());
+        assert!(!db.columns("todo").await.unwrap().iter().any(|c| c.name == "x"));
+    }
+}
@@ -1,12 +1,38 @@
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::collections::HashSet;
+use serde_json::Value;
 use crate::types::*;
-use crate::db::{DB, DbValue};
+use crate::db::{DB, Query};
+
+const CURSOR_PREFIX: &str = "after:";
 
 fn timestamp() -> i64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
 }
 
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/* A cursor names the sort keys of the page along with their values in its last row, so that
+ * the next page starts after that row even if rows were added or removed before it */
+fn encode_cursor(keys: &[(String, RowField)]) -> String {
+    /* Can't fail, there are no maps with non-string keys */
+    let json = serde_json::to_string(keys).unwrap();
+    base64::encode_config(format!("{}{}", CURSOR_PREFIX, json), base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<(String, RowField)>> {
+    let invalid = || anyhow!("Invalid cursor");
+    let raw = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+    let s = String::from_utf8(raw).map_err(|_| invalid())?;
+    let json = s.strip_prefix(CURSOR_PREFIX).ok_or_else(invalid)?;
+    serde_json::from_str(json).map_err(|_| invalid())
+}
+
 impl FieldDef {
     pub fn name(&self) -> &str {
         match self {
@@ -20,40 +46,133 @@ impl FieldDef {
         }
     }
 
+    /* The default given in app.yml, as written */
+    pub fn declared_default(&self) -> Option<&Value> {
+        let r = match self {
+            Self::String(d) => &d.default,
+            Self::Integer(d) => &d.default,
+            Self::Float(d) => &d.default,
+            Self::Boolean(d) => &d.default,
+            Self::DateTime(d) => &d.default,
+            Self::User(d) => &d.default,
+            Self::Reference(d) => &d.default,
+        };
+        r.as_ref()
+    }
+
+    pub fn renamed_from(&self) -> Option<&str> {
+        let r = match self {
+            Self::String(d) => &d.renamed_from,
+            Self::Integer(d) => &d.renamed_from,
+            Self::Float(d) => &d.renamed_from,
+            Self::Boolean(d) => &d.renamed_from,
+            Self::DateTime(d) => &d.renamed_from,
+            Self::User(d) => &d.renamed_from,
+            Self::Reference(d) => &d.renamed_from,
+        };
+        r.as_deref()
+    }
+
     pub fn default_value(&self) -> Option<RowField> {
+        if let Some(x) = self.declared_default() {
+            return self.coerce_value(x).ok();
+        }
         match self {
             Self::String(_) => None,
             Self::Integer(_) => None,
             Self::Float(_) => None,
             Self::Boolean(_) => None,
-            Self::DateTime(d) =>
-                match d.default_now {
-                    Some(true) => Some(RowField::DateTime(timestamp())),
-                    _ => None,
-                },
+            /* Auto fields default to now too, for the rows that exist when they're added */
+            Self::DateTime(d) if d.default_now == Some(true) || self.is_auto() =>
+                Some(RowField::DateTime(timestamp())),
+            Self::DateTime(_) => None,
             Self::User(_) => None,
             Self::Reference(_) => None,
         }
     }
 
-    fn type_sql(&self) -> String {
-        let typename = match self {
-            FieldDef::String(_) => "VARCHAR(128)",
-            FieldDef::Integer(_) => "BIGINT",
-            FieldDef::Float(_) => "FLOAT",
-            FieldDef::Boolean(_) => "INTEGER",
-            FieldDef::DateTime(_) => "DATETIME",
-            FieldDef::User(_) => "BIGINT",
-            FieldDef::Reference(_) => "BIGINT",
+    /* Whether the field is maintained by the server, rather than set from the input */
+    pub fn is_auto(&self) -> bool {
+        matches!(self, Self::DateTime(d) if d.auto_now == Some(true) || d.auto_now_add == Some(true))
+    }
+
+    /* The value an auto field gets when a record is created or updated */
+    pub fn auto_value(&self, creating: bool) -> Option<RowField> {
+        match self {
+            Self::DateTime(d) if d.auto_now == Some(true) || (creating && d.auto_now_add == Some(true)) =>
+                Some(RowField::DateTime(timestamp())),
+            _ => None,
+        }
+    }
+
+    /* Convert a textual value, such as a query string parameter, to the type of this field */
+    pub fn parse_value(&self, s: &str) -> Result<RowField> {
+        let r = match self {
+            Self::String(_) => Some(RowField::String(s.to_string())),
+            Self::Integer(_) | Self::User(_) | Self::Reference(_) =>
+                s.parse().ok().map(RowField::Integer),
+            Self::Float(_) => s.parse().ok().map(RowField::Float),
+            Self::Boolean(_) => parse_bool(s).map(RowField::Boolean),
+            Self::DateTime(_) => s.parse().ok().map(RowField::DateTime),
         };
+        r.ok_or_else(|| anyhow!("Invalid value '{}' for field {}", s, self.name()))
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Integer(_) | Self::User(_) | Self::Reference(_) => "integer",
+            Self::Float(_) => "number",
+            Self::Boolean(_) => "boolean",
+            Self::DateTime(_) => "timestamp",
+        }
+    }
+
+    /* Convert a JSON input value to the type of this field. Non-string fields also accept their
+     * textual form, as parse_value does; nothing else is converted implicitly. */
+    pub fn coerce_value(&self, v: &Value) -> std::result::Result<RowField, String> {
+        let expected = || format!("expected {}", self.type_name());
+        match (self, v) {
+            (_, Value::Null) if self.is_optional() => Ok(RowField::Null),
+            (_, Value::Null) => Err("cannot be null".to_string()),
+            (Self::String(d), Value::String(x)) => match d.max_length {
+                Some(n) if x.chars().count() > n => Err(format!("longer than {} characters", n)),
+                _ => Ok(RowField::String(x.to_string())),
+            },
+            (Self::String(_), _) => Err(expected()),
+            (Self::Integer(_) | Self::User(_) | Self::Reference(_), Value::Number(x)) =>
+                x.as_i64().map(RowField::Integer).ok_or_else(expected),
+            (Self::DateTime(_), Value::Number(x)) =>
+                x.as_i64().map(RowField::DateTime).ok_or_else(expected),
+            (Self::Float(_), Value::Number(x)) =>
+                x.as_f64().map(RowField::Float).ok_or_else(expected),
+            (Self::Boolean(_), Value::Bool(x)) => Ok(RowField::Boolean(*x)),
+            (_, Value::String(x)) => self.parse_value(x).map_err(|_| expected()),
+            _ => Err(expected()),
+        }
+    }
+
+    pub fn column_type(&self) -> String {
+        match self {
+            FieldDef::String(d) => format!("VARCHAR({})", d.max_length.unwrap_or(128)),
+            FieldDef::Integer(_) => "BIGINT".to_string(),
+            FieldDef::Float(_) => "FLOAT".to_string(),
+            FieldDef::Boolean(_) => "INTEGER".to_string(),
+            FieldDef::DateTime(_) => "DATETIME".to_string(),
+            FieldDef::User(_) => "BIGINT".to_string(),
+            FieldDef::Reference(_) => "BIGINT".to_string(),
+        }
+    }
+
+    pub fn type_sql(&self) -> String {
         format!("{} {}{}",
             self.name(),
-            typename,
+            self.column_type(),
             if self.is_optional() { "" } else { " NOT NULL" },
         )
     }
 
-    fn is_optional(&self) -> bool {
+    pub fn is_optional(&self) -> bool {
         let optional = match self {
             FieldDef::String(d) => d.optional,
             FieldDef::Integer(d) => d.optional,
@@ -67,6 +186,16 @@ impl FieldDef {
     }
 }
 
+impl ReferenceDesc {
+    /* Name of the field on the target model that lists the records referring to it */
+    pub fn reverse_name(&self, source: &ModelDef) -> String {
+        match &self.related_name {
+            Some(x) => x.to_string(),
+            None => format!("{}_set", source.name.to_lowercase()),
+        }
+    }
+}
+
 impl ModelDef {
     pub fn make_user_model() -> ModelDef {
         let fields = vec![
@@ -80,11 +209,26 @@ impl ModelDef {
             description: Some("User model".to_string()),
             fields: Some(fields),
             visibility_scope: None,
+            renamed_from: None,
         }
     }
 
-    fn create_table_query(&self) -> String {
-        let table_name = &self.name;
+    /* The part of the user model that can be exposed to other users, e.g. through references */
+    pub fn make_public_user_model() -> ModelDef {
+        let fields = vec![
+            FieldDef::make_string("name", "user name"),
+            FieldDef::make_string("email", "user email"),
+        ];
+        ModelDef {
+            name: "__oct_user".to_string(),
+            description: Some("User model".to_string()),
+            fields: Some(fields),
+            visibility_scope: None,
+            renamed_from: None,
+        }
+    }
+
+    pub fn create_table_query(&self, table_name: &str) -> String {
         let mut ret = format!(r#"CREATE TABLE {} (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     _oct_owner BIGINT,
@@ -100,45 +244,15 @@ impl ModelDef {
         ret
     }
 
-    fn alter_table_query(&self, f: &FieldDef) -> String {
-        format!(r#"ALTER TABLE {}
-            ADD {}"#,
-            self.name,
-            f.type_sql()
-        )
-    }
-
-    pub async fn create_table(&self, db: &DB) -> Result<()> {
-        let sql = self.create_table_query();
-        db.execute(&sql, &[])?;
-        Ok(())
-    }
-
-    pub async fn alter_table(&self, db: &DB, old: &ModelDef) -> Result<()> {
-        let old_fields: HashSet<&str> = if let Some(x) = &old.fields {
-            x.into_iter().map(|f| f.name()).collect()
-        } else {
-            HashSet::new()
-        };
-        for f in self.fields.as_ref().unwrap_or(&Vec::new()) {
-            if old_fields.contains(f.name()) {
-                continue;
-            }
-            let sql = self.alter_table_query(f);
-            db.execute(&sql, &[])?;
-        }
-        Ok(())
-    }
-
-    pub async fn create(&self, db: &DB, rec: &Row, uid: Option<i64>) -> Result<()> {
-        let table_name = &self.name;
-        let mut keys = vec!["_oct_owner"];
-        let mut vals = vec![DbValue::Integer(uid.unwrap_or(-1))];
+    pub async fn create(&self, db: &DB, rec: &Row, uid: Option<i64>) -> Result<i64> {
+        let mut values = vec![("_oct_owner", RowField::Integer(uid.unwrap_or(-1)))];
         for desc in self.fields.as_ref().unwrap() {
             let name = desc.name();
-            let val = if !rec.fields.contains_key(name) {
+            let val = if let Some(x) = desc.auto_value(true) {
+                x
+            } else if !rec.fields.contains_key(name) {
                 if let Some(x) = desc.default_value() {
-                    x.to_db_value()
+                    x
                 } else {
                     if !desc.is_optional() {
                         bail!("Field {} is missing", name);
@@ -146,78 +260,457 @@ impl ModelDef {
                     continue;
                 }
             } else {
-                rec.fields.get(name).unwrap().to_db_value()
+                rec.fields.get(name).unwrap().clone()
             };
-            keys.push(name);
-            vals.push(val);
-        }
-        let sql = format!("INSERT INTO {} ({}) VALUES ({})",
-                           table_name,
-                           keys.join(","),
-                           keys.iter().map(|_| "?").collect::<Vec<&str>>().join(","));
-        db.execute(&sql, vals.as_slice());
-        Ok(())
+            values.push((name, val));
+        }
+        let st = Query::new(self).insert(&values)?;
+        db.insert(&st.sql, &st.params).await
+    }
+
+    /* Check an incoming JSON record against the model fields and convert it to a row. All the
+     * offending fields are reported together in a ValidationError. */
+    pub fn validate_input(&self, v: &Value, mode: WriteMode) -> Result<Row> {
+        let obj = match v {
+            Value::Object(o) => o,
+            _ => bail!("Record must be a JSON object"),
+        };
+        let mut ret = Row::new();
+        let mut errors = Vec::new();
+        let mut error = |field: &str, message: String| errors.push(FieldError {
+            field: field.to_string(),
+            message,
+        });
+        for (k, v) in obj {
+            if k == "id" {
+                match v.as_i64() {
+                    Some(x) => ret.set(k, RowField::Integer(x)),
+                    None => error(k, "expected integer".to_string()),
+                }
+                continue;
+            }
+            match self.get_field(k).map(|f| f.coerce_value(v)) {
+                Some(Ok(x)) => ret.set(k, x),
+                Some(Err(m)) => error(k, m),
+                None => error(k, "unknown field".to_string()),
+            }
+        }
+        if mode != WriteMode::Patch {
+            for desc in self.fields.as_ref().unwrap_or(&Vec::new()) {
+                let name = desc.name();
+                if obj.contains_key(name) || desc.is_auto() {
+                    continue;
+                }
+                match desc.default_value() {
+                    Some(x) if mode == WriteMode::Replace => ret.set(name, x),
+                    Some(_) => (),
+                    None if desc.is_optional() => {
+                        if mode == WriteMode::Replace {
+                            ret.set(name, RowField::Null);
+                        }
+                    },
+                    None => error(name, "is required".to_string()),
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ValidationError { errors }.into());
+        }
+        Ok(ret)
     }
 
-    pub async fn update(&self, db: &DB, rec: &Row, uid: Option<i64>) -> Result<()> {
-        let table_name = &self.name;
-        let mut keys = Vec::new();
-        let mut vals = Vec::new();
+    pub async fn update(&self, db: &DB, rec: &Row, uid: Option<i64>) -> Result<usize> {
+        let mut values = Vec::new();
         let mut id = None;
         for (k, v) in rec.fields.iter() {
             if k == "id" {
                 id = Some(v.get_int().unwrap());
                 continue;
             }
-            keys.push(k);
-            vals.push(v.to_db_value());
+            if self.get_field(k).is_some_and(|f| f.is_auto()) {
+                continue;
+            }
+            values.push((k.as_str(), v.clone()));
         }
         let id = if let Some(x) = id {
             x
         } else {
             bail!("No id found in rec");
         };
-        let sql = format!("UPDATE {} SET {} WHERE {}",
-                           table_name,
-                           keys.iter().map(|x| format!("{}=?", x)).collect::<Vec<String>>().join(","),
-                           self.condition(uid, Some(id))
-        );
-        db.execute(&sql, vals.as_slice())?;
-        Ok(())
+        if values.is_empty() {
+            bail!("No fields to update");
+        }
+        for desc in self.fields.iter().flatten() {
+            if let Some(x) = desc.auto_value(false) {
+                values.push((desc.name(), x));
+            }
+        }
+        let mut q = self.scoped(uid);
+        q.eq("id", RowField::Integer(id))?;
+        let st = q.update(&values)?;
+        let r = db.execute(&st.sql, &st.params).await?;
+        Ok(r)
     }
 
-
     pub async fn delete(&self, db: &DB, pks: &[i64], uid: Option<i64>) -> Result<usize> {
-        let sql = format!("DELETE FROM {} WHERE {} AND id in ({})",
-                           self.name,
-                           self.condition(uid, None),
-                           pks.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(","));
-
-        let r = db.execute(&sql, &[])?;
+        let mut q = self.scoped(uid);
+        q.filter("id", &FilterOp::In(pks.iter().map(|x| RowField::Integer(*x)).collect()))?;
+        let st = q.delete();
+        let r = db.execute(&st.sql, &st.params).await?;
         Ok(r)
     }
 
     pub async fn select(&self, db: &DB, uid: Option<i64>, id: Option<i64>) -> Result<Vec<Row>> {
-        db.query(self, &self.condition(uid, id))
+        let q = ModelQuery {
+            id,
+            ..Default::default()
+        };
+        self.select_query(db, uid, &q).await
     }
 
-    fn get_visibility_scope(&self) -> ModelVisibilityScope {
-        self.visibility_scope.clone().unwrap_or(ModelVisibilityScope::Everyone)
+    pub async fn get(&self, db: &DB, uid: Option<i64>, id: i64) -> Result<Option<Row>> {
+        let mut r = self.select(db, uid, Some(id)).await?;
+        Ok(r.pop())
+    }
+
+    pub async fn select_query(&self, db: &DB, uid: Option<i64>, q: &ModelQuery) -> Result<Vec<Row>> {
+        db.select(&self.query(uid, q)?).await
+    }
+
+    pub async fn select_page(&self, db: &DB, uid: Option<i64>, q: &ModelQuery) -> Result<Page> {
+        let total = db.count(&self.filtered(uid, q)?).await?;
+        let mut query = self.query(uid, q)?;
+        /* One more row tells whether there is a next page */
+        query.limit = q.limit.map(|n| n + 1);
+        let mut rows = db.select(&query).await?;
+        let next_cursor = match q.limit {
+            Some(n) if rows.len() > n => {
+                rows.truncate(n);
+                rows.last().map(|r| {
+                    let keys: Vec<(String, RowField)> = q.sort_keys().into_iter()
+                        .map(|k| {
+                            let v = r.fields.get(&k.field).cloned().unwrap_or(RowField::Null);
+                            (k.field, v)
+                        })
+                        .collect();
+                    encode_cursor(&keys)
+                })
+            },
+            _ => None,
+        };
+        Ok(Page {
+            rows,
+            total,
+            next_cursor,
+        })
+    }
+
+    pub fn get_field(&self, name: &str) -> Option<&FieldDef> {
+        self.fields.as_ref()?.iter().find(|f| f.name() == name)
+    }
+
+    fn check_query_field(&self, name: &str) -> Result<()> {
+        if name != "id" && self.get_field(name).is_none() {
+            bail!("Unknown field: {}", name);
+        }
+        Ok(())
+    }
+
+    fn parse_query_value(&self, name: &str, s: &str) -> Result<RowField> {
+        if name == "id" {
+            return s.parse()
+                .map(RowField::Integer)
+                .map_err(|_| anyhow!("Invalid value '{}' for field id", s));
+        }
+        match self.get_field(name) {
+            Some(f) => f.parse_value(s),
+            None => bail!("Unknown field: {}", name),
+        }
     }
 
-    fn condition(&self, uid: Option<i64>, id: Option<i64>) -> String {
-        let mut ret = match self.get_visibility_scope() {
-            ModelVisibilityScope::Everyone => "1".to_string(),
-            ModelVisibilityScope::Owner => {
-                match uid {
-                    None | Some(0) => "1".to_string(),
-                    Some(x) => format!("_oct_owner={}", x),
+    fn parse_filter(&self, key: &str, val: &str) -> Result<FieldFilter> {
+        let (field, op) = match key.rsplit_once("__") {
+            Some((f, op)) if ["eq", "ne", "lt", "gt", "in", "like", "isnull"].contains(&op) => (f, op),
+            _ => (key, "eq"),
+        };
+        self.check_query_field(field)?;
+        let op = match op {
+            "ne" => FilterOp::Ne(self.parse_query_value(field, val)?),
+            "lt" => FilterOp::Lt(self.parse_query_value(field, val)?),
+            "gt" => FilterOp::Gt(self.parse_query_value(field, val)?),
+            "in" => {
+                let mut vals = Vec::new();
+                for v in val.split(',') {
+                    vals.push(self.parse_query_value(field, v)?);
                 }
+                FilterOp::In(vals)
+            },
+            "like" => FilterOp::Like(val.to_string()),
+            "isnull" => match parse_bool(val) {
+                Some(x) => FilterOp::IsNull(x),
+                None => bail!("Invalid value '{}' for isnull", val),
             },
+            _ => FilterOp::Eq(self.parse_query_value(field, val)?),
         };
-        if let Some(id) = id {
-            ret += &format!(" AND id={}", id);
+        Ok(FieldFilter {
+            field: field.to_string(),
+            op,
+        })
+    }
+
+    /* Parse request parameters into a query. Besides the reserved keys (id, order_by, limit,
+     * offset, cursor), every key is a filter in the form of "field" or "field__op". The limit
+     * can't be over the maximum page size. */
+    pub fn parse_query(&self, params: &HashMap<String, String>) -> Result<ModelQuery> {
+        let mut q = ModelQuery::default();
+        let mut cursor = None;
+        let mut keys: Vec<&String> = params.keys().collect();
+        keys.sort();
+        for k in keys {
+            let v = &params[k];
+            match k.as_str() {
+                "id" => {
+                    q.id = Some(v.parse().map_err(|_| anyhow!("Invalid id: {}", v))?);
+                },
+                "order_by" => {
+                    for key in v.split(',') {
+                        let (field, desc) = match key.strip_prefix('-') {
+                            Some(x) => (x, true),
+                            None => (key, false),
+                        };
+                        self.check_query_field(field)?;
+                        q.order_by.push(SortKey {
+                            field: field.to_string(),
+                            desc,
+                        });
+                    }
+                },
+                "limit" => {
+                    q.limit = Some(v.parse().map_err(|_| anyhow!("Invalid limit: {}", v))?);
+                },
+                "offset" => {
+                    q.offset = v.parse().map_err(|_| anyhow!("Invalid offset: {}", v))?;
+                },
+                "cursor" => {
+                    cursor = Some(decode_cursor(v)?);
+                },
+                _ => q.filters.push(self.parse_filter(k, v)?),
+            }
         }
-        ret
+        let max_size = config().max_page_size;
+        if q.limit.is_some_and(|n| n > max_size) {
+            bail!("Limit can't be over {}", max_size);
+        }
+        if let Some(keys) = cursor {
+            /* The cursor must come from a page with the same order */
+            let fields: Vec<String> = q.sort_keys().into_iter().map(|k| k.field).collect();
+            if !keys.iter().map(|(k, _)| k).eq(fields.iter()) {
+                bail!("Cursor doesn't match order_by");
+            }
+            q.after = keys.into_iter().map(|(_, v)| v).collect();
+        }
+        Ok(q)
+    }
+
+    /* The records of q visible to uid, unsorted and not paged */
+    fn filtered(&self, uid: Option<i64>, q: &ModelQuery) -> Result<Query<'_>> {
+        let mut query = self.scoped(uid);
+        if let Some(id) = q.id {
+            query.eq("id", RowField::Integer(id))?;
+        }
+        for f in &q.filters {
+            query.filter(&f.field, &f.op)?;
+        }
+        Ok(query)
+    }
+
+    /* The select of q, among the records visible to uid */
+    fn query(&self, uid: Option<i64>, q: &ModelQuery) -> Result<Query<'_>> {
+        let mut query = self.filtered(uid, q)?;
+        for k in &q.order_by {
+            query.order_by(&k.field, k.desc)?;
+        }
+        if !q.after.is_empty() {
+            query.after(&q.sort_keys(), &q.after)?;
+        }
+        query.limit = q.limit;
+        query.offset = q.offset;
+        Ok(query)
+    }
+
+    fn get_visibility_scope(&self) -> ModelVisibilityScope {
+        self.visibility_scope.clone().unwrap_or(ModelVisibilityScope::Everyone)
+    }
+
+    /* A query on the records uid can see, all of them for anonymous and admin users */
+    fn scoped(&self, uid: Option<i64>) -> Query<'_> {
+        let mut q = Query::new(self);
+        if let (ModelVisibilityScope::Owner, Some(x)) = (self.get_visibility_scope(), uid) {
+            if x != 0 {
+                /* Can't fail, every table has _oct_owner */
+                q.eq("_oct_owner", RowField::Integer(x)).unwrap();
+            }
+        }
+        q
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn todo_model() -> ModelDef {
+        ModelDef {
+            name: "todo".to_string(),
+            description: None,
+            fields: Some(vec![
+                FieldDef::make_string("subject", "subject"),
+                FieldDef::make_integer("priority", "priority"),
+            ]),
+            visibility_scope: None,
+            renamed_from: None,
+        }
+    }
+
+    fn params(kvs: &[(&str, &str)]) -> HashMap<String, String> {
+        kvs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn query_test() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let db = DB::new(tf.path().to_str().unwrap()).unwrap();
+        let model = todo_model();
+        db.execute(&model.create_table_query(&model.name), &[]).await.unwrap();
+        for i in 0..5 {
+            let mut rec = Row::new();
+            rec.set("subject", RowField::String(format!("item {}", i)));
+            rec.set("priority", RowField::Integer(i % 3));
+            model.create(&db, &rec, None).await.unwrap();
+        }
+
+        assert!(model.parse_query(&params(&[("owner", "1")])).is_err());
+        assert!(model.parse_query(&params(&[("priority__gt", "x")])).is_err());
+        assert!(model.parse_query(&params(&[("order_by", "-nope")])).is_err());
+
+        let q = model.parse_query(&params(&[("priority__lt", "2"), ("order_by", "-priority,subject")])).unwrap();
+        let rows = model.select_query(&db, None, &q).await.unwrap();
+        let subjects: Vec<&str> = rows.iter().map(|r| r.get_str("subject").unwrap()).collect();
+        assert_eq!(subjects, vec!["item 1", "item 4", "item 0", "item 3"]);
+
+        let q = model.parse_query(&params(&[("subject__in", "item 0,item 2"), ("priority__ne", "0")])).unwrap();
+        let rows = model.select_query(&db, None, &q).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get_str("subject"), Some("item 2"));
+
+        let max = config().max_page_size;
+        assert!(model.parse_query(&params(&[("limit", &(max + 1).to_string())])).is_err());
+
+        let q = model.parse_query(&params(&[("limit", "2")])).unwrap();
+        let page = model.select_page(&db, None, &q).await.unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(page.rows.len(), 2);
+        let cursor = page.next_cursor.unwrap();
+        /* Removing item 0 before the cursor doesn't shift the next page */
+        model.delete(&db, &[1], None).await.unwrap();
+        let q = model.parse_query(&params(&[("limit", "2"), ("cursor", &cursor)])).unwrap();
+        let page = model.select_page(&db, None, &q).await.unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(page.rows[0].get_str("subject"), Some("item 2"));
+        assert!(model.parse_query(&params(&[("cursor", &cursor), ("order_by", "priority")])).is_err());
+        assert!(model.parse_query(&params(&[("cursor", "b2Zmc2V0OjI")])).is_err());
+
+        let mut subjects = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut p = params(&[("limit", "2"), ("order_by", "-priority")]);
+            if let Some(c) = cursor {
+                p.insert("cursor".to_string(), c);
+            }
+            let page = model.select_page(&db, None, &model.parse_query(&p).unwrap()).await.unwrap();
+            subjects.extend(page.rows.iter().map(|r| r.get_str("subject").unwrap().to_string()));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(subjects, vec!["item 2", "item 1", "item 4", "item 3"]);
+        let q = model.parse_query(&params(&[("limit", "2"), ("offset", "3")])).unwrap();
+        let page = model.select_page(&db, None, &q).await.unwrap();
+        assert_eq!(page.rows.len(), 1);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn validate_input_test() {
+        let mut model = todo_model();
+        if let Some(FieldDef::String(d)) = model.fields.as_mut().unwrap().get_mut(0) {
+            d.max_length = Some(5);
+        }
+
+        let rec = model.validate_input(&json!({"subject": "hi", "priority": "3"}), WriteMode::Create).unwrap();
+        assert_eq!(rec.get_int("priority"), Some(3));
+
+        let e = model.validate_input(&json!({"subject": 1, "priority": {}, "owner": 2}), WriteMode::Create)
+            .unwrap_err();
+        let e = e.downcast_ref::<ValidationError>().unwrap();
+        let fields: Vec<&str> = e.errors.iter().map(|x| x.field.as_str()).collect();
+        assert_eq!(fields, vec!["owner", "priority", "subject"]);
+
+        let e = model.validate_input(&json!({"subject": "too long"}), WriteMode::Create).unwrap_err();
+        let e = e.downcast_ref::<ValidationError>().unwrap();
+        assert_eq!(e.errors.len(), 2);
+        assert_eq!(e.errors[0].message, "longer than 5 characters");
+        assert_eq!(e.errors[1].message, "is required");
+
+        assert!(model.validate_input(&json!({"priority": 1}), WriteMode::Patch).is_ok());
+        assert!(model.validate_input(&json!({"priority": 1}), WriteMode::Replace).is_err());
+        assert!(model.validate_input(&json!({"priority": null}), WriteMode::Patch).is_err());
+        assert!(model.validate_input(&json!([1]), WriteMode::Patch).is_err());
+    }
+
+    #[tokio::test]
+    async fn auto_now_test() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let db = DB::new(tf.path().to_str().unwrap()).unwrap();
+        let model: ModelDef = serde_yaml::from_str("
+name: todo
+fields:
+  - name: subject
+    type: string
+  - name: created
+    type: datetime
+    auto_now_add: true
+  - name: updated
+    type: datetime
+    auto_now: true
+").unwrap();
+        db.execute(&model.create_table_query(&model.name), &[]).await.unwrap();
+        let time = |rec: &Row, field: &str| match rec.get(field) {
+            Some(RowField::DateTime(x)) => *x,
+            x => panic!("unexpected {:?}", x),
+        };
+
+        let rec = model.validate_input(&json!({"subject": "a", "created": 1}), WriteMode::Create).unwrap();
+        let id = model.create(&db, &rec, None).await.unwrap();
+        let rec = model.get(&db, None, id).await.unwrap().unwrap();
+        let created = time(&rec, "created");
+        assert!(created > 1);
+        assert!(time(&rec, "updated") >= created);
+
+        db.execute("UPDATE todo SET created=5, updated=5", &[]).await.unwrap();
+        let mut rec = model.validate_input(&json!({"subject": "b", "created": 1}), WriteMode::Replace).unwrap();
+        rec.set("id", RowField::Integer(id));
+        model.update(&db, &rec, None).await.unwrap();
+        let rec = model.get(&db, None, id).await.unwrap().unwrap();
+        assert_eq!(rec.get_str("subject"), Some("b"));
+        assert_eq!(time(&rec, "created"), 5);
+        assert!(time(&rec, "updated") >= created);
+
+        let mut rec = Row::new();
+        rec.set("id", RowField::Integer(id));
+        rec.set("subject=subject, created", RowField::Integer(0));
+        assert!(model.update(&db, &rec, None).await.is_err());
     }
 }
@@ -1,7 +1,9 @@
 use core::time::Duration;
+use std::fmt;
 use tokio::time::sleep;
 use serde_json::Value;
 use crate::types::*;
+use crate::store::{MetaStore, StoreFuture};
 
 pub async fn start() -> Result<()> {
     let r = std::process::Command::new("./orm/manage.py")
@@ -16,7 +18,7 @@ pub async fn start() -> Result<()> {
         .spawn()
         .expect("failed to start django server");
     for _ in 1..5 {
-        match orm_get("/user/?username=foo", false).await {
+        match orm_get("/user/", &[("username", "foo")], true).await {
             Ok(_) => { break; }
             _ => {
                 sleep(Duration::from_secs(1)).await;
@@ -27,15 +29,81 @@ pub async fn start() -> Result<()> {
     Ok(())
 }
 
-fn orm_url(path: &str) -> String {
-    format!("http://{}{}", config().orm_addr, path)
+/* An error response of the ORM server */
+#[derive(Debug)]
+pub struct OrmError {
+    pub method: reqwest::Method,
+    pub path: String,
+    pub status: u16,
+    pub body: String,
 }
 
-async fn orm_get(path: &str, multi: bool) -> Result<Value> {
-    let resp = reqwest::get(&orm_url(path))
-        .await?
-        .text()
-        .await?;
+impl fmt::Display for OrmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ORM request {} {} failed with status {}: {}", self.method, self.path, self.status, self.body)
+    }
+}
+
+impl std::error::Error for OrmError {}
+
+lazy_static! {
+    /* Shared so that connections to the ORM server are reused */
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config().orm_timeout))
+        .build()
+        .expect("failed to create ORM client");
+}
+
+/* Delay before the first retry, doubled for each one after it */
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+fn orm_url(path: &str, query: &[(&str, &str)]) -> Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(&format!("http://{}{}", config().orm_addr, path))?;
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    Ok(url)
+}
+
+/* Send a request and return the body of the response, which must be a success. Failed
+ * connections and server errors are retried, except for POST which may have taken effect. */
+async fn orm_request(method: reqwest::Method, path: &str, query: &[(&str, &str)],
+                     body: Option<&Value>) -> Result<String> {
+    let url = orm_url(path, query)?;
+    let retries = if method == reqwest::Method::POST { 0 } else { config().orm_retries };
+    let mut attempt = 0;
+    loop {
+        let mut req = CLIENT.request(method.clone(), url.clone());
+        if let Some(x) = body {
+            req = req.json(x);
+        }
+        let r = req.send().await;
+        let retry = match &r {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(e) => e.is_connect() || e.is_timeout(),
+        };
+        if retry && attempt < retries {
+            sleep(RETRY_DELAY * 2u32.pow(attempt)).await;
+            attempt += 1;
+            continue;
+        }
+        let resp = r?;
+        let status = resp.status();
+        let text = resp.text().await?;
+        if !status.is_success() {
+            bail!(OrmError {
+                method,
+                path: path.to_string(),
+                status: status.as_u16(),
+                body: text,
+            });
+        }
+        return Ok(text);
+    }
+}
+
+async fn orm_get(path: &str, query: &[(&str, &str)], multi: bool) -> Result<Value> {
+    let resp = orm_request(reqwest::Method::GET, path, query, None).await?;
     let v: Vec<Value> = serde_json::from_str(&resp)?;
     if multi {
         Ok(v.into())
@@ -50,104 +118,156 @@ async fn orm_get(path: &str, multi: bool) -> Result<Value> {
     }
 }
 
-async fn orm_post<T: Serialize>(path: &str, data: &T) -> Result<()> {
-    let client = reqwest::Client::new();
-    client.post(orm_url(path))
-    .json(data)
-    .send()
-    .await?;
+async fn orm_post(path: &str, data: &Value) -> Result<()> {
+    orm_request(reqwest::Method::POST, path, &[], Some(data)).await?;
     Ok(())
 }
 
-async fn orm_put<T: Serialize>(path: &str, data: &T) -> Result<()> {
-    let client = reqwest::Client::new();
-    client.put(orm_url(path))
-    .json(data)
-    .send()
-    .await?;
+async fn orm_put(path: &str, data: &Value) -> Result<()> {
+    orm_request(reqwest::Method::PUT, path, &[], Some(data)).await?;
     Ok(())
 }
 
 async fn orm_delete(path: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    client.delete(orm_url(path))
-    .send()
-    .await?;
+    orm_request(reqwest::Method::DELETE, path, &[], None).await?;
     Ok(())
 }
 
-impl OctUser {
-    pub async fn get(username: &str) -> Result<OctUser> {
-        let v = orm_get(&format!("/user/?username={}", username), false).await?;
-        let user: OctUser = serde_json::from_value(v)?;
-        Ok(user)
-    }
+/* The Django ORM server, the store used before SqliteStore */
+pub struct DjangoStore;
 
-    pub async fn create(&self) -> Result<()> {
-        orm_post("/user/", self).await?;
-        Ok(())
+fn run<T: Send + 'static>(f: impl std::future::Future<Output = Result<T>> + Send + 'static) -> StoreFuture<T> {
+    Box::pin(f)
+}
+
+impl MetaStore for DjangoStore {
+    fn get_user(&self, username: &str) -> StoreFuture<OctUser> {
+        let username = username.to_string();
+        run(async move {
+            let v = orm_get("/user/", &[("username", &username)], false).await?;
+            let user: OctUser = serde_json::from_value(v)?;
+            Ok(user)
+        })
     }
 
-    pub async fn apps(&self) -> Result<Vec<OctApp>> {
-        let v = orm_get(&format!("/app/?user__username={}", self.username), true).await?;
-        let r = serde_json::from_value(v)?;
-        Ok(r)
+    fn user_by_token(&self, token: &str) -> StoreFuture<OctUser> {
+        let token = token.to_string();
+        run(async move {
+            let v = orm_get("/user/", &[("token", &token)], false).await?;
+            let r = serde_json::from_value(v)?;
+            Ok(r)
+        })
     }
 
-    pub async fn by_token(token: &str) -> Result<OctUser> {
-        let v = orm_get(&format!("/user/?token={}", token), false).await?;
-        let r = serde_json::from_value(v)?;
-        Ok(r)
+    fn create_user(&self, user: &OctUser) -> StoreFuture<()> {
+        let data = serde_json::to_value(user);
+        run(async move { orm_post("/user/", &data?).await })
     }
-}
 
-impl OctApp {
-    pub async fn get_all() -> Result<Vec<OctApp>> {
-        let v = orm_get("/app/", true).await?;
-        let r = serde_json::from_value(v)?;
-        Ok(r)
+    fn apps(&self, username: Option<&str>) -> StoreFuture<Vec<OctApp>> {
+        let username = username.map(|x| x.to_string());
+        run(async move {
+            let query = match &username {
+                Some(u) => vec![("user__username", u.as_str())],
+                None => vec![],
+            };
+            let v = orm_get("/app/", &query, true).await?;
+            let r = serde_json::from_value(v)?;
+            Ok(r)
+        })
     }
 
-    pub async fn orm_create(&self) -> Result<()> {
-        orm_post("/app/", self).await?;
-        Ok(())
+    fn app_by_handle(&self, handle: &str) -> StoreFuture<OctApp> {
+        let handle = handle.to_string();
+        run(async move {
+            let v = orm_get("/app/", &[("handle", &handle)], false).await?;
+            let app = serde_json::from_value(v)?;
+            Ok(app)
+        })
     }
 
-    pub async fn update(&self) -> Result<()> {
-        orm_put(&format!("/app/{}/", self.id.unwrap()), self).await?;
-        Ok(())
+    fn app_by_name(&self, username: &str, name: &str) -> StoreFuture<OctApp> {
+        let (username, name) = (username.to_string(), name.to_string());
+        run(async move {
+            let v = orm_get("/app/", &[("name", &name), ("user__username", &username)], false).await?;
+            let app = serde_json::from_value(v)?;
+            Ok(app)
+        })
     }
 
-    pub async fn delete(id: i64) -> Result<()> {
-        orm_delete(&format!("/app/{}/", id)).await?;
-        Ok(())
+    fn create_app(&self, app: &OctApp) -> StoreFuture<()> {
+        let data = serde_json::to_value(app);
+        run(async move { orm_post("/app/", &data?).await })
     }
 
-    pub async fn by_handle(handle: &str) -> Result<OctApp> {
-        let v = orm_get(&format!("/app/?handle={}", handle), false).await?;
-        let app = serde_json::from_value(v)?;
-        Ok(app)
+    fn update_app(&self, app: &OctApp) -> StoreFuture<()> {
+        let path = format!("/app/{}/", app.id.unwrap());
+        let data = serde_json::to_value(app);
+        run(async move { orm_put(&path, &data?).await })
     }
 
-    pub async fn by_name(username: &str, name: &str) -> Result<OctApp> {
-        let v = orm_get(&format!("/app/?name={}&user__username={}", name, username), false).await?;
-        let app = serde_json::from_value(v)?;
-        Ok(app)
+    fn delete_app(&self, id: i64) -> StoreFuture<()> {
+        run(async move { orm_delete(&format!("/app/{}/", id)).await })
     }
 
-    pub async fn event(&self, msg: &str) -> Result<()> {
-        let ae = AppEvent {
-            app: self.id.unwrap(),
-            content: msg.to_string(),
-            datetime: None,
-        };
-        orm_post("/event/", &ae).await?;
-        Ok(())
+    fn add_event(&self, event: &AppEvent) -> StoreFuture<()> {
+        let data = serde_json::to_value(event);
+        run(async move { orm_post("/event/", &data?).await })
     }
 
-    pub async fn get_events(&self) -> Result<Vec<AppEvent>> {
-        let v = orm_get(&format!("/event/?ordering=-datetime&app__id={}", self.id.unwrap_or(-1)), true).await?;
-        let ret = serde_json::from_value(v)?;
-        Ok(ret)
+    fn events(&self, app: i64) -> StoreFuture<Vec<AppEvent>> {
+        run(async move {
+            let app = app.to_string();
+            let v = orm_get("/event/", &[("ordering", "-datetime"), ("app__id", &app)], true).await?;
+            let ret = serde_json::from_value(v)?;
+            Ok(ret)
+        })
     }
 }
+
+/* Answer the connections one by one with the responses, recording the request lines */
+#[cfg(test)]
+async fn fake_orm_server(responses: Vec<(u16, &'static str)>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for (status, body) in responses {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 65536];
+            let n = sock.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]).to_string();
+            requests.push(req.lines().next().unwrap_or_default().to_string());
+            let resp = format!("HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                               status, body.len(), body);
+            sock.write_all(resp.as_bytes()).await.unwrap();
+        }
+        requests
+    });
+    (addr, handle)
+}
+
+#[tokio::test]
+async fn orm_client_test() {
+    let user = r#"[{"id": 1, "username": "a&b=c", "display_name": "A", "email": "", "token": "t"}]"#;
+    let (addr, server) = fake_orm_server(vec![(503, "busy"), (200, user), (400, r#"{"name": ["required"]}"#)]).await;
+    crate::config::config_write().orm_addr = addr;
+
+    let u = DjangoStore.get_user("a&b=c").await.unwrap();
+    assert_eq!(u.username, "a&b=c");
+    let app = OctApp {
+        id: None,
+        ..OctApp::for_test("ABCDE")
+    };
+    let e = DjangoStore.create_app(&app).await.unwrap_err();
+    let e = e.downcast_ref::<OrmError>().unwrap();
+    assert_eq!((e.status, e.path.as_str()), (400, "/app/"));
+
+    let requests = server.await.unwrap();
+    assert_eq!(requests, vec![
+        "GET /user/?username=a%26b%3Dc HTTP/1.1",
+        "GET /user/?username=a%26b%3Dc HTTP/1.1",
+        "POST /app/ HTTP/1.1",
+    ]);
+}
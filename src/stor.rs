@@ -1,10 +1,29 @@
-use std::{fs, path::Path};
+use std::{fs, path::{Path, PathBuf}};
 use std::io::prelude::*;
 use std::process::Command;
 use serde_json;
 use crate::types::*;
 
-#[derive(Debug)]
+/* Resolve rel to an existing path below the directory base. Symlinks are followed, but neither
+ * they nor ".." may lead out of base. */
+pub fn resolve_within(base: &Path, rel: &str) -> Result<PathBuf> {
+    let root = base.canonicalize()?;
+    let mut p = root.clone();
+    for seg in rel.split('/') {
+        match seg {
+            "" | "." => continue,
+            ".." => bail!("Path escapes the directory: {}", rel),
+            x => p.push(x),
+        }
+    }
+    let p = p.canonicalize()?;
+    if !p.starts_with(&root) {
+        bail!("Path escapes the directory: {}", rel);
+    }
+    Ok(p)
+}
+
+#[derive(Debug, Clone)]
 pub struct Entry {
     name: String,
 }
@@ -26,6 +45,11 @@ impl Entry {
         format!("{}/{}", config().data_dir, self.name)
     }
 
+    /* Path of the existing file rel below this entry, see resolve_within */
+    pub fn resolve(&self, rel: &str) -> Result<PathBuf> {
+        resolve_within(Path::new(&self.fullpath()), rel)
+    }
+
     pub async fn size(&self) -> Result<u64> {
         let out = Command::new("du")
             .args(&["-sb", &self.fullpath()])
@@ -84,3 +108,21 @@ impl Entry {
         Ok(ret)
     }
 }
+
+#[test]
+fn resolve_within_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let base = dir.path().join("repo");
+    fs::create_dir_all(base.join("static")).unwrap();
+    fs::write(base.join("static/a.css"), "a").unwrap();
+    fs::write(outside.path().join("secret"), "s").unwrap();
+    std::os::unix::fs::symlink(outside.path(), base.join("static/out")).unwrap();
+    std::os::unix::fs::symlink(base.join("static/a.css"), base.join("link.css")).unwrap();
+
+    assert_eq!(resolve_within(&base, "static/a.css").unwrap(), base.join("static/a.css").canonicalize().unwrap());
+    assert!(resolve_within(&base, "link.css").is_ok());
+    assert!(resolve_within(&base, "static/../../repo/static/a.css").is_err());
+    assert!(resolve_within(&base, "static/out/secret").is_err());
+    assert!(resolve_within(&base, "static/missing").is_err());
+}
@@ -0,0 +1,491 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use chrono::{NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use futures::future::BoxFuture;
+use rusqlite::{params, Connection, OptionalExtension, Row as SqlRow};
+use crate::types::*;
+
+/*
+ * Persistence of users, apps and app events. The server keeps them in a SQLite file of its own,
+ * the Django ORM server it used before can still be used instead while moving over, see
+ * orm::DjangoStore. An empty store imports the database of the Django server if there is one.
+ */
+
+pub const STORE_FILE: &str = "meta.sqlite";
+/* Database of the Django server, in the same data directory */
+pub const DJANGO_DB_FILE: &str = "orm.sqlite";
+
+pub type StoreFuture<T> = BoxFuture<'static, Result<T>>;
+
+/* Lookups fail when nothing matches, like the queries of the Django server did */
+pub trait MetaStore: Send + Sync {
+    fn get_user(&self, username: &str) -> StoreFuture<OctUser>;
+    fn user_by_token(&self, token: &str) -> StoreFuture<OctUser>;
+    fn create_user(&self, user: &OctUser) -> StoreFuture<()>;
+    /* Apps of the user, or of everyone when None */
+    fn apps(&self, username: Option<&str>) -> StoreFuture<Vec<OctApp>>;
+    fn app_by_handle(&self, handle: &str) -> StoreFuture<OctApp>;
+    fn app_by_name(&self, username: &str, name: &str) -> StoreFuture<OctApp>;
+    fn create_app(&self, app: &OctApp) -> StoreFuture<()>;
+    fn update_app(&self, app: &OctApp) -> StoreFuture<()>;
+    fn delete_app(&self, id: i64) -> StoreFuture<()>;
+    fn add_event(&self, event: &AppEvent) -> StoreFuture<()>;
+    /* Events of the app, newest first */
+    fn events(&self, app: i64) -> StoreFuture<Vec<AppEvent>>;
+}
+
+lazy_static! {
+    static ref STORE: RwLock<Option<Arc<dyn MetaStore>>> = RwLock::new(None);
+}
+
+pub fn set_store(store: Arc<dyn MetaStore>) {
+    *STORE.write().unwrap() = Some(store);
+}
+
+fn store() -> Arc<dyn MetaStore> {
+    STORE.read().unwrap().clone().expect("metadata store is not set up")
+}
+
+impl OctUser {
+    pub async fn get(username: &str) -> Result<OctUser> {
+        store().get_user(username).await
+    }
+
+    pub async fn create(&self) -> Result<()> {
+        store().create_user(self).await
+    }
+
+    pub async fn apps(&self) -> Result<Vec<OctApp>> {
+        store().apps(Some(&self.username)).await
+    }
+
+    pub async fn by_token(token: &str) -> Result<OctUser> {
+        store().user_by_token(token).await
+    }
+}
+
+impl OctApp {
+    pub async fn get_all() -> Result<Vec<OctApp>> {
+        store().apps(None).await
+    }
+
+    pub async fn orm_create(&self) -> Result<()> {
+        store().create_app(self).await
+    }
+
+    pub async fn update(&self) -> Result<()> {
+        store().update_app(self).await
+    }
+
+    /* Forget the app, its connections to the app database are closed too */
+    pub async fn delete(&self) -> Result<()> {
+        store().delete_app(self.id.unwrap_or(-1)).await?;
+        crate::db::DB::close(&self.db_file().fullpath());
+        Ok(())
+    }
+
+    pub async fn by_handle(handle: &str) -> Result<OctApp> {
+        store().app_by_handle(handle).await
+    }
+
+    pub async fn by_name(username: &str, name: &str) -> Result<OctApp> {
+        store().app_by_name(username, name).await
+    }
+
+    pub async fn event(&self, msg: &str) -> Result<()> {
+        let ae = AppEvent {
+            app: self.id.unwrap(),
+            content: msg.to_string(),
+            datetime: None,
+        };
+        store().add_event(&ae).await
+    }
+
+    pub async fn get_events(&self) -> Result<Vec<AppEvent>> {
+        store().events(self.id.unwrap_or(-1)).await
+    }
+}
+
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE oct_user (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    email TEXT NOT NULL,
+    token TEXT NOT NULL,
+    display_name TEXT NOT NULL
+);
+CREATE INDEX oct_user_token ON oct_user (token);
+CREATE TABLE oct_app (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user INTEGER NOT NULL REFERENCES oct_user (id),
+    name TEXT NOT NULL,
+    handle TEXT NOT NULL UNIQUE,
+    admin_token TEXT NOT NULL,
+    git_repo TEXT,
+    git_ref TEXT,
+    webhook_secret TEXT,
+    poll_interval INTEGER,
+    app_path TEXT,
+    UNIQUE (user, name)
+);
+CREATE TABLE oct_app_event (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    app INTEGER NOT NULL REFERENCES oct_app (id),
+    datetime TEXT NOT NULL,
+    content TEXT NOT NULL
+);
+CREATE INDEX oct_app_event_app ON oct_app_event (app, datetime);
+";
+
+const USER_COLUMNS: &str = "id, username, display_name, email, token";
+const APP_COLUMNS: &str = "a.id, a.user, a.name, a.handle, a.git_repo, a.git_ref, a.admin_token, \
+                           a.webhook_secret, a.poll_interval, a.app_path";
+
+fn user_from_row(r: &SqlRow) -> rusqlite::Result<OctUser> {
+    Ok(OctUser {
+        id: r.get(0)?,
+        username: r.get(1)?,
+        display_name: r.get(2)?,
+        email: r.get(3)?,
+        token: r.get(4)?,
+    })
+}
+
+fn app_from_row(r: &SqlRow) -> rusqlite::Result<OctApp> {
+    Ok(OctApp {
+        id: r.get(0)?,
+        user: r.get(1)?,
+        name: r.get(2)?,
+        handle: r.get(3)?,
+        git_repo: r.get(4)?,
+        git_ref: r.get(5)?,
+        admin_token: r.get(6)?,
+        webhook_secret: r.get(7)?,
+        poll_interval: r.get(8)?,
+        app_path: r.get(9)?,
+    })
+}
+
+/* Times are kept as RFC 3339 text in UTC, which sorts in time order */
+fn format_time(t: &DateTime) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn parse_time(s: &str) -> Option<DateTime> {
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    /* As Django writes them to SQLite */
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").ok()
+        .map(|t| Utc.from_utc_datetime(&t))
+}
+
+fn found<T>(r: rusqlite::Result<Option<T>>) -> Result<T> {
+    match r? {
+        Some(x) => Ok(x),
+        None => bail!("Object not found"),
+    }
+}
+
+/* Queries are run one at a time, on the blocking threads of tokio */
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<SqliteStore> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        if version == 0 {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(SCHEMA)?;
+            tx.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+            tx.commit()?;
+        } else if version > SCHEMA_VERSION {
+            bail!("Metadata store {} is from a newer version", path);
+        }
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /* Open the store in the data directory. An empty one gets the data of the Django server. */
+    pub fn open_data_dir(data_dir: &Path) -> Result<SqliteStore> {
+        let store = SqliteStore::open(&data_dir.join(STORE_FILE).to_string_lossy())?;
+        let django = data_dir.join(DJANGO_DB_FILE);
+        if django.exists() && store.is_empty()? {
+            let (users, apps, events) = store.import_django(&django)?;
+            println!("Imported {} users, {} apps and {} events from {}",
+                     users, apps, events, django.display());
+        }
+        Ok(store)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let n: i64 = conn.query_row("SELECT (SELECT COUNT(*) FROM oct_user) + (SELECT COUNT(*) FROM oct_app)",
+                                    [], |r| r.get(0))?;
+        Ok(n == 0)
+    }
+
+    /* Copy users, apps and events from the SQLite database of the Django server, keeping their
+     * ids. Only into an empty store. Returns the number of each copied. */
+    pub fn import_django(&self, path: &Path) -> Result<(usize, usize, usize)> {
+        if !self.is_empty()? {
+            bail!("Metadata store is not empty, not importing {}", path.display());
+        }
+        let src = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        /* Databases of older Django migrations lack the newer app columns */
+        let mut app_columns = Vec::new();
+        {
+            let mut stmt = src.prepare("PRAGMA table_info(rest_app)")?;
+            let rows = stmt.query_map([], |r| r.get::<_, String>(1))?;
+            for r in rows {
+                app_columns.push(r?);
+            }
+        }
+        let optional = |c: &str| if app_columns.iter().any(|x| x == c) { c.to_string() } else { format!("NULL AS {}", c) };
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut users = 0;
+        {
+            let mut stmt = src.prepare("SELECT id, username, display_name, COALESCE(email, ''), token FROM rest_user")?;
+            let rows = stmt.query_map([], user_from_row)?;
+            for u in rows {
+                let u = u?;
+                tx.execute(&format!("INSERT INTO oct_user ({}) VALUES (?, ?, ?, ?, ?)", USER_COLUMNS),
+                           params![u.id, u.username, u.display_name, u.email, u.token])?;
+                users += 1;
+            }
+        }
+        let mut apps = 0;
+        {
+            let sql = format!("SELECT id, user_id, name, handle, git_repo, git_ref, admin_token, {}, {}, {} FROM rest_app",
+                              optional("webhook_secret"), optional("poll_interval"), optional("app_path"));
+            let mut stmt = src.prepare(&sql)?;
+            let rows = stmt.query_map([], app_from_row)?;
+            for a in rows {
+                insert_app(&tx, &a?, true)?;
+                apps += 1;
+            }
+        }
+        let mut events = 0;
+        {
+            let mut stmt = src.prepare("SELECT id, app_id, datetime, content FROM rest_appevent")?;
+            let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?,
+                                                 r.get::<_, String>(2)?, r.get::<_, String>(3)?)))?;
+            for e in rows {
+                let (id, app, datetime, content) = e?;
+                let datetime = match parse_time(&datetime) {
+                    Some(x) => format_time(&x),
+                    None => bail!("Invalid time of event {}: {}", id, datetime),
+                };
+                tx.execute("INSERT INTO oct_app_event (id, app, datetime, content) VALUES (?, ?, ?, ?)",
+                           params![id, app, datetime, content])?;
+                events += 1;
+            }
+        }
+        tx.commit()?;
+        Ok((users, apps, events))
+    }
+
+    fn run<T, F>(&self, f: F) -> StoreFuture<T>
+        where T: Send + 'static, F: FnOnce(&mut Connection) -> Result<T> + Send + 'static {
+        let conn = self.conn.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?
+        })
+    }
+}
+
+fn query_user(conn: &Connection, cond: &str, value: &str) -> Result<OctUser> {
+    let sql = format!("SELECT {} FROM oct_user WHERE {} = ?", USER_COLUMNS, cond);
+    found(conn.query_row(&sql, [value], user_from_row).optional())
+}
+
+fn query_apps(conn: &Connection, cond: &str, values: &[&str]) -> Result<Vec<OctApp>> {
+    let sql = format!("SELECT {} FROM oct_app a JOIN oct_user u ON u.id = a.user WHERE {} ORDER BY a.id",
+                      APP_COLUMNS, cond);
+    let mut stmt = conn.prepare_cached(&sql)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(values), app_from_row)?;
+    let mut ret = Vec::new();
+    for r in rows {
+        ret.push(r?);
+    }
+    Ok(ret)
+}
+
+fn query_app(conn: &Connection, cond: &str, values: &[&str]) -> Result<OctApp> {
+    let mut apps = query_apps(conn, cond, values)?;
+    match apps.len() {
+        1 => Ok(apps.remove(0)),
+        0 => bail!("Object not found"),
+        _ => bail!("Found more than 1 objects"),
+    }
+}
+
+/* With the id of the app, or a new one */
+fn insert_app(conn: &Connection, app: &OctApp, keep_id: bool) -> Result<()> {
+    let id = if keep_id { app.id } else { None };
+    conn.execute("INSERT INTO oct_app (id, user, name, handle, git_repo, git_ref, admin_token, \
+                  webhook_secret, poll_interval, app_path) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                 params![id, app.user, app.name, app.handle, app.git_repo, app.git_ref, app.admin_token,
+                         app.webhook_secret, app.poll_interval, app.app_path])?;
+    Ok(())
+}
+
+impl MetaStore for SqliteStore {
+    fn get_user(&self, username: &str) -> StoreFuture<OctUser> {
+        let username = username.to_string();
+        self.run(move |conn| query_user(conn, "username", &username))
+    }
+
+    fn user_by_token(&self, token: &str) -> StoreFuture<OctUser> {
+        let token = token.to_string();
+        self.run(move |conn| query_user(conn, "token", &token))
+    }
+
+    fn create_user(&self, user: &OctUser) -> StoreFuture<()> {
+        let user = user.clone();
+        self.run(move |conn| {
+            conn.execute("INSERT INTO oct_user (username, display_name, email, token) VALUES (?, ?, ?, ?)",
+                         params![user.username, user.display_name, user.email, user.token])?;
+            Ok(())
+        })
+    }
+
+    fn apps(&self, username: Option<&str>) -> StoreFuture<Vec<OctApp>> {
+        let username = username.map(|x| x.to_string());
+        self.run(move |conn| match username {
+            Some(u) => query_apps(conn, "u.username = ?", &[&u]),
+            None => query_apps(conn, "1", &[]),
+        })
+    }
+
+    fn app_by_handle(&self, handle: &str) -> StoreFuture<OctApp> {
+        let handle = handle.to_string();
+        self.run(move |conn| query_app(conn, "a.handle = ?", &[&handle]))
+    }
+
+    fn app_by_name(&self, username: &str, name: &str) -> StoreFuture<OctApp> {
+        let (username, name) = (username.to_string(), name.to_string());
+        self.run(move |conn| query_app(conn, "u.username = ? AND a.name = ?", &[&username, &name]))
+    }
+
+    fn create_app(&self, app: &OctApp) -> StoreFuture<()> {
+        let app = app.clone();
+        self.run(move |conn| insert_app(conn, &app, false))
+    }
+
+    fn update_app(&self, app: &OctApp) -> StoreFuture<()> {
+        let app = app.clone();
+        self.run(move |conn| {
+            let r = conn.execute("UPDATE oct_app SET name = ?, handle = ?, git_repo = ?, git_ref = ?, admin_token = ?, \
+                                  webhook_secret = ?, poll_interval = ?, app_path = ? WHERE id = ?",
+                                 params![app.name, app.handle, app.git_repo, app.git_ref, app.admin_token,
+                                         app.webhook_secret, app.poll_interval, app.app_path, app.id])?;
+            if r == 0 {
+                bail!("Object not found");
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_app(&self, id: i64) -> StoreFuture<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM oct_app_event WHERE app = ?", [id])?;
+            tx.execute("DELETE FROM oct_app WHERE id = ?", [id])?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn add_event(&self, event: &AppEvent) -> StoreFuture<()> {
+        let time = format_time(&event.datetime.unwrap_or_else(Utc::now));
+        let (app, content) = (event.app, event.content.clone());
+        self.run(move |conn| {
+            conn.execute("INSERT INTO oct_app_event (app, datetime, content) VALUES (?, ?, ?)",
+                         params![app, time, content])?;
+            Ok(())
+        })
+    }
+
+    fn events(&self, app: i64) -> StoreFuture<Vec<AppEvent>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached("SELECT app, datetime, content FROM oct_app_event WHERE app = ? \
+                                                ORDER BY datetime DESC, id DESC")?;
+            let rows = stmt.query_map([app], |r| Ok(AppEvent {
+                app: r.get(0)?,
+                datetime: parse_time(&r.get::<_, String>(1)?),
+                content: r.get(2)?,
+            }))?;
+            let mut ret = Vec::new();
+            for r in rows {
+                ret.push(r?);
+            }
+            Ok(ret)
+        })
+    }
+}
+
+#[tokio::test]
+async fn sqlite_store_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let django = dir.path().join(DJANGO_DB_FILE);
+    let src = Connection::open(&django).unwrap();
+    src.execute_batch("
+CREATE TABLE rest_user (id integer PRIMARY KEY, username varchar(100), email varchar(100) NULL,
+                        token varchar(100), display_name varchar(100));
+CREATE TABLE rest_app (id integer PRIMARY KEY, user_id integer, name varchar(50), handle varchar(100),
+                       admin_token varchar(100), git_repo text NULL, git_ref text NULL, yml text NULL);
+CREATE TABLE rest_appevent (id integer PRIMARY KEY, app_id integer, datetime datetime, content text);
+INSERT INTO rest_user VALUES (3, 'alice', NULL, 'tok', 'Alice');
+INSERT INTO rest_app VALUES (7, 3, 'todo', 'ABCDE', 'admin', 'https://example.com/todo.git', NULL, NULL);
+INSERT INTO rest_appevent VALUES (1, 7, '2021-12-02 17:42:01.123456', 'created');
+INSERT INTO rest_appevent VALUES (2, 7, '2021-12-02 17:43:00', 'synced');
+").unwrap();
+    drop(src);
+
+    let store = SqliteStore::open_data_dir(dir.path()).unwrap();
+    let user = store.user_by_token("tok").await.unwrap();
+    assert_eq!((user.id, user.username.as_str(), user.email.as_str()), (Some(3), "alice", ""));
+    let app = store.app_by_name("alice", "todo").await.unwrap();
+    assert_eq!((app.id, app.user, app.handle.as_str()), (Some(7), Some(3), "ABCDE"));
+    assert_eq!(app.webhook_secret, None);
+    let events = store.events(7).await.unwrap();
+    let contents: Vec<&str> = events.iter().map(|x| x.content.as_str()).collect();
+    assert_eq!(contents, vec!["synced", "created"]);
+    assert!(store.import_django(&django).is_err());
+
+    let mut app2 = app.clone();
+    app2.handle = "FGHIJ".to_string();
+    app2.name = "notes".to_string();
+    store.create_app(&app2).await.unwrap();
+    assert!(store.create_app(&app2).await.is_err());
+    let mut app2 = store.app_by_handle("FGHIJ").await.unwrap();
+    assert!(app2.id.unwrap() > 7);
+    app2.poll_interval = Some(300);
+    store.update_app(&app2).await.unwrap();
+    assert_eq!(store.app_by_handle("FGHIJ").await.unwrap().poll_interval, Some(300));
+    assert_eq!(store.apps(Some("alice")).await.unwrap().len(), 2);
+    assert!(store.apps(Some("bob")).await.unwrap().is_empty());
+
+    store.add_event(&AppEvent {
+        app: 7,
+        datetime: None,
+        content: "deleted".to_string(),
+    }).await.unwrap();
+    assert_eq!(store.events(7).await.unwrap()[0].content, "deleted");
+    store.delete_app(7).await.unwrap();
+    assert!(store.app_by_handle("ABCDE").await.is_err());
+    assert!(store.events(7).await.unwrap().is_empty());
+
+    let store = SqliteStore::open_data_dir(dir.path()).unwrap();
+    assert_eq!(store.apps(None).await.unwrap().len(), 1);
+    assert!(store.get_user("bob").await.is_err());
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use crate::types::*;
+use crate::apps::*;
+use crate::stor::Entry;
+use crate::migrate::{self, MigrationPlan, DbSchema};
+use crate::deploy::{Deployment, PendingDeployment};
+use crate::fetch::{fetch_repo, FetchLimits};
+use crate::worker::JobProgress;
+
+/* Prefix of the directory the new version of the repo is cloned into before it's activated, one
+ * per job, as a fetch that timed out may still be writing to the directory of its job */
+const SYNC_WIP: &str = "sync-wip";
+
+/* Fetch the app repo into the directory wip of the app, returns it and the commit fetched */
+async fn clone_repo(app: &OctApp, wip: &str, progress: &JobProgress) -> Result<(Entry, String)> {
+    let next = app.dir().child(wip);
+    let _ = fs::remove_dir_all(next.fullpath());
+    let repo = if let Some(x) = &app.git_repo {
+        x
+    } else {
+        bail!("Git repo is not set for app");
+    };
+    progress.step(&format!("Cloning {}...", repo)).await?;
+    let limits = FetchLimits::from_config();
+    let creds = app.credentials()?;
+    match fetch_repo(repo, app.git_ref.as_deref(), &next, creds, limits, progress.cancel_signal()).await {
+        Ok(commit) => Ok((next, commit)),
+        Err(e) => {
+            progress.step(&format!("Failed to clone repo: {}", e)).await?;
+            Err(e)
+        },
+    }
+}
+
+pub async fn sync_app(app: &OctApp, triggered_by: &str, progress: &JobProgress) -> Result<Deployment> {
+    let wip = match progress.job_id() {
+        Some(id) => format!("{}-{}", SYNC_WIP, id),
+        None => format!("{}-{}", SYNC_WIP, gen_random_string(8)),
+    };
+    let (next, commit) = clone_repo(app, &wip, progress).await?;
+    let r = sync_repo(app, &next, commit, triggered_by, progress).await;
+    if r.is_err() {
+        let _ = fs::remove_dir_all(next.fullpath());
+    }
+    r
+}
+
+async fn sync_repo(app: &OctApp, next: &Entry, commit: String, triggered_by: &str,
+                   progress: &JobProgress) -> Result<Deployment> {
+    let repod = app.repo();
+    progress.step("Checking schema...").await?;
+    let newdef = get_repo_app_def(next, app.app_path.as_deref()).await?;
+    let had_db = app.has_db();
+    let db = app.db()?;
+    let plan = migrate::plan_migration(&db, &newdef).await?;
+    progress.step("Sync database...").await?;
+    for step in &plan.steps {
+        progress.step(&step.description).await?;
+    }
+    /* No more cancellation from here, the job runs to the end once the database is changed */
+    let mut dep = PendingDeployment::begin(app, triggered_by)?;
+    dep.record.commit = Some(commit);
+    dep.record.app_yml = serde_yaml::to_string(&newdef)?;
+    if had_db && !plan.steps.is_empty() {
+        dep.snapshot_db(&db).await?;
+    }
+    plan.apply(&db).await?;
+    dep.record.migration = plan.steps;
+    dep.save().await?;
+    let _ = app.event("Activating...").await;
+    let _ = fs::remove_dir_all(repod.fullpath());
+    fs::rename(next.fullpath(), repod.fullpath())?;
+    let _ = app.event(&format!("Done, app is up as deployment {}!", dep.record.id)).await;
+    dep.finish(app).await
+}
+
+#[derive(Debug, PartialEq, Serialize, Default)]
+pub struct EndpointChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AccessChange {
+    pub endpoint: String,
+    pub before: Option<Vec<ApiAccessRuleDef>>,
+    pub after: Option<Vec<ApiAccessRuleDef>>,
+}
+
+/* What a sync would change, without changing it */
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SyncPreview {
+    pub migration: Option<MigrationPlan>,
+    /* Reasons the sync would fail, such as destructive steps that app.yml doesn't allow */
+    pub errors: Vec<String>,
+    pub endpoints: EndpointChanges,
+    pub access: Vec<AccessChange>,
+}
+
+fn endpoint_label(ep: &ApiEndpoint) -> String {
+    format!("{} {}", ep.name(), ep.get_path())
+}
+
+/* Compare the endpoints and their effective access rules of two versions of the app */
+pub fn diff_api(old: Option<&AppDef>, new: &AppDef) -> (EndpointChanges, Vec<AccessChange>) {
+    let mut changes = EndpointChanges::default();
+    let mut access = Vec::new();
+    let find = |def: &AppDef, name: &str| def.api.endpoints.iter().find(|x| x.name() == name).cloned();
+    for ep in &new.api.endpoints {
+        let after = new.api.effective_access(ep.access());
+        match old.and_then(|x| find(x, ep.name()).map(|o| (x, o))) {
+            None => {
+                changes.added.push(endpoint_label(ep));
+                access.push(AccessChange {
+                    endpoint: ep.name().to_string(),
+                    before: None,
+                    after: Some(after),
+                });
+            },
+            Some((olddef, o)) => {
+                if &o != ep {
+                    changes.changed.push(endpoint_label(ep));
+                }
+                let before = olddef.api.effective_access(o.access());
+                if before != after {
+                    access.push(AccessChange {
+                        endpoint: ep.name().to_string(),
+                        before: Some(before),
+                        after: Some(after),
+                    });
+                }
+            },
+        }
+    }
+    if let Some(olddef) = old {
+        for ep in &olddef.api.endpoints {
+            if find(new, ep.name()).is_none() {
+                changes.removed.push(endpoint_label(ep));
+                access.push(AccessChange {
+                    endpoint: ep.name().to_string(),
+                    before: Some(olddef.api.effective_access(ep.access())),
+                    after: None,
+                });
+            }
+        }
+    }
+    (changes, access)
+}
+
+/* Clone and check the new version of the app, and report what syncing it would change. The
+ * database is only read, and not even created if the app has none yet. */
+pub async fn preview_sync(app: &OctApp) -> Result<SyncPreview> {
+    /* Cloned apart from the sync jobs so a preview never races with one */
+    let wip = format!("preview-{}", gen_random_string(8));
+    let (next, _) = clone_repo(app, &wip, &JobProgress::detached(app)).await?;
+    let r = preview_repo(app, &next).await;
+    fs::remove_dir_all(next.fullpath())?;
+    r
+}
+
+async fn preview_repo(app: &OctApp, next: &Entry) -> Result<SyncPreview> {
+    let newdef = get_repo_app_def(next, app.app_path.as_deref()).await?;
+    let schema = if app.has_db() {
+        migrate::snapshot(&app.db()?).await?
+    } else {
+        DbSchema::new()
+    };
+    let mut errors = Vec::new();
+    let migration = match migrate::plan(&schema, &newdef) {
+        Ok(plan) => {
+            if let Err(e) = plan.check(&newdef) {
+                errors.push(e.to_string());
+            }
+            Some(plan)
+        },
+        Err(e) => {
+            errors.push(e.to_string());
+            None
+        },
+    };
+    let olddef = app.get_def().await;
+    let (endpoints, access) = diff_api(olddef.as_ref(), &newdef);
+    Ok(SyncPreview {
+        migration,
+        errors,
+        endpoints,
+        access,
+    })
+}
+
+#[test]
+fn diff_api_test() {
+    let old = "
+meta:
+  schema: v0.0.1
+name: test
+models: []
+api:
+  default_access: allow
+  endpoints:
+    - name: version
+      path: /version
+      type: string
+      response: '1'
+    - name: secret
+      path: /secret
+      type: string
+      response: s
+";
+    let new = old.replace("response: '1'", "response: '2'")
+        .replace("    - name: secret\n      path: /secret\n      type: string\n      response: s\n", "")
+        + "    - name: hello\n      path: /hello\n      type: string\n      response: hi\n      access:\n        - action: deny\n";
+    let old = AppDef::from_yaml(old).unwrap();
+    let new = AppDef::from_yaml(&new).unwrap();
+    let (changes, access) = diff_api(Some(&old), &new);
+    assert_eq!(changes.added, vec!["hello /hello"]);
+    assert_eq!(changes.removed, vec!["secret /secret"]);
+    assert_eq!(changes.changed, vec!["version /version"]);
+    let names: Vec<&str> = access.iter().map(|x| x.endpoint.as_str()).collect();
+    assert_eq!(names, vec!["hello", "secret"]);
+    assert!(!access[0].after.as_ref().unwrap()[0].action.allowed());
+
+    let (changes, access) = diff_api(None, &new);
+    assert_eq!(changes.added.len(), 2);
+    assert_eq!(access.len(), 2);
+}
@@ -1,6 +1,6 @@
 use std::fmt;
 use std::sync::{Mutex, MutexGuard};
-pub use std::collections::{HashMap, VecDeque};
+pub use std::collections::{HashMap, HashSet, VecDeque};
 pub use std::sync::Arc;
 use chrono::{Utc, Timelike, Duration};
 use hyper;
@@ -9,6 +9,8 @@ pub use anyhow::{anyhow, bail};
 pub use serde::{Serialize, Deserialize};
 use serde_json::{Value, Number};
 pub use crate::config::config;
+use crate::worker::SyncJobs;
+use crate::apps::AppRegistry;
 
 pub type Result<T> = anyhow::Result<T>;
 pub type Error = anyhow::Error;
@@ -16,6 +18,13 @@ pub type Request = hyper::Request<hyper::Body>;
 pub type Response = hyper::Response<hyper::Body>;
 
 pub type DateTime = chrono::DateTime<Utc>;
+pub type PathParams = HashMap<String, String>;
+
+/* Name of the main file of the app definition */
+pub const APP_YML: &str = "app.yml";
+
+/* Path parameter with the requested file of a static endpoint in prefix mode */
+pub const STATIC_FILE_PARAM: &str = "file";
 
 #[derive(Debug)]
 pub enum TimeSeriesUnit {
@@ -217,21 +226,35 @@ impl Stats {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Context {
     _stats: Mutex<Stats>,
+    #[serde(skip)]
+    _jobs: Mutex<SyncJobs>,
+    #[serde(skip)]
+    _apps: Mutex<AppRegistry>,
 }
 
 impl Context {
     pub fn new(stats: Stats) -> Context {
         Context {
             _stats: Mutex::new(stats),
+            _jobs: Mutex::new(SyncJobs::default()),
+            _apps: Mutex::new(AppRegistry::default()),
         }
     }
 
-    pub fn stats(&self) -> MutexGuard<Stats> {
+    pub fn stats(&self) -> MutexGuard<'_, Stats> {
         self._stats.lock().unwrap()
     }
+
+    pub fn jobs(&self) -> MutexGuard<'_, SyncJobs> {
+        self._jobs.lock().unwrap()
+    }
+
+    pub fn apps(&self) -> MutexGuard<'_, AppRegistry> {
+        self._apps.lock().unwrap()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OctApp {
     pub id: Option<i64>,
     pub user: Option<i64>,
@@ -240,6 +263,34 @@ pub struct OctApp {
     pub git_repo: Option<String>,
     pub git_ref: Option<String>,
     pub admin_token: String,
+    /* Secret that push webhooks are signed with */
+    #[serde(default)]
+    pub webhook_secret: Option<String>,
+    /* Check the remote for new commits every so many seconds, for hosts without webhooks */
+    #[serde(default)]
+    pub poll_interval: Option<u32>,
+    /* Directory of app.yml in the repo, the repo root if not set */
+    #[serde(default)]
+    pub app_path: Option<String>,
+}
+
+impl OctApp {
+    /* An app of user 1 with nothing set up, as tests start from */
+    #[cfg(test)]
+    pub fn for_test(handle: &str) -> OctApp {
+        OctApp {
+            id: Some(1),
+            user: Some(1),
+            name: "test".to_string(),
+            handle: handle.to_string(),
+            git_repo: None,
+            git_ref: None,
+            admin_token: String::new(),
+            webhook_secret: None,
+            poll_interval: None,
+            app_path: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -265,7 +316,7 @@ impl fmt::Display for LoginType {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OctUser {
     pub id: Option<i64>,
     pub username: String,
@@ -311,23 +362,70 @@ fn validate_text(text: &str, max_length: usize) -> Result<()> {
     Ok(())
 }
 
+/* Name of the parameter captured by a path template segment, "{name}" or "{*name}" */
+fn segment_param(seg: &str) -> Option<&str> {
+    let p = seg.strip_prefix('{')?.strip_suffix('}')?;
+    Some(p.strip_prefix('*').unwrap_or(p))
+}
+
 fn validate_api_path(path: &str) -> Result<()> {
     if path.len() < 1 {
         bail!("empty api path");
     }
-    for c in path.chars() {
-        match c {
-            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '/' | '-' | '.' => (),
-            c => { bail!("invalid char: '{}'", c); }
+    let segs: Vec<&str> = path.split('/').collect();
+    for (i, seg) in segs.iter().enumerate() {
+        if let Some(p) = segment_param(seg) {
+            validate_id(p)?;
+            if seg.starts_with("{*") && i != segs.len() - 1 {
+                bail!("catch-all parameter must be the last segment: {}", path);
+            }
+            continue;
+        }
+        for c in seg.chars() {
+            match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' => (),
+                c => { bail!("invalid char: '{}'", c); }
+            }
         }
     }
     Ok(())
 }
 
-fn validate_file_path(path: &str) -> Result<()> {
+/* Whether some request path could match both templates */
+fn paths_overlap(a: &str, b: &str) -> bool {
+    let sa: Vec<&str> = a.split('/').collect();
+    let sb: Vec<&str> = b.split('/').collect();
+    for i in 0..sa.len().max(sb.len()) {
+        let (x, y) = match (sa.get(i), sb.get(i)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return false,
+        };
+        if x.starts_with("{*") || y.starts_with("{*") {
+            return true;
+        }
+        if x != y && segment_param(x).is_none() && segment_param(y).is_none() {
+            return false;
+        }
+    }
+    true
+}
+
+/* Include patterns are file paths that may have glob wildcards */
+fn validate_include(pattern: &str) -> Result<()> {
+    let path: String = pattern.chars()
+        .filter(|c| !matches!(c, '*' | '?' | '[' | ']'))
+        .collect();
+    validate_file_path(&path)
+}
+
+/* A path relative to the app repo, which must not climb out of it */
+pub fn validate_file_path(path: &str) -> Result<()> {
     if path.len() < 1 {
         bail!("empty api path");
     }
+    if path.starts_with('/') || path.split('/').any(|x| x == "..") {
+        bail!("file path must stay inside the repo: {}", path);
+    }
     for c in path.chars() {
         match c {
             'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '/' | '-' | '.' => (),
@@ -337,16 +435,22 @@ fn validate_file_path(path: &str) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct SimpleDesc {
     pub name: String,
     pub description: Option<String>,
-    pub optional: Option<bool>
+    pub optional: Option<bool>,
+    pub default: Option<Value>,
+    pub renamed_from: Option<String>,
 }
 
 impl SimpleDesc {
     fn validate(&self) -> Result<()> {
         validate_id(&self.name)?;
+        if let Some(x) = &self.renamed_from {
+            validate_id(x)?;
+        }
         if let Some(x) = &self.description {
             validate_text(x, 1024)?;
         }
@@ -354,36 +458,86 @@ impl SimpleDesc {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StringDesc {
+    pub name: String,
+    pub description: Option<String>,
+    pub optional: Option<bool>,
+    pub max_length: Option<usize>,
+    pub default: Option<Value>,
+    pub renamed_from: Option<String>,
+}
+
+impl StringDesc {
+    fn validate(&self) -> Result<()> {
+        validate_id(&self.name)?;
+        if let Some(x) = &self.renamed_from {
+            validate_id(x)?;
+        }
+        if let Some(x) = &self.description {
+            validate_text(x, 1024)?;
+        }
+        if self.max_length == Some(0) {
+            bail!("max_length of field {} must be positive", self.name);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct DateTimeDesc {
     pub name: String,
     pub description: Option<String>,
     pub optional: Option<bool>,
     pub default_now: Option<bool>,
+    /* Set to the current time whenever the record is saved */
+    pub auto_now: Option<bool>,
+    /* Set to the current time when the record is created */
+    pub auto_now_add: Option<bool>,
+    pub default: Option<Value>,
+    pub renamed_from: Option<String>,
 }
 
 impl DateTimeDesc {
     fn validate(&self) -> Result<()> {
         validate_id(&self.name)?;
+        if let Some(x) = &self.renamed_from {
+            validate_id(x)?;
+        }
         if let Some(x) = &self.description {
             validate_text(x, 1024)?;
         }
+        if self.auto_now == Some(true) && self.auto_now_add == Some(true) {
+            bail!("Field {} can't have both auto_now and auto_now_add", self.name);
+        }
         Ok(())
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ReferenceDesc {
     pub name: String,
     pub description: Option<String>,
     pub target: String,
-    pub optional: Option<bool>
+    pub optional: Option<bool>,
+    pub related_name: Option<String>,
+    pub default: Option<Value>,
+    pub renamed_from: Option<String>,
 }
 
 impl ReferenceDesc {
     fn validate(&self) -> Result<()> {
         validate_id(&self.name)?;
+        if let Some(x) = &self.renamed_from {
+            validate_id(x)?;
+        }
         validate_id(&self.target)?;
+        if let Some(x) = &self.related_name {
+            validate_id(x)?;
+        }
         if let Some(x) = &self.description {
             validate_text(x, 1024)?;
         }
@@ -391,11 +545,11 @@ impl ReferenceDesc {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum FieldDef {
-    String(SimpleDesc),
+    String(StringDesc),
     Integer(SimpleDesc),
     Boolean(SimpleDesc),
     Float(SimpleDesc),
@@ -415,14 +569,22 @@ impl FieldDef {
             Self::DateTime(d) => d.validate()?,
             Self::Reference(d) => d.validate()?,
         }
+        if let Some(x) = self.declared_default() {
+            if let Err(e) = self.coerce_value(x) {
+                bail!("Invalid default of field {}: {}", self.name(), e);
+            }
+        }
         Ok(())
     }
     pub fn make_string(name: &str, desc: &str) -> FieldDef {
         FieldDef::String(
-            SimpleDesc {
+            StringDesc {
                 name: name.to_string(),
                 description: Some(desc.to_string()),
                 optional: None,
+                max_length: None,
+                default: None,
+                renamed_from: None,
             }
         )
     }
@@ -432,6 +594,8 @@ impl FieldDef {
                 name: name.to_string(),
                 description: Some(desc.to_string()),
                 optional: None,
+                default: None,
+                renamed_from: None,
             }
         )
     }
@@ -441,6 +605,8 @@ impl FieldDef {
                 name: name.to_string(),
                 description: Some(desc.to_string()),
                 optional: None,
+                default: None,
+                renamed_from: None,
             }
         )
     }
@@ -450,6 +616,8 @@ impl FieldDef {
                 name: name.to_string(),
                 description: Some(desc.to_string()),
                 optional: None,
+                default: None,
+                renamed_from: None,
             }
         )
     }
@@ -460,6 +628,10 @@ impl FieldDef {
                 description: Some(desc.to_string()),
                 optional: None,
                 default_now: None,
+                auto_now: None,
+                auto_now_add: None,
+                default: None,
+                renamed_from: None,
             }
         )
     }
@@ -469,6 +641,8 @@ impl FieldDef {
                 name: name.to_string(),
                 description: Some(desc.to_string()),
                 optional: None,
+                default: None,
+                renamed_from: None,
             }
         )
     }
@@ -479,6 +653,9 @@ impl FieldDef {
                 description: Some(desc.to_string()),
                 target: target.to_string(),
                 optional: None,
+                related_name: None,
+                default: None,
+                renamed_from: None,
             }
         )
     }
@@ -524,6 +701,7 @@ impl ApiAccessMethod {
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ApiAccessRuleDef {
     #[serde(default = "default_action")]
     pub action: ApiAccessRuleAction,
@@ -557,6 +735,7 @@ impl ApiAccessRuleDef {
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct StringApiDesc {
     pub name: String,
     pub path: String,
@@ -583,18 +762,28 @@ impl StringApiDesc {
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct StaticApiDesc {
     pub name: String,
     pub path: String,
     pub description: Option<String>,
     pub localfile: String,
+    /* Serve the directory localfile under path, e.g. path/a/b.css from localfile/a/b.css */
+    pub prefix: Option<bool>,
     pub access: Option<Vec<ApiAccessRuleDef>>,
 }
 
 impl StaticApiDesc {
+    pub fn is_prefix(&self) -> bool {
+        self.prefix.unwrap_or(false)
+    }
+
     fn validate(&self) -> Result<()> {
         validate_id(&self.name)?;
         validate_api_path(&self.path)?;
+        if self.path.split('/').any(|x| segment_param(x).is_some()) {
+            bail!("Static endpoint path cannot have parameters: {}", self.path);
+        }
         if let Some(x) = &self.description {
             validate_text(x, 1024)?;
         }
@@ -609,6 +798,7 @@ impl StaticApiDesc {
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ModelApiDesc {
     pub name: String,
     pub path: String,
@@ -635,6 +825,7 @@ impl ModelApiDesc {
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct GraphQLApiDesc {
     pub name: String,
     pub description: Option<String>,
@@ -665,27 +856,15 @@ pub enum ModelVisibilityScope {
     Owner,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ModelDef {
     pub name: String,
     pub description: Option<String>,
     pub fields: Option<Vec<FieldDef>>,
     pub visibility_scope: Option<ModelVisibilityScope>,
-}
-
-impl ModelDef {
-    fn validate(&self) -> Result<()> {
-        validate_id(&self.name)?;
-        if let Some(x) = &self.description {
-            validate_text(x, 1024)?;
-        }
-        if let Some(x) = &self.fields {
-            for f in x {
-                f.validate()?;
-            }
-        }
-        Ok(())
-    }
+    /* Previous name of the model, so that migration renames its table instead of replacing it */
+    pub renamed_from: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -709,6 +888,19 @@ impl ApiEndpoint {
         String::from(r)
     }
 
+    /* Path templates to route to this endpoint, in prefix mode a static endpoint also serves
+     * everything below its path */
+    fn route_paths(&self) -> Vec<String> {
+        let path = self.get_path();
+        match self {
+            ApiEndpoint::StaticFile(x) if x.is_prefix() => {
+                let files = format!("{}/{{*{}}}", path.trim_end_matches('/'), STATIC_FILE_PARAM);
+                vec![path, files]
+            },
+            _ => vec![path],
+        }
+    }
+
     pub fn name(&self) -> &str {
         match self {
             ApiEndpoint::String(x) => &x.name,
@@ -718,6 +910,14 @@ impl ApiEndpoint {
         }
     }
 
+    pub fn path_params(&self) -> Vec<String> {
+        self.get_path()
+            .split('/')
+            .filter_map(segment_param)
+            .map(|x| x.to_string())
+            .collect()
+    }
+
     pub fn access(&self) -> Option<&Vec<ApiAccessRuleDef>> {
         let x = match self {
             ApiEndpoint::String(x) => &x.access,
@@ -760,13 +960,24 @@ impl ApiDefAccessDef {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct ApiDef {
     pub default_access: Option<ApiDefAccessDef>,
+    #[serde(default)]
     pub endpoints: Vec<ApiEndpoint>,
 }
 
 impl ApiDef {
+    /* The rules that apply to an endpoint with the given access rules, no rules deny everything */
+    pub fn effective_access(&self, rules: Option<&Vec<ApiAccessRuleDef>>) -> Vec<ApiAccessRuleDef> {
+        match (rules, &self.default_access) {
+            (Some(x), _) => x.clone(),
+            (None, Some(x)) => x.get_rules(),
+            (None, None) => Vec::new(),
+        }
+    }
+
     pub fn get_default_access(&self) -> Vec<ApiAccessRuleDef> {
         match &self.default_access {
             Some(x) => x.get_rules(),
@@ -775,21 +986,55 @@ impl ApiDef {
     }
 }
 
+/* The path templates of the endpoints of an ApiDef, built once per loaded app */
+#[derive(Debug)]
+pub struct ApiRouter {
+    router: matchit::Router<usize>,
+}
+
 impl ApiDef {
-    pub fn find_endpoint(&self, path: &str) -> Option<ApiEndpoint> {
-        for ep in &self.endpoints {
-            if ep.get_path() == path {
-                return Some(ep.clone())
+    pub fn router(&self) -> Result<ApiRouter> {
+        let mut router = matchit::Router::new();
+        for (i, ep) in self.endpoints.iter().enumerate() {
+            for path in ep.route_paths() {
+                router.insert(path, i)?;
             }
         }
-        None
+        Ok(ApiRouter {
+            router,
+        })
     }
 
-    fn validate(&self) -> Result<()> {
-        for ep in &self.endpoints {
-            ep.validate()?;
+    #[cfg(test)]
+    pub fn route(&self, path: &str) -> Option<(ApiEndpoint, PathParams)> {
+        self.router().unwrap().route(self, path)
+    }
+}
+
+impl ApiRouter {
+    /* Find the endpoint of api, which the router was built from, whose path template matches
+     * path, with the captured parameters */
+    pub fn route(&self, api: &ApiDef, path: &str) -> Option<(ApiEndpoint, PathParams)> {
+        let router = &self.router;
+        let capture = |m: matchit::Match<&usize>| {
+            let params: PathParams = m.params.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            (api.endpoints[*m.value].clone(), params)
+        };
+        if let Ok(m) = router.at(path) {
+            return Some(capture(m));
+        }
+        /* A model endpoint also serves each of its records at "<path>/<id>" */
+        let (base, id) = path.rsplit_once('/')?;
+        id.parse::<i64>().ok()?;
+        match capture(router.at(base).ok()?) {
+            (ep @ ApiEndpoint::Model(_), mut params) if !params.contains_key("id") => {
+                params.insert("id".to_string(), id.to_string());
+                Some((ep, params))
+            },
+            _ => None,
         }
-        Ok(())
     }
 }
 
@@ -801,6 +1046,7 @@ pub struct AppEvent {
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AppMeta {
     schema: String,
 }
@@ -815,27 +1061,214 @@ impl AppMeta {
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MigrationDef {
+    /* Allow migration steps that lose data, such as dropping tables or columns */
+    pub allow_destructive: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AppDef {
     pub name: String,
+    #[serde(default)]
     pub models: Vec<ModelDef>,
+    #[serde(default)]
     pub api: ApiDef,
     pub meta: AppMeta,
+    pub migration: Option<MigrationDef>,
+    /* Files with more models and endpoints, as glob patterns relative to app.yml */
+    pub include: Option<Vec<String>>,
+    #[serde(skip)]
+    pub origins: AppDefOrigins,
+}
+
+/* The file each model and endpoint was defined in, with its index in that file */
+#[derive(Debug, PartialEq, Default)]
+pub struct AppDefOrigins {
+    pub models: Vec<(String, usize)>,
+    pub endpoints: Vec<(String, usize)>,
+}
+
+/* Models and endpoints in a file included by app.yml */
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppDefFragment {
+    #[serde(default)]
+    pub models: Vec<ModelDef>,
+    #[serde(default)]
+    pub endpoints: Vec<ApiEndpoint>,
 }
 
 impl AppDef {
+    /* A definition in a single file, apps are loaded with get_repo_app_def */
+    #[cfg(test)]
     pub fn from_yaml(yml: &str) -> Result<AppDef> {
-        let app: AppDef = serde_yaml::from_str(yml)?;
+        let app = Self::parse_yaml(yml)?;
         app.validate()?;
         Ok(app)
     }
 
-    fn validate(&self) -> Result<()> {
-        validate_id(&self.name)?;
-        self.meta.validate()?;
-        for model in &self.models {
-            model.validate()?;
+    /* Parse app.yml without validating it, as the included files may be missing from it */
+    pub fn parse_yaml(yml: &str) -> Result<AppDef> {
+        let mut app: AppDef = serde_yaml::from_str(yml)?;
+        for p in app.include.iter().flatten() {
+            validate_include(p)?;
+        }
+        app.origins.models = (0..app.models.len()).map(|i| (APP_YML.to_string(), i)).collect();
+        app.origins.endpoints = (0..app.api.endpoints.len()).map(|i| (APP_YML.to_string(), i)).collect();
+        Ok(app)
+    }
+
+    /* Merge the included files, given as their names and contents. A model or endpoint may only
+     * be defined once across all of the files. */
+    pub fn add_fragments(&mut self, files: &[(String, String)]) -> Result<()> {
+        let main = APP_YML.to_string();
+        let mut models: HashMap<String, &String> = self.models.iter()
+            .map(|x| (x.name.clone(), &main))
+            .collect();
+        let mut endpoints: HashMap<String, &String> = self.api.endpoints.iter()
+            .map(|x| (x.name().to_string(), &main))
+            .collect();
+        for (file, yml) in files {
+            let frag: AppDefFragment = match serde_yaml::from_str(yml) {
+                Ok(x) => x,
+                Err(e) => bail!("{}: {}", file, e),
+            };
+            for (i, m) in frag.models.into_iter().enumerate() {
+                if let Some(prev) = models.insert(m.name.clone(), file) {
+                    bail!("Model {} is defined in both {} and {}", m.name, prev, file);
+                }
+                self.models.push(m);
+                self.origins.models.push((file.clone(), i));
+            }
+            for (i, ep) in frag.endpoints.into_iter().enumerate() {
+                if let Some(prev) = endpoints.insert(ep.name().to_string(), file) {
+                    bail!("Endpoint {} is defined in both {} and {}", ep.name(), prev, file);
+                }
+                self.api.endpoints.push(ep);
+                self.origins.endpoints.push((file.clone(), i));
+            }
+        }
+        Ok(())
+    }
+
+    /* Where the model or endpoint at index i was defined, as its file and YAML path there */
+    fn model_origin(&self, i: usize) -> (String, String) {
+        match self.origins.models.get(i) {
+            Some((file, j)) if file != APP_YML => (file.clone(), format!("models[{}]", j)),
+            _ => (APP_YML.to_string(), format!("models[{}]", i)),
+        }
+    }
+
+    fn endpoint_origin(&self, i: usize) -> (String, String) {
+        match self.origins.endpoints.get(i) {
+            Some((file, j)) if file != APP_YML => (file.clone(), format!("endpoints[{}]", j)),
+            _ => (APP_YML.to_string(), format!("api.endpoints[{}]", i)),
+        }
+    }
+
+    /* Check the whole definition, including references between its parts. Every problem is
+     * reported rather than just the first one. */
+    pub fn check(&self) -> Vec<AppDefError> {
+        let mut errors = Vec::new();
+        let mut error = |(file, path), message| errors.push(AppDefError::new(file, path, message));
+        let main = |path: &str| (APP_YML.to_string(), path.to_string());
+        let sub = |(file, path): (String, String), child: &str| (file, format!("{}.{}", path, child));
+        if let Err(e) = validate_id(&self.name) {
+            error(main("name"), e.to_string());
+        }
+        if let Err(e) = self.meta.validate() {
+            error(main("meta.schema"), e.to_string());
+        }
+        let mut models: HashMap<&str, usize> = HashMap::new();
+        for (i, m) in self.models.iter().enumerate() {
+            let origin = self.model_origin(i);
+            if let Err(e) = validate_id(&m.name) {
+                error(sub(origin.clone(), "name"), e.to_string());
+            }
+            if let Some(Err(e)) = m.renamed_from.as_deref().map(validate_id) {
+                error(sub(origin.clone(), "renamed_from"), e.to_string());
+            }
+            if let Some(Err(e)) = m.description.as_deref().map(|x| validate_text(x, 1024)) {
+                error(sub(origin.clone(), "description"), e.to_string());
+            }
+            if let Some(j) = models.insert(&m.name, i) {
+                let (file, path) = self.model_origin(j);
+                error(sub(origin.clone(), "name"),
+                      format!("Model {} is already defined at {}: {}", m.name, file, path));
+            }
+            let mut fields: HashSet<&str> = HashSet::new();
+            for (k, f) in m.fields.iter().flatten().enumerate() {
+                let field = sub(origin.clone(), &format!("fields[{}]", k));
+                if let Err(e) = f.validate() {
+                    error(field.clone(), e.to_string());
+                }
+                if !fields.insert(f.name()) {
+                    error(sub(field.clone(), "name"),
+                          format!("Field {} is defined twice in model {}", f.name(), m.name));
+                }
+                if let FieldDef::Reference(r) = f {
+                    if !self.models.iter().any(|x| x.name == r.target) {
+                        error(sub(field, "target"),
+                              format!("Field {} of model {} refers to unknown model {}", f.name(), m.name, r.target));
+                    }
+                }
+            }
+        }
+        let mut endpoints: HashMap<&str, usize> = HashMap::new();
+        for (i, ep) in self.api.endpoints.iter().enumerate() {
+            let origin = self.endpoint_origin(i);
+            if let Err(e) = ep.validate() {
+                error(origin.clone(), e.to_string());
+            }
+            if let Some(j) = endpoints.insert(ep.name(), i) {
+                let (file, path) = self.endpoint_origin(j);
+                error(sub(origin.clone(), "name"),
+                      format!("Endpoint {} is already defined at {}: {}", ep.name(), file, path));
+            }
+            for (j, other) in self.api.endpoints[..i].iter().enumerate() {
+                let overlap = ep.route_paths().iter()
+                    .any(|a| other.route_paths().iter().any(|b| paths_overlap(a, b)));
+                if overlap {
+                    let (file, path) = self.endpoint_origin(j);
+                    error(sub(origin.clone(), "path"),
+                          format!("Endpoint path {} is ambiguous with endpoint {} at {}: {}",
+                                  ep.get_path(), other.name(), file, path));
+                }
+            }
+            if let ApiEndpoint::Model(m) = ep {
+                let model = match self.get_model(&m.model) {
+                    Some(x) => x,
+                    None => {
+                        error(sub(origin, "model"),
+                              format!("Endpoint {} refers to unknown model {}", m.name, m.model));
+                        continue;
+                    },
+                };
+                for p in ep.path_params() {
+                    let known = p == "id" || model.fields.iter().flatten().any(|f| f.name() == p);
+                    if !known {
+                        error(sub(origin.clone(), "path"),
+                              format!("Path parameter '{}' of endpoint {} is not a field of {}", p, m.name, m.model));
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            if let Err(e) = self.api.router() {
+                errors.push(AppDefError::new(APP_YML.to_string(), "api".to_string(), e.to_string()));
+            }
+        }
+        errors
+    }
+
+    #[cfg(test)]
+    pub fn validate(&self) -> Result<()> {
+        let errors = self.check();
+        if !errors.is_empty() {
+            bail!(AppDefErrors { errors });
         }
-        self.api.validate()?;
         Ok(())
     }
 
@@ -849,7 +1282,7 @@ impl AppDef {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum RowField {
     String(String),
     Integer(i64),
@@ -891,11 +1324,7 @@ impl Row {
         }
     }
 
-    pub fn from_json(s: &str) -> Result<Row> {
-        let v = serde_json::from_str(s)?;
-        Self::from_value(&v)
-    }
-
+    #[cfg(test)]
     pub fn from_value(v: &Value) -> Result<Row> {
         let mut ret = Row::new();
         match v {
@@ -912,6 +1341,7 @@ impl Row {
                             }
                         }
                         Value::String(x) => RowField::String(x.to_string()),
+                        Value::Null => RowField::Null,
                         _ => bail!("Invalid data type"),
                     };
                     ret.fields.insert(k.to_string(), f);
@@ -953,6 +1383,199 @@ impl Row {
     }
 }
 
+/* An error reported to the client: the HTTP status, a machine readable code, a message for
+ * humans and optionally structured details. Handlers can return it as an Err to set the
+ * response, see HttpError::from_error. */
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct HttpError {
+    pub status: u16,
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl HttpError {
+    pub fn new(status: u16, code: &'static str, message: &str) -> HttpError {
+        HttpError {
+            status,
+            code,
+            message: message.to_string(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> HttpError {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn bad_request(message: &str) -> HttpError {
+        Self::new(400, "bad_request", message)
+    }
+
+    pub fn unauthorized(message: &str) -> HttpError {
+        Self::new(401, "unauthorized", message)
+    }
+
+    pub fn not_found(message: &str) -> HttpError {
+        Self::new(404, "not_found", message)
+    }
+
+    pub fn method_not_allowed() -> HttpError {
+        Self::new(405, "method_not_allowed", "Method not allowed")
+    }
+
+    pub fn internal(message: &str) -> HttpError {
+        Self::new(500, "internal_error", message)
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/* How an incoming record is checked against the model fields */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WriteMode {
+    /* A new record: required fields must be present unless they have a default */
+    Create,
+    /* A full replacement (PUT): missing optional fields are reset to null */
+    Replace,
+    /* A partial update (PATCH): only the given fields are checked */
+    Patch,
+}
+
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/* Every problem found in an incoming record, reported together rather than one at a time */
+#[derive(Debug, PartialEq, Clone)]
+pub struct ValidationError {
+    pub errors: Vec<FieldError>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msgs: Vec<String> = self.errors.iter()
+            .map(|e| format!("{}: {}", e.field, e.message))
+            .collect();
+        write!(f, "Invalid record: {}", msgs.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/* A problem in the app definition, at a YAML path in one of its files. The line and column are
+ * known once the error is located in the file text. */
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct AppDefError {
+    pub file: String,
+    pub path: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl AppDefError {
+    pub fn new(file: String, path: String, message: String) -> AppDefError {
+        AppDefError {
+            file,
+            path,
+            line: None,
+            column: None,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for AppDefError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(l), Some(c)) => write!(f, "{}:{}:{}: {}: {}", self.file, l, c, self.path, self.message),
+            _ => write!(f, "{}: {}: {}", self.file, self.path, self.message),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AppDefErrors {
+    pub errors: Vec<AppDefError>,
+}
+
+impl fmt::Display for AppDefErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msgs: Vec<String> = self.errors.iter().map(|e| e.to_string()).collect();
+        write!(f, "Invalid app definition:\n{}", msgs.join("\n"))
+    }
+}
+
+impl std::error::Error for AppDefErrors {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FilterOp {
+    Eq(RowField),
+    Ne(RowField),
+    Lt(RowField),
+    Gt(RowField),
+    In(Vec<RowField>),
+    Like(String),
+    IsNull(bool),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FieldFilter {
+    pub field: String,
+    pub op: FilterOp,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SortKey {
+    pub field: String,
+    pub desc: bool,
+}
+
+/* Filters, ordering and paging of a model select, parsed from request parameters and checked
+ * against the model fields. */
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ModelQuery {
+    pub id: Option<i64>,
+    pub filters: Vec<FieldFilter>,
+    pub order_by: Vec<SortKey>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    /* Values of the sort keys of the row a cursor points at, the rows after it are selected */
+    pub after: Vec<RowField>,
+}
+
+impl ModelQuery {
+    /* The keys rows are sorted by, ending with id so that the order is total */
+    pub fn sort_keys(&self) -> Vec<SortKey> {
+        let mut keys = self.order_by.clone();
+        if !keys.iter().any(|k| k.field == "id") {
+            keys.push(SortKey {
+                field: "id".to_string(),
+                desc: false,
+            });
+        }
+        keys
+    }
+}
+
+#[derive(Debug)]
+pub struct Page {
+    pub rows: Vec<Row>,
+    pub total: usize,
+    pub next_cursor: Option<String>,
+}
+
 pub fn gen_random_string(n: usize) -> String {
     let mut ret = String::new();
     let mut rng = rand::thread_rng();
@@ -1004,3 +1627,143 @@ api:
     let r = AppDef::from_yaml(good);
     assert!(r.is_ok());
 }
+
+#[test]
+fn route_test() {
+    let yaml = "
+meta:
+  schema: v0.0.1
+name: test
+models:
+  - name: item
+    fields:
+      - name: list
+        type: integer
+api:
+  endpoints:
+    - name: items
+      path: /items
+      type: model
+      model: item
+    - name: list_items
+      path: /lists/{list}/items
+      type: model
+      model: item
+      ";
+    let app = AppDef::from_yaml(yaml).unwrap();
+    let (ep, params) = app.api.route("/items").unwrap();
+    assert_eq!(ep.get_path(), "/items");
+    assert!(params.is_empty());
+    let (_, params) = app.api.route("/items/3").unwrap();
+    assert_eq!(params["id"], "3");
+    assert!(app.api.route("/items/x").is_none());
+    let (ep, params) = app.api.route("/lists/2/items/5").unwrap();
+    assert_eq!(ep.get_path(), "/lists/{list}/items");
+    assert_eq!(params["list"], "2");
+    assert_eq!(params["id"], "5");
+
+    let ambiguous = yaml.replace("/lists/{list}/items", "/{list}");
+    assert!(AppDef::from_yaml(&ambiguous).is_err());
+    let unknown = yaml.replace("{list}", "{owner}");
+    assert!(AppDef::from_yaml(&unknown).is_err());
+
+    let assets = "
+    - name: assets
+      path: /assets
+      type: staticfile
+      localfile: static
+      prefix: true
+";
+    let app = AppDef::from_yaml(&(yaml.trim_end().to_string() + assets)).unwrap();
+    let (ep, params) = app.api.route("/assets/css/a.css").unwrap();
+    assert_eq!(ep.name(), "assets");
+    assert_eq!(params[STATIC_FILE_PARAM], "css/a.css");
+    assert!(app.api.route("/assets").unwrap().1.is_empty());
+    let escaping = yaml.trim_end().to_string() + &assets.replace("static", "../static");
+    assert!(AppDef::from_yaml(&escaping).is_err());
+    let ambiguous = yaml.trim_end().to_string() + &assets.replace("/assets", "/lists");
+    assert!(AppDef::from_yaml(&ambiguous).is_err());
+}
+
+#[test]
+fn check_test() {
+    let yaml = "
+meta:
+  schema: v0.0.1
+name: test
+models:
+  - name: todo
+    fields:
+      - name: subject
+        type: string
+      - name: subject
+        type: string
+      - name: tag
+        type: reference
+        target: label
+  - name: todo
+api:
+  endpoints:
+    - name: todos
+      type: model
+      path: /todo
+      model: task
+    - name: hello
+      type: string
+      path: /todo
+      response: hi
+    - name: hello
+      type: string
+      path: /hello
+      response: hi
+";
+    let app = AppDef::parse_yaml(yaml).unwrap();
+    let errors: Vec<(String, String)> = app.check().into_iter().map(|e| (e.path, e.message)).collect();
+    let expected = [
+        ("models[0].fields[1].name", "Field subject is defined twice in model todo"),
+        ("models[0].fields[2].target", "Field tag of model todo refers to unknown model label"),
+        ("models[1].name", "Model todo is already defined at app.yml: models[0]"),
+        ("api.endpoints[0].model", "Endpoint todos refers to unknown model task"),
+        ("api.endpoints[1].path", "Endpoint path /todo is ambiguous with endpoint todos at app.yml: api.endpoints[0]"),
+        ("api.endpoints[2].name", "Endpoint hello is already defined at app.yml: api.endpoints[1]"),
+    ];
+    let expected: Vec<(String, String)> = expected.iter().map(|(p, m)| (p.to_string(), m.to_string())).collect();
+    assert_eq!(errors, expected);
+    let e = app.validate().unwrap_err();
+    assert_eq!(e.downcast_ref::<AppDefErrors>().unwrap().errors.len(), 6);
+}
+
+#[test]
+fn example_test() {
+    let yml = std::fs::read_to_string("example.yml").unwrap();
+    AppDef::from_yaml(&yml).unwrap();
+}
+
+#[test]
+fn unknown_keys_test() {
+    let yaml = "
+meta:
+  schema: v0.0.1
+name: test
+models:
+  - name: todo
+    fields:
+      - name: created
+        type: datetime
+        auto_now_add: true
+api:
+  endpoints:
+    - name: todos
+      type: model
+      path: /todo
+      model: todo
+";
+    assert!(AppDef::from_yaml(yaml).is_ok());
+    let e = AppDef::parse_yaml(&yaml.replace("auto_now_add", "auto_now_sometimes")).unwrap_err();
+    assert!(e.to_string().contains("unknown field `auto_now_sometimes`"));
+    assert!(AppDef::parse_yaml(&yaml.replace("model: todo", "model: todo\n      paginate: true")).is_err());
+    assert!(AppDef::parse_yaml(&yaml.replace("  - name: todo\n", "  - name: todo\n    table: todos\n")).is_err());
+    assert!(AppDef::parse_yaml(&yaml.replace("name: test", "name: test\nversion: 2")).is_err());
+    let both = yaml.replace("auto_now_add: true", "auto_now_add: true\n        auto_now: true");
+    assert!(AppDef::from_yaml(&both).is_err());
+}
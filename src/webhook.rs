@@ -0,0 +1,226 @@
+use std::sync::Arc;
+use hmac::{Hmac, Mac};
+use hyper::{HeaderMap, Method};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::types::*;
+use crate::http::*;
+use crate::worker::submit_sync;
+
+/*
+ * Push webhooks of GitHub, GitLab and Gitea, posted to /meta/hook/<app handle>. GitHub and Gitea
+ * sign the payload with HMAC-SHA256 of the app's webhook secret, GitLab sends the secret itself
+ * as the token. A push to the ref the app is deployed from starts a sync job.
+ */
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ZERO_COMMIT: &str = "0000000000000000000000000000000000000000";
+
+#[derive(Debug, PartialEq)]
+pub struct Push {
+    pub source: &'static str,
+    pub git_ref: String,
+    pub default_branch: Option<String>,
+}
+
+fn signature_valid(secret: &str, body: &[u8], sig_hex: &str) -> bool {
+    let mut mac = match HmacSha256::new_from_slice(secret.as_bytes()) {
+        Ok(x) => x,
+        Err(_) => { return false; },
+    };
+    mac.update(body);
+    match hex::decode(sig_hex) {
+        Ok(sig) => mac.verify_slice(&sig).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/* Compare digests rather than the strings so the time taken doesn't tell how much matched */
+fn token_valid(secret: &str, token: &str) -> bool {
+    Sha256::digest(secret.as_bytes()) == Sha256::digest(token.as_bytes())
+}
+
+/* Verify a webhook request and parse it, None if it's valid but not a push of some commit */
+pub fn parse_push(headers: &HeaderMap, body: &[u8], secret: &str) -> Result<Option<Push>> {
+    let header = |name: &str| headers.get(name).and_then(|x| x.to_str().ok());
+    /* Gitea sends the GitHub headers too, so look for its own first */
+    let (source, valid, is_push) = if let Some(event) = header("X-Gitea-Event") {
+        let valid = header("X-Gitea-Signature").is_some_and(|s| signature_valid(secret, body, s));
+        ("gitea", valid, event == "push")
+    } else if let Some(event) = header("X-Gitlab-Event") {
+        let valid = header("X-Gitlab-Token").is_some_and(|t| token_valid(secret, t));
+        ("gitlab", valid, event == "Push Hook" || event == "Tag Push Hook")
+    } else if let Some(event) = header("X-GitHub-Event") {
+        let valid = header("X-Hub-Signature-256")
+            .and_then(|s| s.strip_prefix("sha256="))
+            .is_some_and(|s| signature_valid(secret, body, s));
+        ("github", valid, event == "push")
+    } else {
+        bail!(HttpError::bad_request("Unknown webhook source"));
+    };
+    if !valid {
+        bail!(HttpError::unauthorized("Invalid webhook signature"));
+    }
+    if !is_push {
+        return Ok(None);
+    }
+    let v: Value = match serde_json::from_slice(body) {
+        Ok(x) => x,
+        Err(_) => bail!(HttpError::bad_request("Invalid webhook payload")),
+    };
+    if v["deleted"] == true || v["after"] == ZERO_COMMIT {
+        return Ok(None);
+    }
+    let git_ref = match v["ref"].as_str() {
+        Some(x) => x.to_string(),
+        None => bail!(HttpError::bad_request("Webhook payload has no ref")),
+    };
+    let default_branch = v["repository"]["default_branch"].as_str()
+        .or_else(|| v["project"]["default_branch"].as_str())
+        .map(|x| x.to_string());
+    Ok(Some(Push {
+        source,
+        git_ref,
+        default_branch,
+    }))
+}
+
+/* Whether a pushed ref is the one the app is cloned from, see OctApp::git_ref */
+pub fn ref_matches(pushed: &str, git_ref: Option<&str>, default_branch: Option<&str>) -> bool {
+    match git_ref.filter(|x| !x.is_empty()) {
+        Some(r) if r.starts_with("refs/") => pushed == r,
+        Some(r) => pushed == format!("refs/heads/{}", r) || pushed == format!("refs/tags/{}", r),
+        None => default_branch.is_some_and(|b| pushed == format!("refs/heads/{}", b)),
+    }
+}
+
+async fn handle_webhook_post(ctx: Arc<Context>, handle: &str, req: Request) -> Result<Response> {
+    let app = match OctApp::by_handle(handle).await {
+        Ok(x) => x,
+        Err(_) => { return http404("App not found"); },
+    };
+    let secret = match app.webhook_secret.as_deref() {
+        Some(x) if !x.is_empty() && app.git_repo.is_some() => x.to_string(),
+        _ => { return http404("Webhook is not enabled for app"); },
+    };
+    let headers = req.headers().clone();
+    let body = to_bytes(req.into_body()).await?;
+    let push = match parse_push(&headers, &body, &secret)? {
+        Some(x) => x,
+        None => { return json_response(&json!({ "triggered": false })); },
+    };
+    if !ref_matches(&push.git_ref, app.git_ref.as_deref(), push.default_branch.as_deref()) {
+        return json_response(&json!({ "triggered": false }));
+    }
+    let _ = app.event(&format!("Push to {} received from {}", push.git_ref, push.source)).await;
+    let id = submit_sync(ctx.clone(), app, &format!("{} webhook", push.source))?;
+    let job = ctx.jobs().get(id).cloned();
+    json_response_with_status(202, &json!({ "triggered": true, "job": job }))
+}
+
+pub async fn handle_webhook_request(ctx: Arc<Context>, handle: &str, req: Request) -> Result<Response> {
+    match req.method() {
+        &Method::POST => handle_webhook_post(ctx, handle, req).await,
+        _ => http405(),
+    }
+}
+
+/* Commit of the ref among the (name, sha) refs of a remote, the peeled commit for annotated tags */
+pub fn remote_head(refs: &[(String, String)], git_ref: Option<&str>) -> Option<String> {
+    let wanted = match git_ref.filter(|x| !x.is_empty()) {
+        Some(r) if r.starts_with("refs/") => vec![r.to_string()],
+        Some(r) => vec![
+            format!("refs/heads/{}", r),
+            format!("refs/tags/{}^{{}}", r),
+            format!("refs/tags/{}", r),
+        ],
+        None => vec!["HEAD".to_string()],
+    };
+    wanted.iter()
+        .find_map(|w| refs.iter().find(|(r, _)| r == w))
+        .map(|(_, sha)| sha.to_string())
+}
+
+#[cfg(test)]
+fn signed_headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (k, v) in pairs {
+        headers.insert(*k, v.parse().unwrap());
+    }
+    headers
+}
+
+#[test]
+fn parse_push_test() {
+    let secret = "s3cret";
+    let sign = |body: &str| {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    };
+    let github = r#"{"ref":"refs/heads/main","before":"6113728f27ae82c7b1a177c8d03f9e96e0adf246","after":"59b20b8d5c6ff8d09518454d4dd8b7b30f095ab5","deleted":false,"repository":{"full_name":"octocat/app","default_branch":"main"},"pusher":{"name":"octocat"}}"#;
+    let gitea = r#"{"ref":"refs/tags/v1.0","before":"0000000000000000000000000000000000000000","after":"28e1879d029cb852e4844d9c718537df08844e03","repository":{"full_name":"gitea/app","default_branch":"master"}}"#;
+    let gitlab = r#"{"object_kind":"push","ref":"refs/heads/dev","after":"da1560886d4f094c3e6c9ef40349f7d38b5d27d7","project":{"path_with_namespace":"group/app","default_branch":"master"}}"#;
+    let deleted = r#"{"ref":"refs/heads/main","after":"0000000000000000000000000000000000000000","deleted":true}"#;
+
+    let h = signed_headers(&[("X-GitHub-Event", "push".into()), ("X-Hub-Signature-256", format!("sha256={}", sign(github)))]);
+    assert_eq!(parse_push(&h, github.as_bytes(), secret).unwrap(), Some(Push {
+        source: "github",
+        git_ref: "refs/heads/main".to_string(),
+        default_branch: Some("main".to_string()),
+    }));
+    assert!(parse_push(&h, github.replace("main", "evil").as_bytes(), secret).is_err());
+    assert!(parse_push(&h, github.as_bytes(), "other").is_err());
+
+    let h = signed_headers(&[("X-GitHub-Event", "ping".into()), ("X-Hub-Signature-256", format!("sha256={}", sign("{}")))]);
+    assert_eq!(parse_push(&h, b"{}", secret).unwrap(), None);
+
+    let h = signed_headers(&[
+        ("X-Gitea-Event", "push".into()),
+        ("X-Gitea-Signature", sign(gitea)),
+        ("X-GitHub-Event", "push".into()),
+    ]);
+    assert_eq!(parse_push(&h, gitea.as_bytes(), secret).unwrap().unwrap().source, "gitea");
+
+    let h = signed_headers(&[("X-Gitlab-Event", "Push Hook".into()), ("X-Gitlab-Token", secret.into())]);
+    let p = parse_push(&h, gitlab.as_bytes(), secret).unwrap().unwrap();
+    assert_eq!(p.git_ref, "refs/heads/dev");
+    assert_eq!(p.default_branch.as_deref(), Some("master"));
+    let h = signed_headers(&[("X-Gitlab-Event", "Push Hook".into()), ("X-Gitlab-Token", "wrong".into())]);
+    assert!(parse_push(&h, gitlab.as_bytes(), secret).is_err());
+
+    let h = signed_headers(&[("X-GitHub-Event", "push".into()), ("X-Hub-Signature-256", format!("sha256={}", sign(deleted)))]);
+    assert_eq!(parse_push(&h, deleted.as_bytes(), secret).unwrap(), None);
+    assert!(parse_push(&HeaderMap::new(), b"{}", secret).is_err());
+}
+
+#[test]
+fn ref_matches_test() {
+    assert!(ref_matches("refs/heads/main", None, Some("main")));
+    assert!(!ref_matches("refs/heads/dev", Some(""), Some("main")));
+    assert!(ref_matches("refs/heads/dev", Some("dev"), Some("main")));
+    assert!(ref_matches("refs/tags/v1", Some("v1"), None));
+    assert!(ref_matches("refs/heads/x", Some("refs/heads/x"), None));
+    assert!(!ref_matches("refs/heads/main", None, None));
+}
+
+#[test]
+fn remote_head_test() {
+    let out = "\
+6113728f27ae82c7b1a177c8d03f9e96e0adf246\tHEAD
+6113728f27ae82c7b1a177c8d03f9e96e0adf246\trefs/heads/main
+59b20b8d5c6ff8d09518454d4dd8b7b30f095ab5\trefs/heads/dev
+28e1879d029cb852e4844d9c718537df08844e03\trefs/tags/v1
+da1560886d4f094c3e6c9ef40349f7d38b5d27d7\trefs/tags/v1^{}
+";
+    let out: Vec<(String, String)> = out.lines()
+        .filter_map(|l| l.split_once('\t'))
+        .map(|(sha, r)| (r.to_string(), sha.to_string()))
+        .collect();
+    assert_eq!(remote_head(&out, None).as_deref(), Some("6113728f27ae82c7b1a177c8d03f9e96e0adf246"));
+    assert_eq!(remote_head(&out, Some("dev")).as_deref(), Some("59b20b8d5c6ff8d09518454d4dd8b7b30f095ab5"));
+    assert_eq!(remote_head(&out, Some("v1")).as_deref(), Some("da1560886d4f094c3e6c9ef40349f7d38b5d27d7"));
+    assert_eq!(remote_head(&out, Some("refs/heads/main")).as_deref(), Some("6113728f27ae82c7b1a177c8d03f9e96e0adf246"));
+    assert_eq!(remote_head(&out, Some("missing")), None);
+}
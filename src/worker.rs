@@ -1,8 +1,15 @@
 use core::time::Duration;
+use std::time::Instant;
 use futures::try_join;
+use chrono::Utc;
+use tokio::sync::watch;
 use tokio::time::sleep;
 use crate::types::*;
 use crate::stats::*;
+use crate::deploy::{list_deployments, rollback, synced_commit, Deployment};
+use crate::fetch::{list_remote, git_head, FetchLimits};
+use crate::sync::sync_app;
+use crate::webhook::remote_head;
 
 pub async fn stat_worker(ctx: Arc<Context>) -> Result<()> {
     loop {
@@ -13,9 +20,302 @@ pub async fn stat_worker(ctx: Arc<Context>) -> Result<()> {
     }
 }
 
+/* Check the remote of an app that polls for new commits, and sync it if the head moved */
+async fn poll_app(ctx: &Arc<Context>, app: OctApp) -> Result<()> {
+    let repo = match &app.git_repo {
+        Some(x) => x,
+        None => { return Ok(()); },
+    };
+    if ctx.jobs().active_job(&app).is_some() {
+        return Ok(());
+    }
+    let limits = FetchLimits {
+        timeout: Duration::from_secs(POLL_TIMEOUT),
+        ..FetchLimits::from_config()
+    };
+    /* Compared with what the last sync deployed rather than the checked out commit, which is
+     * older after a rollback and would have the rollback undone */
+    let deployments = list_deployments(&app).await?;
+    let synced = match synced_commit(&deployments) {
+        Some(x) => Some(x.to_string()),
+        None if !deployments.is_empty() => { return Ok(()); },
+        None => git_head(&app.repo()),
+    };
+    let refs = list_remote(repo, app.credentials()?, limits).await?;
+    let head = remote_head(&refs, app.git_ref.as_deref());
+    if head.is_some() && head != synced {
+        let _ = app.event("New commit found on remote").await;
+        submit_sync(ctx.clone(), app, "poll")?;
+    }
+    Ok(())
+}
+
+pub async fn poll_worker(ctx: Arc<Context>) -> Result<()> {
+    let mut last_poll: HashMap<String, Instant> = HashMap::new();
+    loop {
+        sleep(Duration::from_secs(POLL_TICK)).await;
+        let apps = match OctApp::get_all().await {
+            Ok(x) => x,
+            Err(e) => {
+                println!("failed to list apps to poll: {}", e);
+                continue;
+            }
+        };
+        for app in apps {
+            let interval = match app.poll_interval {
+                Some(x) if x > 0 => Duration::from_secs(x.into()),
+                _ => { continue; }
+            };
+            if last_poll.get(&app.handle).is_some_and(|t| t.elapsed() < interval) {
+                continue;
+            }
+            last_poll.insert(app.handle.clone(), Instant::now());
+            let handle = app.handle.clone();
+            if let Err(e) = poll_app(&ctx, app).await {
+                println!("failed to poll app {}: {}", handle, e);
+            }
+        }
+    }
+}
+
 pub async fn run_worker(ctx: Arc<Context>) -> Result<()> {
     let sw = stat_worker(ctx.clone());
+    let pw = poll_worker(ctx.clone());
 
-    try_join!(sw)?;
+    try_join!(sw, pw)?;
     Ok(())
 }
+
+/* Seconds between checks for apps that are due to poll their remote */
+const POLL_TICK: u64 = 60;
+/* Seconds listing the refs of a remote may take */
+const POLL_TIMEOUT: u64 = 60;
+
+/* Finished jobs kept for status queries */
+const FINISHED_JOBS_KEPT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Sync,
+    /* Can't be cancelled, it changes the database from the start */
+    Rollback,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncJob {
+    pub id: u64,
+    pub kind: JobKind,
+    pub app: String,
+    #[serde(skip)]
+    pub user: i64,
+    pub state: JobState,
+    /* Progress so far, the same messages that are recorded as app events */
+    pub steps: Vec<String>,
+    pub error: Option<String>,
+    pub deployment: Option<Deployment>,
+    pub started: DateTime,
+    pub finished: Option<DateTime>,
+}
+
+/* Sync jobs of all apps, at most one running per app */
+#[derive(Debug, Default)]
+pub struct SyncJobs {
+    next_id: u64,
+    jobs: HashMap<u64, SyncJob>,
+    cancels: HashMap<u64, watch::Sender<bool>>,
+    /* Running job of each app, by app handle */
+    active: HashMap<String, u64>,
+    finished: VecDeque<u64>,
+}
+
+impl SyncJobs {
+    pub fn get(&self, id: u64) -> Option<&SyncJob> {
+        self.jobs.get(&id)
+    }
+
+    pub fn active_job(&self, app: &OctApp) -> Option<u64> {
+        self.active.get(&app.handle).cloned()
+    }
+
+    /* The running job of the app, or else its last finished one */
+    pub fn latest(&self, app: &OctApp) -> Option<&SyncJob> {
+        self.jobs.values()
+            .filter(|x| x.app == app.name && Some(x.user) == app.user)
+            .max_by_key(|x| x.id)
+    }
+
+    fn start(&mut self, app: &OctApp, kind: JobKind) -> Result<(u64, watch::Receiver<bool>)> {
+        if let Some(id) = self.active_job(app) {
+            bail!(HttpError::new(409, "sync_running", &format!("Sync job {} is still running", id)));
+        }
+        self.next_id += 1;
+        let id = self.next_id;
+        let (tx, rx) = watch::channel(false);
+        self.jobs.insert(id, SyncJob {
+            id,
+            kind,
+            app: app.name.clone(),
+            user: app.user.unwrap_or(-1),
+            state: JobState::Running,
+            steps: Vec::new(),
+            error: None,
+            deployment: None,
+            started: Utc::now(),
+            finished: None,
+        });
+        if kind == JobKind::Sync {
+            self.cancels.insert(id, tx);
+        }
+        self.active.insert(app.handle.clone(), id);
+        Ok((id, rx))
+    }
+
+    fn finish(&mut self, app: &OctApp, id: u64, state: JobState, r: Result<Deployment>) {
+        self.active.remove(&app.handle);
+        self.cancels.remove(&id);
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.state = state;
+            job.finished = Some(Utc::now());
+            match r {
+                Ok(d) => job.deployment = Some(d),
+                Err(e) => job.error = Some(e.to_string()),
+            }
+        }
+        self.finished.push_back(id);
+        while self.finished.len() > FINISHED_JOBS_KEPT {
+            if let Some(x) = self.finished.pop_front() {
+                self.jobs.remove(&x);
+            }
+        }
+    }
+
+    /* Ask a running job to stop, it does so at its next step before the database is changed */
+    pub fn cancel(&mut self, id: u64) -> Result<()> {
+        match self.cancels.get(&id) {
+            Some(tx) => {
+                tx.send(true)?;
+                Ok(())
+            },
+            None if self.jobs.get(&id).is_some_and(|x| x.state == JobState::Running) => {
+                bail!(HttpError::new(409, "not_cancellable", "Rollback jobs can't be cancelled"))
+            },
+            None => bail!(HttpError::new(409, "not_running", "Sync job is not running")),
+        }
+    }
+}
+
+/* Reports the steps of a job as app events and tells whether it's cancelled */
+pub struct JobProgress {
+    app: OctApp,
+    job: Option<(Arc<Context>, u64)>,
+    cancel: watch::Receiver<bool>,
+}
+
+impl JobProgress {
+    /* Progress of work that isn't a job, only recorded as app events */
+    pub fn detached(app: &OctApp) -> JobProgress {
+        let (_, cancel) = watch::channel(false);
+        JobProgress {
+            app: app.clone(),
+            job: None,
+            cancel,
+        }
+    }
+
+    pub fn job_id(&self) -> Option<u64> {
+        self.job.as_ref().map(|(_, id)| *id)
+    }
+
+    pub fn cancel_signal(&self) -> watch::Receiver<bool> {
+        self.cancel.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancel.borrow()
+    }
+
+    /* Record a step, fails if the job was cancelled */
+    pub async fn step(&self, msg: &str) -> Result<()> {
+        if let Some((ctx, id)) = &self.job {
+            if let Some(job) = ctx.jobs().jobs.get_mut(id) {
+                job.steps.push(msg.to_string());
+            }
+        }
+        let _ = self.app.event(msg).await;
+        if self.is_cancelled() {
+            bail!("Sync cancelled");
+        }
+        Ok(())
+    }
+}
+
+/* Start syncing the app in the background, returns the job id */
+pub fn submit_sync(ctx: Arc<Context>, app: OctApp, triggered_by: &str) -> Result<u64> {
+    let (id, cancel) = ctx.jobs().start(&app, JobKind::Sync)?;
+    let progress = JobProgress {
+        app: app.clone(),
+        job: Some((ctx.clone(), id)),
+        cancel,
+    };
+    let triggered_by = triggered_by.to_string();
+    tokio::spawn(async move {
+        let r = sync_app(&app, &triggered_by, &progress).await;
+        let state = match &r {
+            Ok(_) => JobState::Succeeded,
+            Err(_) if progress.is_cancelled() => JobState::Cancelled,
+            Err(_) => JobState::Failed,
+        };
+        if let Err(e) = &r {
+            let _ = app.event(&format!("Failed to sync app: {}", e)).await;
+        }
+        ctx.apps().invalidate(&app.handle);
+        ctx.jobs().finish(&app, id, state, r);
+    });
+    Ok(id)
+}
+
+/* Start rolling the app back in the background, as a job so that no sync runs at the same time */
+pub fn submit_rollback(ctx: Arc<Context>, app: OctApp, target: u32, triggered_by: &str) -> Result<u64> {
+    let (id, _) = ctx.jobs().start(&app, JobKind::Rollback)?;
+    let triggered_by = triggered_by.to_string();
+    tokio::spawn(async move {
+        let r = rollback(&app, target, &triggered_by).await;
+        let state = if r.is_ok() { JobState::Succeeded } else { JobState::Failed };
+        if let Err(e) = &r {
+            let _ = app.event(&format!("Failed to roll back: {}", e)).await;
+        }
+        ctx.apps().invalidate(&app.handle);
+        ctx.jobs().finish(&app, id, state, r);
+    });
+    Ok(id)
+}
+
+#[test]
+fn sync_jobs_test() {
+    let app = OctApp::for_test("abcde");
+    let mut jobs = SyncJobs::default();
+    let (id, rx) = jobs.start(&app, JobKind::Sync).unwrap();
+    assert!(jobs.start(&app, JobKind::Rollback).is_err());
+    assert_eq!(jobs.active_job(&app), Some(id));
+    jobs.cancel(id).unwrap();
+    assert!(*rx.borrow());
+    jobs.finish(&app, id, JobState::Cancelled, Err(anyhow!("Sync cancelled")));
+    assert!(jobs.cancel(id).is_err());
+    assert_eq!(jobs.latest(&app).unwrap().state, JobState::Cancelled);
+    let (next, _) = jobs.start(&app, JobKind::Sync).unwrap();
+    assert_eq!(jobs.latest(&app).unwrap().id, next);
+    jobs.finish(&app, next, JobState::Failed, Err(anyhow!("failed")));
+    let (id, _) = jobs.start(&app, JobKind::Rollback).unwrap();
+    let e = jobs.cancel(id).unwrap_err();
+    assert_eq!(e.downcast_ref::<HttpError>().unwrap().status, 409);
+}
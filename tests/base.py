@@ -34,6 +34,8 @@ class BaseTest(unittest.TestCase):
         self.wait_server(self.orm_api)
 
         cmd = ['cargo', 'run', '--',
+               '--store', 'django',
+               '--allow-local-repos',
                '--no-start-orm',
                '--orm-addr', orm_addr,
                '--data', data_dir,
@@ -145,6 +147,9 @@ class BaseTest(unittest.TestCase):
     def get_app(self):
         return self.get("/meta/app")
 
+    def get_admin_token(self, app):
+        return self.get("/meta/credentials?name=" + app['info']['name'])['admin_token']
+
     def create_app_repo(self, name, ymlname):
         ymlpath = os.path.join(self.base_dir, "tests/data/", ymlname)
         repo = os.path.join(self.tmpdir, "repos/", name)
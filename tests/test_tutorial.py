@@ -67,7 +67,7 @@ class TestTutorial(base.BaseTest):
 
     def do_chap03(self):
         app = self.get_app()[0]
-        admin_token = app['info']['admin_token']
+        admin_token = self.get_admin_token(app)
         self.upgrade_app(app, "ch03", "ch03.yml")
 
         r = self.do_get(app['base_uri'] + '/todo')
@@ -99,7 +99,7 @@ class TestTutorial(base.BaseTest):
         self.assertEqual(len(r3), len(r2) + 1)
 
     def do_user_reqs(self, app, username, nitems):
-        admin_token = app['info']['admin_token']
+        admin_token = self.get_admin_token(app)
         user_token = "%stoken" % username
         data = {
             "name": username,
@@ -123,7 +123,7 @@ class TestTutorial(base.BaseTest):
 
     def do_chap04(self):
         app = self.get_app()[0]
-        admin_token = app['info']['admin_token']
+        admin_token = self.get_admin_token(app)
         self.upgrade_app(app, "ch04", "ch04.yml")
 
         r = self.do_get(app['base_uri'] + '/auth/user')